@@ -0,0 +1,95 @@
+//! Heap allocations and time per frame when a frame is received and pushed to several [`PushBus`] subscribers.
+//!
+//! `cargo bench --bench payload`
+//!
+//! The `Vec<u8>` rows are the previous payload representation, for comparison.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use can_adapter::{
+    packet::{FdFlags, Packet},
+    pushbus::PushBus,
+};
+
+const FRAMES: u64 = 1_000_000;
+const SUBSCRIBERS: usize = 4;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn measure(name: &str, mut frame: impl FnMut(u64)) {
+    // warm up, so queues have grown to their steady state size
+    (0..1000).for_each(&mut frame);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    (0..FRAMES).for_each(&mut frame);
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{name:<36} {:>6.2} allocations/frame {:>8.1} ns/frame",
+        allocations as f64 / FRAMES as f64,
+        elapsed.as_nanos() as f64 / FRAMES as f64
+    );
+}
+
+/// Push each frame, then drain every subscriber.
+fn fan_out<T: Clone + Send + Sync + 'static>(name: &str, frame: impl Fn(u64) -> T) {
+    let bus = PushBus::new(name);
+    let mut iters: Vec<_> = (0..SUBSCRIBERS).map(|_| bus.iter()).collect();
+    measure(name, |n| {
+        bus.push(Some(frame(n)));
+        iters.iter_mut().for_each(|i| {
+            black_box(i.next());
+        });
+    });
+}
+
+fn main() {
+    let time = Duration::from_millis(1);
+    let fd = [0x55; 64];
+
+    measure("receive classic", |n| {
+        black_box(Packet::new_rx(0x18FEF100, &n.to_be_bytes(), time, 0));
+    });
+    measure("receive FD 64 bytes", |_| {
+        black_box(Packet::new_rx_fd(0x18FEF100, &fd, FdFlags::default(), time, 0).unwrap());
+    });
+    measure("receive classic, Vec<u8>", |n| {
+        black_box(n.to_be_bytes().to_vec());
+    });
+
+    let name = format!("classic to {SUBSCRIBERS} subscribers");
+    fan_out(&name, |n| {
+        Packet::new_rx(0x18FEF100, &n.to_be_bytes(), time, 0)
+    });
+    let name = format!("FD 64 bytes to {SUBSCRIBERS} subscribers");
+    fan_out(&name, |_| {
+        Packet::new_rx_fd(0x18FEF100, &fd, FdFlags::default(), time, 0).unwrap()
+    });
+    let name = format!("classic to {SUBSCRIBERS} subscribers, Vec<u8>");
+    fan_out(&name, |n| n.to_be_bytes().to_vec());
+}
@@ -0,0 +1,75 @@
+//! Async access to connections for tokio services, behind the `async` feature.
+//!
+//! Streams wait for the next packet instead of returning the empty polls of [`Connection::iter`](crate::connection::Connection::iter),
+//! so no thread is parked per subscriber.
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use futures_util::{Stream, StreamExt};
+
+use crate::{error::Result, packet::Packet};
+
+/// Packets received after the stream was created.  Ends when the connection closes.
+pub type PacketStream = Pin<Box<dyn Stream<Item = Packet> + Send>>;
+
+/// Boxed so [`AsyncConnection`] can be used as `dyn AsyncConnection`, like [`Connection`](crate::connection::Connection).
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<Packet>> + Send + 'a>>;
+
+/// Async counterpart of [`Connection`](crate::connection::Connection), with the same [`Timestamp`](crate::packet::Timestamp)
+/// contract.  Must be used from within a tokio runtime.
+pub trait AsyncConnection: Send + Sync {
+    /// Send packet and return packet echoed back from adapter
+    fn send<'a>(&'a self, packet: &'a Packet) -> SendFuture<'a>;
+
+    /// Subscribe to the packets received from now on.
+    fn stream(&self) -> PacketStream;
+
+    fn stream_until(&self, end: Instant) -> PacketStream {
+        Box::pin(
+            self.stream()
+                .take_until(tokio::time::sleep_until(end.into())),
+        )
+    }
+
+    fn stream_for(&self, duration: Duration) -> PacketStream {
+        self.stream_until(Instant::now() + duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimulatedConnection;
+
+    #[tokio::test]
+    async fn stream_for_ends() -> Result<()> {
+        let connection = SimulatedConnection::new(None)?;
+        let start = Instant::now();
+        let count = connection
+            .stream_for(Duration::from_millis(100))
+            .count()
+            .await;
+        assert!(count > 1);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn echo() -> Result<()> {
+        let connection: Box<dyn AsyncConnection> = Box::new(SimulatedConnection::new(None)?);
+        let stream = connection.stream_for(Duration::from_secs(2));
+        let echo = connection
+            .send(&Packet::new(0x18EAFFF9, &[0xEC, 0xFE, 0]))
+            .await?;
+        assert_eq!(0x18EAFFF9, echo.id);
+        let packet = stream
+            .filter(|p| std::future::ready(p.id == 0x18EAFFF9))
+            .next()
+            .await;
+        assert_eq!(echo.time(), packet.and_then(|p| p.time()));
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! CAN controller error state, from the error and status reports of a connection.
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    packet::{ErrorClass, ErrorClasses, Packet, PacketState, Timestamp},
+    pushbus::PushBus,
+};
+
+/// ISO 11898-1 fault confinement state of the adapter's controller.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorState {
+    /// Both error counters below 96
+    #[default]
+    Active,
+    /// An error counter reached 96
+    Warning,
+    /// An error counter reached 128.  The controller only sends passive error flags.
+    Passive,
+    /// The transmit error counter passed 255.  The controller is off the bus until it recovers.
+    BusOff,
+}
+
+impl ErrorState {
+    /// State for the error counters.  The counters stop at 255, so bus off is only known from a report.
+    pub fn from_counters(tx_errors: u8, rx_errors: u8) -> ErrorState {
+        match tx_errors.max(rx_errors) {
+            128.. => ErrorState::Passive,
+            96.. => ErrorState::Warning,
+            _ => ErrorState::Active,
+        }
+    }
+
+    /// The worst state in `errors`, if any.
+    fn reported(errors: ErrorClasses) -> Option<ErrorState> {
+        if errors.contains(ErrorClass::BusOff) {
+            Some(ErrorState::BusOff)
+        } else if errors.contains(ErrorClass::ErrorPassive) {
+            Some(ErrorState::Passive)
+        } else if errors.contains(ErrorClass::ErrorWarning) {
+            Some(ErrorState::Warning)
+        } else if errors.contains(ErrorClass::Restarted) {
+            Some(ErrorState::Active)
+        } else {
+            None
+        }
+    }
+}
+
+/// Controller state and error counters.  The counters are `None` when the adapter doesn't report them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BusState {
+    pub error_state: ErrorState,
+    pub tx_errors: Option<u8>,
+    pub rx_errors: Option<u8>,
+}
+
+impl BusState {
+    /// The state after an error or status report.  Error frames only report changes, so the state is kept when they
+    /// don't name one.  A status report describes the whole state, so one without a state is error active.
+    pub fn after(&self, packet: &Packet) -> BusState {
+        let Some(errors) = packet.errors() else {
+            return *self;
+        };
+        if errors.contains(ErrorClass::Dropped) || errors.contains(ErrorClass::Disconnected) {
+            return *self;
+        }
+        let error_state = match (ErrorState::reported(errors), &packet.state) {
+            (Some(state), _) => state,
+            (None, PacketState::Status { .. }) => ErrorState::Active,
+            (None, _) => self.error_state,
+        };
+        BusState {
+            error_state,
+            ..*self
+        }
+    }
+}
+
+impl Display for BusState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.error_state)?;
+        if let Some(tx) = self.tx_errors {
+            write!(f, " tx {tx}")?;
+        }
+        if let Some(rx) = self.rx_errors {
+            write!(f, " rx {rx}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An error or status report, with the state after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusEvent {
+    pub time: Timestamp,
+    pub channel: u32,
+    /// The errors reported.  Empty when only the state or counters changed.
+    pub errors: ErrorClasses,
+    pub state: BusState,
+}
+
+impl BusEvent {
+    pub fn new(packet: &Packet, state: BusState) -> BusEvent {
+        BusEvent {
+            time: packet.timestamp().unwrap_or_default(),
+            channel: packet.channel().unwrap_or_default(),
+            errors: packet.errors().unwrap_or_default(),
+            state,
+        }
+    }
+}
+
+/// Tracks the state of a connection and publishes [`BusEvent`]s.
+#[derive(Clone)]
+pub(crate) struct BusMonitor {
+    state: Arc<Mutex<BusState>>,
+    events: PushBus<BusEvent>,
+}
+
+impl BusMonitor {
+    pub fn new(name: &str) -> Self {
+        BusMonitor {
+            state: Default::default(),
+            events: PushBus::new(&format!("{name} bus state")),
+        }
+    }
+
+    /// Record an error or status report.  `reported` is the state from the adapter, when the report has one that the
+    /// packet can't carry.  An event is published if the report has errors or the state changed.  Returns whether the
+    /// state changed.
+    pub fn report(&self, packet: &Packet, reported: Option<BusState>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let next = reported.unwrap_or_else(|| state.after(packet));
+        let changed = next != *state;
+        *state = next;
+        if changed || !packet.errors().unwrap_or_default().is_empty() {
+            self.events.push(Some(BusEvent::new(packet, next)));
+        }
+        changed
+    }
+
+    pub fn state(&self) -> BusState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn events(&self) -> Box<dyn Iterator<Item = Option<BusEvent>> + Send + Sync> {
+        self.events.iter()
+    }
+
+    pub fn close(&mut self) {
+        self.events.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn classes(errors: &[ErrorClass]) -> ErrorClasses {
+        errors.iter().copied().collect()
+    }
+
+    #[test]
+    fn after() {
+        let time = Duration::from_millis(1);
+        let active = BusState::default();
+        let warning = active.after(&Packet::new_error(
+            classes(&[ErrorClass::ErrorWarning]),
+            &[],
+            time,
+            0,
+        ));
+        assert_eq!(ErrorState::Warning, warning.error_state);
+        // error frames without a state keep it
+        let ack = Packet::new_error(classes(&[ErrorClass::Ack]), &[], time, 0);
+        assert_eq!(warning, warning.after(&ack));
+        let off = warning.after(&Packet::new_error(
+            classes(&[ErrorClass::BusOff, ErrorClass::ErrorPassive]),
+            &[],
+            time,
+            0,
+        ));
+        assert_eq!(ErrorState::BusOff, off.error_state);
+        let restarted = Packet::new_error(classes(&[ErrorClass::Restarted]), &[], time, 0);
+        assert_eq!(active, off.after(&restarted));
+        // a status report without a state is error active, but gap markers aren't reports
+        let status = Packet::new_status(classes(&[ErrorClass::Overrun]), time, 0);
+        assert_eq!(active, warning.after(&status));
+        assert_eq!(warning, warning.after(&Packet::new_gap(3, &ack)));
+    }
+
+    #[test]
+    fn from_counters() {
+        assert_eq!(ErrorState::Active, ErrorState::from_counters(95, 0));
+        assert_eq!(ErrorState::Warning, ErrorState::from_counters(0, 96));
+        assert_eq!(ErrorState::Passive, ErrorState::from_counters(128, 0));
+        let state = BusState {
+            error_state: ErrorState::Passive,
+            tx_errors: Some(130),
+            rx_errors: Some(0),
+        };
+        assert_eq!("Passive tx 130 rx 0", state.to_string());
+    }
+
+    #[test]
+    fn monitor() {
+        let monitor = BusMonitor::new("test");
+        let mut events = monitor.events();
+        let time = Duration::from_millis(5);
+        let ack = Packet::new_error(classes(&[ErrorClass::Ack]), &[], time, 1);
+        assert!(!monitor.report(&ack, None));
+        let passive = BusState {
+            error_state: ErrorState::Passive,
+            tx_errors: Some(128),
+            rx_errors: Some(0),
+        };
+        assert!(monitor.report(&ack, Some(passive)));
+        assert_eq!(passive, monitor.state());
+
+        let event = events.find_map(|e| e).unwrap();
+        assert_eq!(1, event.channel);
+        assert_eq!(time, event.time.elapsed);
+        assert!(event.errors.contains(ErrorClass::Ack));
+        assert_eq!(ErrorState::Active, event.state.error_state);
+        assert_eq!(Some(passive), events.find_map(|e| e).map(|e| e.state));
+
+        // a status report without errors or a change, such as a periodic poll, is not an event
+        let status = Packet::new_status(ErrorClasses::default(), time, 1);
+        assert!(!monitor.report(&status, Some(passive)));
+        assert_eq!(Some(None), events.next());
+    }
+}
@@ -1,7 +1,15 @@
-use std::time::{Duration, Instant};
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
 
-use crate::{packet::Packet, sim, slcan};
-use anyhow::Result;
+use crate::{
+    bus_state::{BusEvent, BusState},
+    error::{Error, Result},
+    packet::{Packet, PacketState, TimeSource, Timestamp},
+    sim, slcan,
+    stats::Stats,
+};
 
 #[cfg(windows)]
 use crate::rp1210;
@@ -28,6 +36,135 @@ impl IntoIterator for &dyn Connection {
     }
 }
 
+/// Time base of one connection.  Connections create one when they open and stamp every packet with it, so all
+/// connections follow the [`Timestamp`] contract.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    start: Instant,
+    anchor: SystemTime,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new()
+    }
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock {
+            start: Instant::now(),
+            anchor: SystemTime::now(),
+        }
+    }
+
+    /// Wall clock time when the clock read zero.
+    pub fn anchor(&self) -> SystemTime {
+        self.anchor
+    }
+
+    /// Host timestamp for now.
+    pub fn now(&self) -> Timestamp {
+        Timestamp::new(self.start.elapsed(), self.anchor, TimeSource::Host)
+    }
+
+    /// Timestamp for a wall clock time from another source, such as the kernel receive time.
+    pub fn at(&self, wall: SystemTime, source: TimeSource) -> Timestamp {
+        Timestamp::new(
+            wall.duration_since(self.anchor).unwrap_or_default(),
+            self.anchor,
+            source,
+        )
+    }
+}
+
+/// Matches identifiers whose bits under `mask` equal those of `id`, like the acceptance filters of CAN controllers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMask {
+    pub id: u32,
+    pub mask: u32,
+}
+
+impl IdMask {
+    pub fn new(id: u32, mask: u32) -> Self {
+        IdMask { id, mask }
+    }
+
+    /// Only `id`.
+    pub fn exact(id: u32) -> Self {
+        IdMask::new(id, 0x1FFF_FFFF)
+    }
+
+    pub fn matches(&self, id: u32) -> bool {
+        (id ^ self.id) & self.mask == 0
+    }
+}
+
+/// Selects the packets of a [`Connection::subscribe`] subscriber.
+#[derive(Clone)]
+pub enum Filter {
+    /// Packets matching any of the masks.  Error and status reports match every list, so sessions still see bus
+    /// problems and dropped packets.
+    Ids(Vec<IdMask>),
+    Predicate(Arc<dyn Fn(&Packet) -> bool + Send + Sync>),
+}
+
+impl Filter {
+    pub fn predicate(predicate: impl Fn(&Packet) -> bool + Send + Sync + 'static) -> Self {
+        Filter::Predicate(Arc::new(predicate))
+    }
+
+    pub fn matches(&self, packet: &Packet) -> bool {
+        match self {
+            Filter::Ids(masks) => matches_any(masks, packet),
+            Filter::Predicate(predicate) => predicate(packet),
+        }
+    }
+}
+
+fn matches_any(masks: &[IdMask], packet: &Packet) -> bool {
+    matches!(
+        packet.state,
+        PacketState::Error { .. } | PacketState::Status { .. }
+    ) || masks.iter().any(|m| m.matches(packet.id))
+}
+
+/// Filters from [`Connection::set_filters`], checked on receive by connections whose adapter filters only
+/// approximately, or not at all.  Empty accepts everything.
+#[derive(Clone, Default)]
+pub(crate) struct Acceptance(Arc<RwLock<Vec<IdMask>>>);
+
+impl Acceptance {
+    pub fn set(&self, filters: &[IdMask]) {
+        *self.0.write().unwrap() = filters.to_vec();
+    }
+
+    pub fn accepts(&self, packet: &Packet) -> bool {
+        let filters = self.0.read().unwrap();
+        filters.is_empty() || matches_any(&filters, packet)
+    }
+}
+
+impl From<IdMask> for Filter {
+    fn from(mask: IdMask) -> Self {
+        Filter::Ids(vec![mask])
+    }
+}
+
+impl From<Vec<IdMask>> for Filter {
+    fn from(masks: Vec<IdMask>) -> Self {
+        Filter::Ids(masks)
+    }
+}
+
+impl From<&[IdMask]> for Filter {
+    fn from(masks: &[IdMask]) -> Self {
+        Filter::Ids(masks.to_vec())
+    }
+}
+
+/// Packets from [`Connection::iter`] and [`Connection::send`] carry a [`Timestamp`] from the [`Clock`] of the
+/// connection, or from the adapter clock with its own anchor.
 pub trait Connection: Send + Sync {
     /// Send packet on CAN adapter
     fn send(&self, packet: &Packet) -> Result<Packet>;
@@ -35,25 +172,102 @@ pub trait Connection: Send + Sync {
     /// read packets. Some(None) does not indicate end of iterator. Some(None) indicates that a poll() returned None.
     fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync>;
 
+    /// Packets received until `end`.  Ends at `end` even if the bus is quiet, because the clock is checked on the empty
+    /// polls of [`Connection::iter`] too.  Connections with a blocking receive override this to wait without polling.
     fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
-        Box::new(self.iter().filter(|o| o.is_some()).map_while(move |o| {
-            if Instant::now() > end {
-                None
-            } else {
-                o
-            }
-        }))
+        Box::new(
+            self.iter()
+                .take_while(move |_| Instant::now() < end)
+                .flatten(),
+        )
     }
 
     fn iter_for(&self, duration: Duration) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
         self.iter_until(Instant::now() + duration)
     }
+
+    /// Read the packets matching `filter`, with the empty polls of [`Connection::iter`].  Connections with a
+    /// [`PushBus`](crate::pushbus::PushBus) override this to filter on receive, so other packets are never queued for
+    /// the subscriber.
+    fn subscribe(&self, filter: Filter) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        Box::new(self.iter().map(move |p| p.filter(|p| filter.matches(p))))
+    }
+
+    /// Packets matching `filter` received until `end`.
+    fn subscribe_until(
+        &self,
+        filter: Filter,
+        end: Instant,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        Box::new(
+            self.subscribe(filter)
+                .take_while(move |_| Instant::now() < end)
+                .flatten(),
+        )
+    }
+
+    fn subscribe_for(
+        &self,
+        filter: Filter,
+        duration: Duration,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.subscribe_until(filter, Instant::now() + duration)
+    }
+
+    /// Program acceptance filters into the adapter, so it only forwards packets matching one of `filters`, and error
+    /// and status reports.  Empty accepts everything.  [`Connection::send`] still returns the echo of a packet that
+    /// doesn't match, but subscribers don't receive it.
+    fn set_filters(&self, _filters: &[IdMask]) -> Result<()> {
+        Err(Error::Unsupported(
+            "Acceptance filters are not supported by this connection".into(),
+        ))
+    }
+
+    /// Traffic since the connection opened.  `None` if the connection doesn't count it.
+    fn stats(&self) -> Option<Stats> {
+        None
+    }
+
+    /// Current controller state.  `None` if the connection doesn't track it.
+    fn bus_state(&self) -> Option<BusState> {
+        None
+    }
+
+    /// Error and status reports received from now on, each with the state after it, and the empty polls of
+    /// [`Connection::iter`].  Connections that don't track the state derive it from the packets they receive.
+    fn bus_events(&self) -> Box<dyn Iterator<Item = Option<BusEvent>> + Send + Sync> {
+        let mut state = BusState::default();
+        let reports = Filter::predicate(|p| p.errors().is_some() && p.dropped().is_none());
+        Box::new(self.subscribe(reports).map(move |p| {
+            p.map(|p| {
+                state = state.after(&p);
+                BusEvent::new(&p, state)
+            })
+        }))
+    }
 }
 
 pub trait ConnectionFactory {
     fn create(&self) -> Result<Box<dyn Connection>>;
     fn command_line(&self) -> String;
     fn name(&self) -> String;
+
+    /// Create the connection listen only, so it doesn't affect the bus, and [`Connection::send`] fails.  Fails for
+    /// adapters without the mode.
+    fn create_listen_only(&self) -> Result<Box<dyn Connection>> {
+        Err(Error::Unsupported(format!(
+            "{} has no listen only mode",
+            self.name()
+        )))
+    }
+}
+
+/// The error from [`Connection::send`] on a listen only connection.
+pub(crate) fn check_listen_only(listen_only: bool, adapter: &str) -> Result<()> {
+    if listen_only {
+        return Err(Error::ListenOnly(adapter.into()));
+    }
+    Ok(())
 }
 
 pub struct ProtocolDescriptor {
@@ -80,3 +294,143 @@ pub fn enumerate_connections() -> Result<Vec<ProtocolDescriptor>> {
     .filter(|c| !c.devices.is_empty())
     .collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bus_state::ErrorState, packet::ErrorClass, sim::SimulatedConnection};
+
+    /// Only the required methods, to test the provided ones.
+    struct Minimal(SimulatedConnection);
+
+    impl Connection for Minimal {
+        fn send(&self, packet: &Packet) -> Result<Packet> {
+            self.0.send(packet)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+            self.0.iter()
+        }
+    }
+
+    #[test]
+    fn iter_for_quiet_bus() -> Result<()> {
+        const TIMEOUT: Duration = Duration::from_millis(50);
+        let connections: [Box<dyn Connection>; 2] = [
+            Box::new(SimulatedConnection::silent()),
+            Box::new(Minimal(SimulatedConnection::silent())),
+        ];
+        for connection in connections {
+            let start = Instant::now();
+            assert_eq!(0, connection.iter_for(TIMEOUT).count());
+            let elapsed = start.elapsed();
+            assert!(elapsed >= TIMEOUT && elapsed < TIMEOUT * 4, "{elapsed:?}");
+
+            // packets before the deadline are still delivered
+            let iter = connection.iter_for(TIMEOUT);
+            connection.send(&Packet::new(0x18FEF1F9, &[1]))?;
+            assert_eq!(vec![0x18FEF1F9], iter.map(|p| p.id).collect::<Vec<_>>());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn subscribe() -> Result<()> {
+        let connections: [Box<dyn Connection>; 2] = [
+            Box::new(SimulatedConnection::silent()),
+            Box::new(Minimal(SimulatedConnection::silent())),
+        ];
+        for connection in connections {
+            let masks = connection.subscribe_for(
+                vec![IdMask::new(0xECF900, 0xFFFF00), IdMask::exact(0x100)].into(),
+                Duration::from_millis(50),
+            );
+            let predicate = connection.subscribe_for(
+                Filter::predicate(|p| p.payload.len() == 2),
+                Duration::from_millis(50),
+            );
+            for (id, payload) in [
+                (0x18ECF900, &[1][..]),
+                (0x18EC00F9, &[2]),
+                (0x100, &[3, 3]),
+                (0x18EBF900, &[4]),
+            ] {
+                connection.send(&Packet::new(id, payload))?;
+            }
+            // both deadlines run at once
+            let masks = std::thread::spawn(move || masks.map(|p| p.id).collect::<Vec<_>>());
+            assert_eq!(vec![0x100], predicate.map(|p| p.id).collect::<Vec<_>>());
+            assert_eq!(vec![0x18ECF900, 0x100], masks.join().unwrap());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn id_mask() {
+        assert!(IdMask::new(0x18FEF100, 0xFFFF00).matches(0x0CFEF1FE));
+        assert!(!IdMask::new(0x18FEF100, 0xFFFF00).matches(0x18FEF200));
+        assert!(IdMask::exact(0x7E8).matches(0x7E8));
+        assert!(!IdMask::exact(0x7E8).matches(0x7E9));
+
+        let status = Packet::new_status(
+            [ErrorClass::BusOff].into_iter().collect(),
+            Duration::ZERO,
+            0,
+        );
+        assert!(Filter::from(IdMask::exact(0x7E8)).matches(&status));
+    }
+
+    /// Replays packets, for reports the simulator doesn't make.
+    struct Replay(Vec<Packet>);
+
+    impl Connection for Replay {
+        fn send(&self, packet: &Packet) -> Result<Packet> {
+            Ok(packet.clone())
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+            Box::new(self.0.clone().into_iter().map(Some))
+        }
+    }
+
+    #[test]
+    fn bus_events() {
+        let time = Duration::from_millis(1);
+        let error = |errors: &[ErrorClass]| {
+            Packet::new_error(errors.iter().copied().collect(), &[], time, 0)
+        };
+        let ack = error(&[ErrorClass::Ack]);
+        let connection = Replay(vec![
+            error(&[ErrorClass::ErrorPassive]),
+            Packet::new_rx(0x100, &[1], time, 0),
+            Packet::new_gap(2, &ack),
+            ack.clone(),
+            error(&[ErrorClass::BusOff]),
+            error(&[ErrorClass::Restarted]),
+        ]);
+        assert_eq!(None, connection.bus_state());
+        let events: Vec<_> = connection.bus_events().flatten().collect();
+        assert_eq!(
+            vec![
+                ErrorState::Passive,
+                ErrorState::Passive,
+                ErrorState::BusOff,
+                ErrorState::Active
+            ],
+            events
+                .iter()
+                .map(|e| e.state.error_state)
+                .collect::<Vec<_>>()
+        );
+        assert!(events[1].errors.contains(ErrorClass::Ack));
+    }
+
+    #[test]
+    fn listen_only() {
+        assert!(check_listen_only(false, "test").is_ok());
+        assert!(matches!(
+            check_listen_only(true, "test"),
+            Err(Error::ListenOnly(adapter)) if adapter == "test"
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+//! Errors from connections and the protocols on them, so callers can tell a timeout from a NACK or a lost adapter.
+use std::{fmt::Display, io};
+
+/// The error of [`Connection`](crate::connection::Connection), the adapters, [`J1939`](crate::j1939::J1939) and
+/// [`Iso15765`](crate::uds::iso15765::Iso15765).  It converts into `anyhow::Error`, and back without losing the
+/// variant.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Nothing received in time, such as a response, a flow control or a CTS.
+    Timeout(String),
+    /// The adapter didn't echo a sent packet with this id.
+    NoEcho { id: u32 },
+    /// A negative response (`7F`) to `service`, with the response code.
+    Nack { service: u8, code: u8 },
+    /// The other node aborted a J1939 transport session, with the reason from TP.Conn_Abort.
+    Aborted { pgn: u32, reason: u8 },
+    /// A frame or response that doesn't follow the protocol.
+    Protocol(String),
+    /// The adapter is closed or was lost.
+    Disconnected(String),
+    /// Sending on a listen only connection.
+    ListenOnly(String),
+    /// Not supported by the connection or adapter.
+    Unsupported(String),
+    /// A value the adapter can't use, such as a bitrate, an id or a payload length.
+    InvalidArgument(String),
+    /// An error reported by the adapter or its driver.
+    Adapter(String),
+    /// Reading or writing the adapter failed.
+    Io(io::Error),
+    /// Anything else, such as a log file that can't be replayed.
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Timeout(what) => write!(f, "Timed out waiting for {what}"),
+            Error::NoEcho { id } => write!(f, "No echo of {id:08X} from the adapter"),
+            Error::Nack { service, code } => {
+                write!(f, "Negative response to {service:02X}: {code:02X}")
+            }
+            Error::Aborted { pgn, reason } => {
+                write!(f, "Transport of PGN {pgn:04X} aborted, reason {reason}")
+            }
+            Error::Disconnected(adapter) => write!(f, "{adapter} is disconnected"),
+            Error::ListenOnly(adapter) => write!(f, "{adapter} is listen only, so it can't send"),
+            Error::Protocol(msg)
+            | Error::Unsupported(msg)
+            | Error::InvalidArgument(msg)
+            | Error::Adapter(msg) => write!(f, "{msg}"),
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        match e.kind {
+            serialport::ErrorKind::Io(kind) => Error::Io(io::Error::new(kind, e.description)),
+            serialport::ErrorKind::NoDevice => Error::Disconnected(e.description),
+            serialport::ErrorKind::InvalidInput => Error::InvalidArgument(e.description),
+            serialport::ErrorKind::Unknown => Error::Adapter(e.description),
+        }
+    }
+}
+
+/// Unwraps an `Error` that was converted to `anyhow::Error`.
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast().unwrap_or_else(Error::Other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anyhow_round_trip() {
+        let e: anyhow::Error = Error::NoEcho { id: 0x18EAFFF9 }.into();
+        assert!(matches!(Error::from(e), Error::NoEcho { id: 0x18EAFFF9 }));
+        let e = Error::from(anyhow::anyhow!("no file"));
+        assert!(matches!(e, Error::Other(_)));
+        assert_eq!("no file", e.to_string());
+    }
+}
@@ -0,0 +1,625 @@
+//! Vector ASC log files.
+//!
+//! ```text
+//! date Mon Jan 15 10:23:45.123 2024
+//! base hex  timestamps absolute
+//! internal events logged
+//! // version 13.0.0
+//! Begin Triggerblock Mon Jan 15 10:23:45.123 2024
+//!    0.000000 Start of measurement
+//!    0.001000 1 18FEF100x Rx d 8 FF FF FF FF FF FF FF FF
+//!    0.002000 1 7DF Rx r 8
+//!    0.003000 CANFD 1 Rx 1A4 1 0 9 12 00 01 02 03 04 05 06 07 08 09 0A 0B 0 0 3000 0 0 0 0 0
+//!    0.004000 1 ErrorFrame Ack
+//!    0.005000 CAN 1 Status:chip status error passive
+//! End TriggerBlock
+//! ```
+//!
+//! ASC channels start at 1.  [`AscReader`] and [`AscWriter`] map them to and from the 0 based [`Packet`] channels.
+use std::{
+    io::{BufRead, Lines, Write},
+    str::SplitWhitespace,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::formats::{civil_from_days, days_from_civil, LogReader, LogWriter};
+use crate::packet::{dlc_to_len, ErrorClass, ErrorClasses, FdFlags, IdType, Packet, PacketState};
+
+/// Flags of a `CANFD` line: extended data length, bit rate switch and error state indicator.
+const FD_EDL: u32 = 1 << 12;
+const FD_BRS: u32 = 1 << 13;
+const FD_ESI: u32 = 1 << 14;
+
+/// Number base of the ids and data bytes, from the `base` header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Base {
+    #[default]
+    Hex,
+    Dec,
+}
+
+impl Base {
+    fn radix(self) -> u32 {
+        match self {
+            Base::Hex => 16,
+            Base::Dec => 10,
+        }
+    }
+}
+
+/// Streaming ASC reader.  Yields a [`Packet`] for each frame, error or status line.  Header lines and other events,
+/// such as `Statistic:` and `SV:`, are skipped.
+pub struct AscReader<R> {
+    lines: Lines<R>,
+    base: Base,
+    relative: bool,
+    last: Duration,
+    start: Option<SystemTime>,
+}
+
+impl<R: BufRead> AscReader<R> {
+    pub fn new(reader: R) -> Self {
+        AscReader {
+            lines: reader.lines(),
+            base: Base::Hex,
+            relative: false,
+            last: Duration::ZERO,
+            start: None,
+        }
+    }
+
+    /// Wall clock time of the start of the measurement, from the `date` header.
+    pub fn start(&self) -> Option<SystemTime> {
+        self.start
+    }
+
+    fn header(&mut self, line: &str) -> bool {
+        if let Some(date) = line.strip_prefix("date ") {
+            self.start = parse_date(date);
+        } else if line.starts_with("base ") {
+            let mut words = line.split_whitespace();
+            while let Some(word) = words.next() {
+                match (word, words.next()) {
+                    ("base", Some("dec")) => self.base = Base::Dec,
+                    ("base", Some("hex")) => self.base = Base::Hex,
+                    ("timestamps", Some(ts)) => self.relative = ts == "relative",
+                    _ => {}
+                }
+            }
+        } else {
+            // Begin Triggerblock, End TriggerBlock, comments, etc.
+            return line
+                .split_whitespace()
+                .next()
+                .is_none_or(|t| t.parse::<f64>().is_err());
+        }
+        true
+    }
+}
+
+impl<R: BufRead> Iterator for AscReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if self.header(&line) {
+                continue;
+            }
+            if !is_frame(&line) {
+                // relative times are from the previous event, even if it isn't a frame
+                if self.relative {
+                    if let Some(Ok(time)) = line.split_whitespace().next().map(parse_time) {
+                        self.last += time;
+                    }
+                }
+                continue;
+            }
+            return Some(parse_line(&line, self.base).map(|p| {
+                let time = p.time().unwrap_or_default();
+                self.last = if self.relative {
+                    self.last + time
+                } else {
+                    time
+                };
+                let channel = p.channel().unwrap_or_default().saturating_sub(1);
+                p.with_time(self.last).with_channel(channel)
+            }));
+        }
+    }
+}
+
+impl<R: BufRead> LogReader for AscReader<R> {
+    fn start(&self) -> Option<SystemTime> {
+        AscReader::start(self)
+    }
+}
+
+/// Streaming ASC writer.  The header is written on creation and the trailer on [`AscWriter::finish`] or drop.
+pub struct AscWriter<W: Write> {
+    writer: W,
+    last: Duration,
+    finished: bool,
+}
+
+impl<W: Write> AscWriter<W> {
+    /// `start` is the wall clock time of the start of the measurement.
+    pub fn new(mut writer: W, start: SystemTime) -> Result<Self> {
+        let date = format_date(start);
+        writeln!(writer, "date {date}")?;
+        writeln!(writer, "base hex  timestamps absolute")?;
+        writeln!(writer, "internal events logged")?;
+        writeln!(writer, "// version 13.0.0")?;
+        writeln!(writer, "Begin Triggerblock {date}")?;
+        writeln!(writer, "{:11.6} Start of measurement", 0.0)?;
+        Ok(AscWriter {
+            writer,
+            last: Duration::ZERO,
+            finished: false,
+        })
+    }
+}
+
+impl<W: Write> LogWriter for AscWriter<W> {
+    /// Packets that have not been sent yet have no time, so they are logged with the time of the previous packet.
+    fn write(&mut self, packet: &Packet) -> Result<()> {
+        if let Some(time) = packet.time() {
+            self.last = time;
+        }
+        let time = format!("{:11.6}", self.last.as_secs_f64());
+        let channel = packet.channel().unwrap_or_default() + 1;
+        writeln!(self.writer, "{}", format_line(packet, &time, channel))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.finished {
+            self.finished = true;
+            writeln!(self.writer, "End TriggerBlock")?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for AscWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Format one ASC line.  `time` is preformatted so that callers can choose the precision.
+pub fn format_line(p: &Packet, time: &str, channel: u32) -> String {
+    let id = format!("{:X}{}", p.id, if p.is_extended() { "x" } else { "" });
+    let dir = if p.is_tx() { "Tx" } else { "Rx" };
+    let line = match p.state {
+        PacketState::Remote { dlc, .. } => format!("{time} {channel} {id} {dir} r {dlc:X}"),
+        PacketState::Error { errors, .. } => format!("{time} {channel} ErrorFrame {errors}"),
+        PacketState::Status { errors, .. } => {
+            format!("{time} CAN {channel} Status:{}", status_text(errors))
+        }
+        _ => match p.fd {
+            // the message duration and length, crc and bit timings aren't known, so they are 0
+            Some(fd) => format!(
+                "{time} CANFD {channel} {dir} {id} {} {} {:X} {} {} 0 0 {:X} 0 0 0 0 0",
+                fd.brs as u8,
+                fd.esi as u8,
+                p.dlc(),
+                p.payload.len(),
+                p.payload_str(),
+                FD_EDL | if fd.brs { FD_BRS } else { 0 } | if fd.esi { FD_ESI } else { 0 }
+            ),
+            None => format!(
+                "{time} {channel} {id} {dir} d {} {}",
+                p.payload.len(),
+                p.payload_str()
+            ),
+        },
+    };
+    line.trim_end().to_string()
+}
+
+/// Vector style chip status, followed by any other error classes.
+fn status_text(errors: ErrorClasses) -> String {
+    let state = if errors.contains(ErrorClass::BusOff) {
+        ErrorClass::BusOff
+    } else if errors.contains(ErrorClass::ErrorPassive) {
+        ErrorClass::ErrorPassive
+    } else if errors.contains(ErrorClass::ErrorWarning) {
+        ErrorClass::ErrorWarning
+    } else {
+        ErrorClass::Controller
+    };
+    let text = match state {
+        ErrorClass::BusOff => "chip status busoff",
+        ErrorClass::ErrorPassive => "chip status error passive",
+        ErrorClass::ErrorWarning => "chip status warning level",
+        _ => "chip status error active",
+    };
+    let others: ErrorClasses = errors.iter().filter(|c| *c != state).collect();
+    if others.is_empty() {
+        text.to_string()
+    } else {
+        format!("{text} - {others}")
+    }
+}
+
+struct Tokens<'a>(SplitWhitespace<'a>);
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self, what: &str) -> Result<&'a str> {
+        self.0.next().ok_or_else(|| anyhow!("Missing {what}"))
+    }
+}
+
+/// Whether an event line is a frame, error frame or status line, as opposed to events such as `Start of measurement`,
+/// `Statistic:` or `SV:`.
+fn is_frame(line: &str) -> bool {
+    let t: Vec<&str> = line.split_whitespace().take(4).collect();
+    match t[..] {
+        [_, "CANFD", ..] => true,
+        [_, "CAN", ..] => line.contains("Status:"),
+        [_, channel, id, ..] if channel.parse::<u32>().is_ok() && id == "ErrorFrame" => true,
+        [_, channel, _, "Rx" | "Tx"] => channel.parse::<u32>().is_ok(),
+        _ => false,
+    }
+}
+
+/// Seconds, which must not be negative.
+fn parse_time(time: &str) -> Result<Duration> {
+    let secs: f64 = time
+        .parse()
+        .map_err(|e| anyhow!("Invalid time: {e} {time:?}"))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| anyhow!("Invalid time: {e} {time:?}"))
+}
+
+/// Parse one ASC event line.  The time and channel are returned as written.
+pub fn parse_line(line: &str, base: Base) -> Result<Packet> {
+    let mut t = Tokens(line.split_whitespace());
+    let time = parse_time(t.next("time")?)?;
+    match t.next("channel")? {
+        "CANFD" => parse_fd(time, t, base),
+        "CAN" => {
+            let channel = parse_channel(t.next("channel")?)?;
+            let status = line
+                .split_once("Status:")
+                .ok_or_else(|| anyhow!("Missing status: {line}"))?
+                .1;
+            Ok(Packet::new_status(parse_status(status), time, channel))
+        }
+        channel => {
+            let channel = parse_channel(channel)?;
+            let id = t.next("id")?;
+            if id == "ErrorFrame" {
+                let errors = t.0.filter_map(|p| p.parse().ok()).collect();
+                return Ok(Packet::new_error(errors, &[], time, channel));
+            }
+            let (id, id_type) = parse_id(id, base)?;
+            let state = parse_dir(t.next("xmit")?, time, channel)?;
+            match t.next("frame type")? {
+                "d" => {
+                    let len = t.next("length")?;
+                    let len = len
+                        .parse::<usize>()
+                        .map_err(|e| anyhow!("Invalid length: {e} {len:?}"))?;
+                    let payload = parse_payload(&mut t, len, base)?;
+                    Ok(Packet {
+                        state,
+                        ..Packet::new(id, &payload).with_id_type(id_type)
+                    })
+                }
+                "r" => {
+                    let dlc =
+                        t.0.next()
+                            .map(|d| u8::from_str_radix(d, 16))
+                            .transpose()?
+                            .unwrap_or_default();
+                    Ok(Packet::new_remote_rx(id, dlc, time, channel).with_id_type(id_type))
+                }
+                other => Err(anyhow!("Invalid frame type: {other}")),
+            }
+        }
+    }
+}
+
+/// `<time> CANFD <channel> <dir> <id> [<name>] <brs> <esi> <dlc> <len> <data>... <duration> <length> <flags> <crc>
+/// <timings>...`.  The fields after the data are ignored.
+fn parse_fd(time: Duration, mut t: Tokens, base: Base) -> Result<Packet> {
+    let channel = parse_channel(t.next("channel")?)?;
+    let state = parse_dir(t.next("xmit")?, time, channel)?;
+    let (id, id_type) = parse_id(t.next("id")?, base)?;
+    let mut brs = t.next("brs")?;
+    if brs != "0" && brs != "1" {
+        // symbolic name
+        brs = t.next("brs")?;
+    }
+    let esi = t.next("esi")?;
+    let dlc = u8::from_str_radix(t.next("dlc")?, 16)?;
+    let len = t.next("length")?.parse::<usize>()?;
+    if len != dlc_to_len(dlc) {
+        return Err(anyhow!(
+            "Length {len} does not match DLC {dlc} ({})",
+            dlc_to_len(dlc)
+        ));
+    }
+    let payload = parse_payload(&mut t, len, base)?;
+    let flags = FdFlags {
+        brs: brs == "1",
+        esi: esi == "1",
+    };
+    Ok(Packet {
+        state,
+        ..Packet::new_fd(id, &payload, flags)?.with_id_type(id_type)
+    })
+}
+
+fn parse_channel(channel: &str) -> Result<u32> {
+    channel
+        .parse()
+        .map_err(|e| anyhow!("Invalid channel: {e} {channel:?}"))
+}
+
+fn parse_dir(dir: &str, time: Duration, channel: u32) -> Result<PacketState> {
+    match dir {
+        "Rx" => Ok(PacketState::RX {
+            time: time.into(),
+            channel,
+        }),
+        "Tx" => Ok(PacketState::Echo {
+            time: time.into(),
+            channel,
+        }),
+        _ => Err(anyhow!("Invalid xmit value: {dir}")),
+    }
+}
+
+/// Extended ids end in `x`.
+fn parse_id(id: &str, base: Base) -> Result<(u32, IdType)> {
+    let (digits, id_type) = match id.strip_suffix('x') {
+        Some(id) => (id, IdType::Extended),
+        None => (id, IdType::Standard),
+    };
+    let id =
+        u32::from_str_radix(digits, base.radix()).map_err(|e| anyhow!("Invalid id: {e} {id:?}"))?;
+    Ok((id, id_type))
+}
+
+fn parse_payload(t: &mut Tokens, len: usize, base: Base) -> Result<Vec<u8>> {
+    (0..len)
+        .map(|_| {
+            let b = t.next("payload byte")?;
+            u8::from_str_radix(b, base.radix())
+                .map_err(|e| anyhow!("Invalid payload byte: {e} {b:?}"))
+        })
+        .collect()
+}
+
+/// `chip status error passive - Overrun`
+fn parse_status(status: &str) -> ErrorClasses {
+    let (state, rest) = status.split_once(" - ").unwrap_or((status, ""));
+    let mut errors: ErrorClasses = rest
+        .split_whitespace()
+        .filter_map(|p| p.parse().ok())
+        .collect();
+    let state = state.trim();
+    if state.ends_with("busoff") {
+        errors.insert(ErrorClass::BusOff);
+    } else if state.ends_with("error passive") {
+        errors.insert(ErrorClass::ErrorPassive);
+    } else if state.ends_with("warning level") {
+        errors.insert(ErrorClass::ErrorWarning);
+    }
+    errors
+}
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// `Mon Jan 15 10:23:45.123 2024` in UTC.
+pub(crate) fn format_date(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let days = (secs / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02}.{:03} {}",
+        DAYS[((days + 4) % 7) as usize],
+        MONTHS[month as usize - 1],
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        since.subsec_millis(),
+        year
+    )
+}
+
+/// Accepts 24 hour times and 12 hour times with `am`/`pm`.
+pub(crate) fn parse_date(date: &str) -> Option<SystemTime> {
+    let words: Vec<&str> = date.split_whitespace().collect();
+    let (month, day, time, rest) = match words.as_slice() {
+        [_, month, day, time, rest @ ..] => (month, day, time, rest),
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+    let day: u32 = day.parse().ok()?;
+    let (pm, year) = match rest {
+        [ampm, year] => (Some(ampm.eq_ignore_ascii_case("pm")), year),
+        [year] => (None, year),
+        _ => return None,
+    };
+    let year: i64 = year.parse().ok()?;
+    let mut hms = time.split(':');
+    let mut hour: u64 = hms.next()?.parse().ok()?;
+    let minute: u64 = hms.next()?.parse().ok()?;
+    let seconds: f64 = hms.next()?.parse().ok()?;
+    match pm {
+        Some(true) if hour < 12 => hour += 12,
+        Some(false) if hour == 12 => hour = 0,
+        _ => {}
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86_400 + hour * 3600 + minute * 60;
+    Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::try_from_secs_f64(seconds).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn date() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_705_314_225_123);
+        assert_eq!("Mon Jan 15 10:23:45.123 2024", format_date(t));
+        let parsed = parse_date("Mon Jan 15 10:23:45.123 2024").unwrap();
+        assert_eq!(
+            1_705_314_225_123,
+            parsed.duration_since(UNIX_EPOCH).unwrap().as_millis()
+        );
+        let parsed = parse_date("Mon Jan 15 10:23:45.123 pm 2024").unwrap();
+        assert_eq!(
+            1_705_314_225 + 12 * 3600,
+            parsed.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        );
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let packets = [
+            Packet::new_rx(
+                0x18FEF100,
+                &[1, 2, 3, 4, 5, 6, 7, 8],
+                Duration::from_millis(1),
+                0,
+            ),
+            Packet::new_rx(0x7E8, &[2, 0x41, 0x0C], Duration::from_millis(2), 1)
+                .with_id_type(IdType::Standard),
+            Packet::new_remote_rx(0x7DF, 8, Duration::from_millis(3), 0)
+                .with_id_type(IdType::Standard),
+            Packet::new_rx_fd(
+                0x1A4,
+                &[0x55; 12],
+                FdFlags {
+                    brs: true,
+                    esi: false,
+                },
+                Duration::from_millis(4),
+                0,
+            )?,
+            Packet::new_error(
+                [ErrorClass::Ack].into_iter().collect(),
+                &[],
+                Duration::from_millis(5),
+                0,
+            ),
+            Packet::new_status(
+                [ErrorClass::BusOff].into_iter().collect(),
+                Duration::from_millis(6),
+                0,
+            ),
+        ];
+        let mut buf = Vec::new();
+        {
+            let mut writer = AscWriter::new(&mut buf, start)?;
+            for p in &packets {
+                writer.write(p)?;
+            }
+        }
+        let text = String::from_utf8(buf.clone())?;
+        assert!(text.contains("   0.001000 1 18FEF100x Rx d 8 01 02 03 04 05 06 07 08\n"));
+        assert!(text.contains("   0.002000 2 7E8 Rx d 3 02 41 0C\n"));
+        assert!(text.ends_with("End TriggerBlock\n"));
+
+        let mut reader = AscReader::new(Cursor::new(buf));
+        let read: Vec<Packet> = reader.by_ref().collect::<Result<_>>()?;
+        assert_eq!(Some(start), reader.start());
+        assert_eq!(packets.len(), read.len());
+        for (a, b) in packets.iter().zip(read.iter()) {
+            assert_eq!(a.to_string(), b.to_string());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fd_fields() -> Result<()> {
+        // as written by CANoe, with a symbolic name and the fields after the data
+        let line = "   0.004000 CANFD 1 Rx 1a4x Engine 1 0 9 12 00 01 02 03 04 05 06 07 08 09 0a 0b 130000 \
+                    272 3000 1f8a 0 0 0 0";
+        let p = parse_line(line, Base::Hex)?;
+        assert_eq!(0x1A4, p.id);
+        assert_eq!(12, p.payload.len());
+        let written = format_line(&p, "0.004000", 1);
+        assert_eq!(
+            "0.004000 CANFD 1 Rx 1A4x 1 0 9 12 00 01 02 03 04 05 06 07 08 09 0A 0B 0 0 3000 0 0 0 0 0",
+            written
+        );
+        let q = parse_line(&written, Base::Hex)?;
+        assert_eq!(p.to_string(), q.to_string());
+        assert_eq!(p.fd, q.fd);
+
+        let esi = Packet::new_rx_fd(
+            0x100,
+            &[],
+            FdFlags {
+                brs: false,
+                esi: true,
+            },
+            Duration::ZERO,
+            0,
+        )?;
+        assert!(format_line(&esi, "0", 1).ends_with(" 0 0  0 0 5000 0 0 0 0 0"));
+        Ok(())
+    }
+
+    #[test]
+    fn relative_dec() -> Result<()> {
+        let log = "date Tue Feb 21 02:10:45.417 pm 2023
+base dec  timestamps relative
+no internal events logged
+Begin Triggerblock Tue Feb 21 02:10:45.417 pm 2023
+   0.500000 1  100             Rx   d 2 1 255  Length = 0 BitCount = 0 ID = 256
+   0.250000 2  4096x           Tx   d 1 16
+   0.250000 1  Statistic: D 0 R 0 XD 0 XR 0 E 0 O 0 B 0.00%
+   0.000000 SV: 8 0 1 ::Engine::Speed = 0
+   0.250000 1  200             Rx   d 1 7
+   0.250000 1  300             Rx   d 2 1
+End TriggerBlock
+";
+        let packets: Vec<Result<Packet>> = AscReader::new(Cursor::new(log)).collect();
+        assert_eq!(4, packets.len());
+        let p = packets[0].as_ref().unwrap();
+        assert_eq!(0x64, p.id);
+        assert_eq!(IdType::Standard, p.id_type);
+        assert_eq!([1, 0xFF][..], p.payload[..]);
+        assert_eq!(Some(0), p.channel());
+        let p = packets[1].as_ref().unwrap();
+        assert_eq!(0x1000, p.id);
+        assert!(p.is_tx());
+        assert_eq!(Some(Duration::from_millis(750)), p.time());
+        assert_eq!(Some(1), p.channel());
+        // other events are skipped, but their times still count
+        let p = packets[2].as_ref().unwrap();
+        assert_eq!(0xC8, p.id);
+        assert_eq!(Some(Duration::from_millis(1250)), p.time());
+        // a frame that is cut short is an error
+        assert!(packets[3].is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_time() {
+        assert!(parse_line("  -0.5 1 18FEF100x Rx d 1 00", Base::Hex).is_err());
+        assert!(parse_line("   NaN 1 18FEF100x Rx d 1 00", Base::Hex).is_err());
+        assert!(parse_date("Mon Jan 15 10:23:-45.123 2024").is_none());
+    }
+}
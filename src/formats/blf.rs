@@ -0,0 +1,793 @@
+//! Vector BLF binary log files.
+//!
+//! A 144 byte `LOGG` file header is followed by `LOBJ` objects.  Frames are stored in zlib compressed `LOG_CONTAINER`
+//! objects, and a frame object may be split across two containers.  All values are little endian.
+//!
+//! Supported objects are `CAN_MESSAGE`, `CAN_MESSAGE2`, `CAN_ERROR_EXT`, `CAN_FD_MESSAGE` and `CAN_FD_MESSAGE_64`.
+//! Others are skipped.  BLF channels start at 1.
+//!
+//! The id of the frame in error isn't known, so the `id` of a `CAN_ERROR_EXT` written here holds the error classes,
+//! and whether it is a status report, instead.
+use std::{
+    io::{ErrorKind, Read, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use zerocopy::{
+    little_endian::{U16, U32, U64},
+    FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout, Unaligned,
+};
+
+use crate::{
+    formats::{civil_from_days, days_from_civil, pack_errors, unpack_errors, LogReader, LogWriter},
+    packet::{dlc_to_len, FdFlags, IdType, Packet, PacketState, CAN_MAX_LEN},
+};
+
+const FILE_SIGNATURE: &[u8; 4] = b"LOGG";
+const OBJECT_SIGNATURE: &[u8; 4] = b"LOBJ";
+const FILE_HEADER_SIZE: usize = 144;
+
+const CAN_MESSAGE: u32 = 1;
+const LOG_CONTAINER: u32 = 10;
+const CAN_ERROR_EXT: u32 = 73;
+const CAN_MESSAGE2: u32 = 86;
+const CAN_FD_MESSAGE: u32 = 100;
+const CAN_FD_MESSAGE_64: u32 = 101;
+
+const NO_COMPRESSION: u16 = 0;
+const ZLIB_DEFLATE: u16 = 2;
+
+/// Object header timestamp units.
+const TIME_TEN_MICS: u32 = 1;
+const TIME_ONE_NANS: u32 = 2;
+
+/// CAN_MESSAGE flags
+const TX: u8 = 0x01;
+const REMOTE: u8 = 0x80;
+/// id flag for 29 bit ids
+const EXTENDED: u32 = 0x8000_0000;
+
+/// CAN_FD_MESSAGE flags
+const FD_EDL: u8 = 0x01;
+const FD_BRS: u8 = 0x02;
+const FD_ESI: u8 = 0x04;
+
+/// CAN_FD_MESSAGE_64 flags
+const FD64_REMOTE: u32 = 0x0010;
+const FD64_EDL: u32 = 0x1000;
+const FD64_BRS: u32 = 0x2000;
+const FD64_ESI: u32 = 0x4000;
+
+/// Uncompressed size of each container.
+const CONTAINER_SIZE: usize = 128 * 1024;
+/// Maximum time frames are buffered, so that little is lost when a live log is interrupted.
+const CONTAINER_INTERVAL: Duration = Duration::from_secs(1);
+/// Largest object or uncompressed container read, so a corrupt size doesn't exhaust memory.
+const MAX_OBJECT_SIZE: usize = 16 * 1024 * 1024;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct BlfTime {
+    year: U16,
+    month: U16,
+    day_of_week: U16,
+    day: U16,
+    hour: U16,
+    minute: U16,
+    second: U16,
+    milliseconds: U16,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct FileHeader {
+    signature: [u8; 4],
+    header_size: U32,
+    application_id: u8,
+    application_major: u8,
+    application_minor: u8,
+    application_build: u8,
+    bin_log_major: u8,
+    bin_log_minor: u8,
+    bin_log_build: u8,
+    bin_log_patch: u8,
+    file_size: U64,
+    uncompressed_size: U64,
+    object_count: U32,
+    objects_read: U32,
+    start: BlfTime,
+    stop: BlfTime,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct ObjectHeaderBase {
+    signature: [u8; 4],
+    header_size: U16,
+    header_version: U16,
+    object_size: U32,
+    object_type: U32,
+}
+
+/// Version 1 object header.  The version 2 header has the same flags and timestamp offsets.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct ObjectHeader {
+    base: ObjectHeaderBase,
+    flags: U32,
+    client_index: U16,
+    object_version: U16,
+    timestamp: U64,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct LogContainer {
+    compression_method: U16,
+    reserved1: [u8; 6],
+    uncompressed_size: U32,
+    reserved2: [u8; 4],
+}
+
+/// CAN_MESSAGE, and the start of CAN_MESSAGE2.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct CanMessage {
+    channel: U16,
+    flags: u8,
+    dlc: u8,
+    id: U32,
+    data: [u8; 8],
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct CanMessage2 {
+    message: CanMessage,
+    frame_length: U32,
+    bit_count: u8,
+    reserved1: u8,
+    reserved2: U16,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct CanErrorExt {
+    channel: U16,
+    length: U16,
+    flags: U32,
+    ecc: u8,
+    position: u8,
+    dlc: u8,
+    reserved1: u8,
+    frame_length: U32,
+    id: U32,
+    flags_ext: U16,
+    reserved2: U16,
+    data: [u8; 8],
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct CanFdMessage {
+    channel: U16,
+    flags: u8,
+    dlc: u8,
+    id: U32,
+    frame_length: U32,
+    arb_bit_count: u8,
+    fd_flags: u8,
+    valid_bytes: u8,
+    reserved1: u8,
+    reserved2: U32,
+    data: [u8; 64],
+}
+
+/// Followed by `valid_bytes` of data.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct CanFdMessage64 {
+    channel: u8,
+    dlc: u8,
+    valid_bytes: u8,
+    tx_count: u8,
+    id: U32,
+    frame_length: U32,
+    flags: U32,
+    btr_cfg_arb: U32,
+    btr_cfg_data: U32,
+    time_offset_brs_ns: U32,
+    time_offset_crc_del_ns: U32,
+    bit_count: U16,
+    dir: u8,
+    ext_data_offset: u8,
+    crc: U32,
+}
+
+fn read<T: FromBytes>(bytes: &[u8], what: &str) -> Result<T> {
+    T::read_from_prefix(bytes)
+        .map(|(t, _)| t)
+        .map_err(|_| anyhow!("Truncated {what}"))
+}
+
+/// Streaming BLF reader.  Times are relative to the [`BlfReader::start`] in the file header.
+pub struct BlfReader<R> {
+    reader: R,
+    started: bool,
+    done: bool,
+    start: Option<SystemTime>,
+    /// uncompressed objects
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> BlfReader<R> {
+    pub fn new(reader: R) -> Self {
+        BlfReader {
+            reader,
+            started: false,
+            done: false,
+            start: None,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Wall clock time of the start of the measurement, from the file header.  Available after the first packet is
+    /// read.
+    pub fn start(&self) -> Option<SystemTime> {
+        self.start
+    }
+
+    fn read_header(&mut self) -> Result<()> {
+        let mut bytes = [0; FILE_HEADER_SIZE];
+        self.reader.read_exact(&mut bytes)?;
+        let header: FileHeader = read(&bytes, "file header")?;
+        if &header.signature != FILE_SIGNATURE {
+            return Err(anyhow!("Not a BLF file"));
+        }
+        let mut extra = (header.header_size.get() as usize).saturating_sub(FILE_HEADER_SIZE);
+        while extra > 0 {
+            let n = extra.min(bytes.len());
+            self.reader.read_exact(&mut bytes[..n])?;
+            extra -= n;
+        }
+        self.start = from_blf_time(&header.start);
+        Ok(())
+    }
+
+    /// Make `n` bytes available at `pos`, uncompressing containers as needed.  False at the end of the file.
+    fn fill(&mut self, n: usize) -> Result<bool> {
+        while self.buf.len() - self.pos < n {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+
+            let mut bytes = [0; size_of::<ObjectHeaderBase>()];
+            match self.reader.read_exact(&mut bytes) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                r => r?,
+            }
+            let base: ObjectHeaderBase = read(&bytes, "object header")?;
+            if &base.signature != OBJECT_SIGNATURE {
+                return Err(anyhow!("Invalid object signature {:?}", base.signature));
+            }
+            let size = base.object_size.get() as usize;
+            if size > MAX_OBJECT_SIZE {
+                return Err(anyhow!("Invalid object size {size}"));
+            }
+            let mut object = vec![0; size.saturating_sub(bytes.len())];
+            self.reader.read_exact(&mut object)?;
+            // padding, which may be missing at the end of the file
+            let mut padding = [0; 4];
+            let _ = self.reader.read_exact(&mut padding[..size % 4]);
+
+            if base.object_type.get() == LOG_CONTAINER {
+                let offset = (base.header_size.get() as usize).saturating_sub(bytes.len());
+                let container = object.get(offset..).unwrap_or_default();
+                let data = container
+                    .get(size_of::<LogContainer>()..)
+                    .unwrap_or_default();
+                let container: LogContainer = read(container, "LOG_CONTAINER")?;
+                match container.compression_method.get() {
+                    NO_COMPRESSION => self.buf.extend_from_slice(data),
+                    ZLIB_DEFLATE => {
+                        ZlibDecoder::new(data)
+                            .take(MAX_OBJECT_SIZE as u64)
+                            .read_to_end(&mut self.buf)?;
+                    }
+                    m => return Err(anyhow!("Unsupported compression method {m}")),
+                }
+            } else {
+                self.buf.extend_from_slice(&bytes);
+                self.buf.extend_from_slice(&object);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Decode the next object.  `None` for unsupported objects.
+    fn next_object(&mut self) -> Result<Option<Option<Packet>>> {
+        if !self.fill(size_of::<ObjectHeader>())? {
+            return Ok(None);
+        }
+        // skip padding between objects
+        if !self.buf[self.pos..].starts_with(OBJECT_SIGNATURE) {
+            self.pos += 1;
+            return Ok(Some(None));
+        }
+        let header: ObjectHeader = read(&self.buf[self.pos..], "object header")?;
+        let size = header.base.object_size.get() as usize;
+        if size < size_of::<ObjectHeaderBase>() {
+            return Err(anyhow!("Invalid object size {size}"));
+        }
+        if !self.fill(size)? {
+            return Err(anyhow!("Truncated object"));
+        }
+        let timestamp = header.timestamp.get();
+        let time = match header.flags.get() {
+            TIME_TEN_MICS => Duration::from_micros(timestamp * 10),
+            _ => Duration::from_nanos(timestamp),
+        };
+        let start = self.pos + header.base.header_size.get() as usize;
+        let body = &self.buf[start.min(self.pos + size)..self.pos + size];
+        let packet = decode(header.base.object_type.get(), body, time);
+        self.pos += size;
+        packet.map(Some)
+    }
+}
+
+impl<R: Read> Iterator for BlfReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if !self.started {
+            self.started = true;
+            if let Err(e) = self.read_header() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        loop {
+            match self.next_object() {
+                Ok(Some(Some(packet))) => return Some(Ok(packet)),
+                Ok(Some(None)) => continue,
+                Ok(None) => return None,
+                Err(e) => {
+                    // resynchronize on the next object
+                    self.pos = (self.pos + 1).min(self.buf.len());
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> LogReader for BlfReader<R> {
+    fn start(&self) -> Option<SystemTime> {
+        BlfReader::start(self)
+    }
+}
+
+fn channel(channel: u16) -> u32 {
+    (channel as u32).saturating_sub(1)
+}
+
+fn id(id: u32) -> (u32, IdType) {
+    if id & EXTENDED != 0 {
+        (id & !EXTENDED, IdType::Extended)
+    } else {
+        (id, IdType::Standard)
+    }
+}
+
+fn direction(packet: Packet, tx: bool) -> Packet {
+    match packet.state {
+        PacketState::RX { time, channel } if tx => Packet {
+            state: PacketState::Echo { time, channel },
+            ..packet
+        },
+        _ => packet,
+    }
+}
+
+fn decode(object_type: u32, body: &[u8], time: Duration) -> Result<Option<Packet>> {
+    let packet = match object_type {
+        CAN_MESSAGE | CAN_MESSAGE2 => {
+            let m: CanMessage = read(body, "CAN_MESSAGE")?;
+            let (id, id_type) = id(m.id.get());
+            let channel = channel(m.channel.get());
+            let packet = if m.flags & REMOTE != 0 {
+                Packet::new_remote_rx(id, m.dlc, time, channel)
+            } else {
+                let len = dlc_to_len(m.dlc).min(CAN_MAX_LEN);
+                Packet::new_rx(id, &m.data[..len], time, channel)
+            };
+            direction(packet.with_id_type(id_type), m.flags & TX != 0)
+        }
+        CAN_ERROR_EXT => {
+            let e: CanErrorExt = read(body, "CAN_ERROR_EXT")?;
+            let len = (e.dlc as usize).min(CAN_MAX_LEN);
+            let channel = channel(e.channel.get());
+            unpack_errors(e.id.get(), &e.data[..len], time, channel)
+        }
+        CAN_FD_MESSAGE => {
+            let m: CanFdMessage = read(body, "CAN_FD_MESSAGE")?;
+            let (id, id_type) = id(m.id.get());
+            let channel = channel(m.channel.get());
+            let len = (m.valid_bytes as usize).min(m.data.len());
+            let packet = if m.fd_flags & FD_EDL != 0 {
+                let flags = FdFlags {
+                    brs: m.fd_flags & FD_BRS != 0,
+                    esi: m.fd_flags & FD_ESI != 0,
+                };
+                Packet::new_rx_fd(id, &m.data[..len], flags, time, channel)?
+            } else if m.flags & REMOTE != 0 {
+                Packet::new_remote_rx(id, m.dlc, time, channel)
+            } else {
+                Packet::new_rx(id, &m.data[..len], time, channel)
+            };
+            direction(packet.with_id_type(id_type), m.flags & TX != 0)
+        }
+        CAN_FD_MESSAGE_64 => {
+            let m: CanFdMessage64 = read(body, "CAN_FD_MESSAGE_64")?;
+            let data = &body[size_of::<CanFdMessage64>()..];
+            let data = &data[..(m.valid_bytes as usize).min(data.len())];
+            let (id, id_type) = id(m.id.get());
+            let channel = channel(m.channel as u16);
+            let flags = m.flags.get();
+            let packet = if flags & FD64_EDL != 0 {
+                let flags = FdFlags {
+                    brs: flags & FD64_BRS != 0,
+                    esi: flags & FD64_ESI != 0,
+                };
+                Packet::new_rx_fd(id, data, flags, time, channel)?
+            } else if flags & FD64_REMOTE != 0 {
+                Packet::new_remote_rx(id, m.dlc, time, channel)
+            } else {
+                Packet::new_rx(id, data, time, channel)
+            };
+            direction(packet.with_id_type(id_type), m.dir != 0)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(packet))
+}
+
+/// Streaming BLF writer.  Frames are buffered for up to a second and written in compressed containers.  The remainder
+/// is written on [`LogWriter::finish`] or drop.
+///
+/// The file size and object count in the file header are left zero because the output may not be seekable.
+pub struct BlfWriter<W: Write> {
+    writer: W,
+    buf: Vec<u8>,
+    last: Duration,
+    written: Instant,
+    finished: bool,
+}
+
+impl<W: Write> BlfWriter<W> {
+    /// `start` is the wall clock time of the start of the measurement.
+    pub fn new(mut writer: W, start: SystemTime) -> Result<Self> {
+        let header = FileHeader {
+            signature: *FILE_SIGNATURE,
+            header_size: U32::new(FILE_HEADER_SIZE as u32),
+            application_id: 0,
+            application_major: 0,
+            application_minor: 0,
+            application_build: 0,
+            bin_log_major: 2,
+            bin_log_minor: 6,
+            bin_log_build: 8,
+            bin_log_patch: 1,
+            file_size: U64::ZERO,
+            uncompressed_size: U64::ZERO,
+            object_count: U32::ZERO,
+            objects_read: U32::ZERO,
+            start: to_blf_time(start),
+            stop: BlfTime::default(),
+        };
+        let mut bytes = header.as_bytes().to_vec();
+        bytes.resize(FILE_HEADER_SIZE, 0);
+        writer.write_all(&bytes)?;
+        Ok(BlfWriter {
+            writer,
+            buf: Vec::with_capacity(CONTAINER_SIZE),
+            last: Duration::ZERO,
+            written: Instant::now(),
+            finished: false,
+        })
+    }
+
+    fn object(&mut self, object_type: u32, body: &[&[u8]]) {
+        let size = size_of::<ObjectHeader>() + body.iter().map(|b| b.len()).sum::<usize>();
+        let header = ObjectHeader {
+            base: ObjectHeaderBase {
+                signature: *OBJECT_SIGNATURE,
+                header_size: U16::new(size_of::<ObjectHeader>() as u16),
+                header_version: U16::new(1),
+                object_size: U32::new(size as u32),
+                object_type: U32::new(object_type),
+            },
+            flags: U32::new(TIME_ONE_NANS),
+            client_index: U16::ZERO,
+            object_version: U16::ZERO,
+            timestamp: U64::new(self.last.as_nanos() as u64),
+        };
+        self.buf.extend_from_slice(header.as_bytes());
+        body.iter().for_each(|b| self.buf.extend_from_slice(b));
+        self.buf.resize(self.buf.len() + size % 4, 0);
+    }
+
+    fn write_container(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buf)?;
+        let data = encoder.finish()?;
+        let size = size_of::<ObjectHeaderBase>() + size_of::<LogContainer>() + data.len();
+        let base = ObjectHeaderBase {
+            signature: *OBJECT_SIGNATURE,
+            header_size: U16::new(size_of::<ObjectHeaderBase>() as u16),
+            header_version: U16::new(1),
+            object_size: U32::new(size as u32),
+            object_type: U32::new(LOG_CONTAINER),
+        };
+        let container = LogContainer {
+            compression_method: U16::new(ZLIB_DEFLATE),
+            reserved1: [0; 6],
+            uncompressed_size: U32::new(self.buf.len() as u32),
+            reserved2: [0; 4],
+        };
+        self.writer.write_all(base.as_bytes())?;
+        self.writer.write_all(container.as_bytes())?;
+        self.writer.write_all(&data)?;
+        self.writer.write_all(&[0; 4][..size % 4])?;
+        self.writer.flush()?;
+        self.buf.clear();
+        self.written = Instant::now();
+        Ok(())
+    }
+}
+
+impl<W: Write> LogWriter for BlfWriter<W> {
+    /// Packets that have not been sent yet have no time, so they are logged with the time of the previous packet.
+    fn write(&mut self, packet: &Packet) -> Result<()> {
+        if let Some(time) = packet.time() {
+            self.last = time;
+        }
+        let channel = packet.channel().unwrap_or_default() + 1;
+        let id = if packet.is_extended() {
+            packet.id | EXTENDED
+        } else {
+            packet.id
+        };
+        let tx = packet.is_tx();
+        match (&packet.state, packet.fd) {
+            (PacketState::Error { .. } | PacketState::Status { .. }, _) => {
+                let len = packet.payload.len().min(CAN_MAX_LEN);
+                let mut e = CanErrorExt::new_zeroed();
+                e.channel = U16::new(channel as u16);
+                e.id = U32::new(pack_errors(packet));
+                e.length = U16::new(size_of::<CanErrorExt>() as u16);
+                e.dlc = len as u8;
+                e.data[..len].copy_from_slice(&packet.payload[..len]);
+                self.object(CAN_ERROR_EXT, &[e.as_bytes()]);
+            }
+            (_, Some(fd)) => {
+                let mut m = CanFdMessage64::new_zeroed();
+                m.channel = channel as u8;
+                m.dlc = packet.dlc();
+                m.valid_bytes = packet.payload.len() as u8;
+                m.id = U32::new(id);
+                let brs = if fd.brs { FD64_BRS } else { 0 };
+                let esi = if fd.esi { FD64_ESI } else { 0 };
+                m.flags = U32::new(FD64_EDL | brs | esi);
+                m.dir = tx as u8;
+                self.object(CAN_FD_MESSAGE_64, &[m.as_bytes(), &packet.payload]);
+            }
+            (state, None) => {
+                let mut m = CanMessage2::new_zeroed();
+                m.message.channel = U16::new(channel as u16);
+                m.message.id = U32::new(id);
+                m.message.flags = if tx { TX } else { 0 };
+                if let PacketState::Remote { dlc, .. } = state {
+                    m.message.flags |= REMOTE;
+                    m.message.dlc = *dlc;
+                } else {
+                    let len = packet.payload.len().min(CAN_MAX_LEN);
+                    m.message.dlc = len as u8;
+                    m.message.data[..len].copy_from_slice(&packet.payload[..len]);
+                }
+                self.object(CAN_MESSAGE2, &[m.as_bytes()]);
+            }
+        }
+        if self.buf.len() >= CONTAINER_SIZE || self.written.elapsed() >= CONTAINER_INTERVAL {
+            self.write_container()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.finished {
+            self.finished = true;
+            self.write_container()?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for BlfWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// BLF SYSTEMTIME in UTC.
+fn to_blf_time(time: SystemTime) -> BlfTime {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let days = (secs / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    BlfTime {
+        year: U16::new(year as u16),
+        month: U16::new(month as u16),
+        day_of_week: U16::new(((days + 4) % 7) as u16),
+        day: U16::new(day as u16),
+        hour: U16::new((secs / 3600 % 24) as u16),
+        minute: U16::new((secs / 60 % 60) as u16),
+        second: U16::new((secs % 60) as u16),
+        milliseconds: U16::new(since.subsec_millis() as u16),
+    }
+}
+
+fn from_blf_time(time: &BlfTime) -> Option<SystemTime> {
+    if time.year.get() == 0 {
+        return None;
+    }
+    let days = days_from_civil(
+        time.year.get() as i64,
+        time.month.get() as u32,
+        time.day.get() as u32,
+    );
+    let secs = u64::try_from(days).ok()? * 86_400
+        + time.hour.get() as u64 * 3600
+        + time.minute.get() as u64 * 60
+        + time.second.get() as u64;
+    Some(
+        UNIX_EPOCH
+            + Duration::from_secs(secs)
+            + Duration::from_millis(time.milliseconds.get() as u64),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::packet::ErrorClass;
+
+    #[test]
+    fn layout() {
+        assert_eq!(72, size_of::<FileHeader>());
+        assert_eq!(32, size_of::<ObjectHeader>());
+        assert_eq!(16, size_of::<LogContainer>());
+        assert_eq!(24, size_of::<CanMessage2>());
+        assert_eq!(32, size_of::<CanErrorExt>());
+        assert_eq!(40, size_of::<CanFdMessage64>());
+        assert_eq!(84, size_of::<CanFdMessage>());
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let start = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        let fd = FdFlags {
+            brs: true,
+            esi: false,
+        };
+        let packets = [
+            Packet::new_rx(
+                0x18FEF100,
+                &[1, 2, 3, 4, 5, 6, 7, 8],
+                Duration::from_millis(1),
+                0,
+            ),
+            Packet {
+                state: PacketState::Echo {
+                    time: Duration::from_millis(2).into(),
+                    channel: 1,
+                },
+                ..Packet::new(0x7E0, &[2, 1, 0]).with_id_type(IdType::Standard)
+            },
+            Packet::new_remote_rx(0x7DF, 8, Duration::from_millis(3), 0)
+                .with_id_type(IdType::Standard),
+            Packet::new_rx_fd(0x18DA00F1, &[0x55; 48], fd, Duration::from_millis(4), 2)?,
+            Packet::new_error(
+                [ErrorClass::Ack, ErrorClass::ErrorPassive]
+                    .into_iter()
+                    .collect(),
+                &[0, 4],
+                Duration::from_millis(5),
+                0,
+            ),
+            Packet::new_status(
+                [ErrorClass::BusOff].into_iter().collect(),
+                Duration::from_millis(6),
+                1,
+            ),
+            Packet::new_gap(3, &Packet::new_rx(0x100, &[], Duration::from_millis(7), 0)),
+        ];
+        let mut buf = Vec::new();
+        {
+            let mut writer = BlfWriter::new(&mut buf, start)?;
+            for p in &packets {
+                writer.write(p)?;
+            }
+        }
+        assert_eq!(FILE_SIGNATURE, &buf[..4]);
+
+        let mut reader = BlfReader::new(Cursor::new(buf));
+        let read: Vec<Packet> = reader.by_ref().collect::<Result<_>>()?;
+        assert_eq!(Some(start), reader.start());
+        assert_eq!(packets.len(), read.len());
+        for (a, b) in packets.iter().zip(read.iter()) {
+            assert_eq!(a.to_string(), b.to_string());
+            assert_eq!((a.errors(), &a.payload), (b.errors(), &b.payload));
+        }
+        assert_eq!(Some(3), read[6].dropped());
+        Ok(())
+    }
+
+    #[test]
+    fn split_containers() -> Result<()> {
+        let mut buf = Vec::new();
+        let count = 2 * CONTAINER_SIZE / size_of::<CanMessage2>();
+        {
+            let mut writer = BlfWriter::new(&mut buf, UNIX_EPOCH)?;
+            for n in 0..count {
+                let payload = (n as u64).to_be_bytes();
+                writer.write(&Packet::new_rx(
+                    0x18FEF100,
+                    &payload,
+                    Duration::from_micros(n as u64),
+                    0,
+                ))?;
+            }
+        }
+        let read: Vec<Packet> = BlfReader::new(Cursor::new(buf)).collect::<Result<_>>()?;
+        assert_eq!(count, read.len());
+        assert_eq!(
+            (count as u64 - 1).to_be_bytes()[..],
+            read[count - 1].payload[..]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn huge_object() -> Result<()> {
+        let mut buf = Vec::new();
+        BlfWriter::new(&mut buf, UNIX_EPOCH)?.finish()?;
+        buf.extend_from_slice(OBJECT_SIGNATURE);
+        buf.extend_from_slice(&[32, 0, 1, 0]);
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        buf.extend_from_slice(&CAN_MESSAGE.to_le_bytes());
+        let mut reader = BlfReader::new(Cursor::new(buf));
+        let e = reader.next().unwrap().unwrap_err();
+        assert!(e.to_string().contains("Invalid object size"), "{e}");
+        Ok(())
+    }
+
+    #[test]
+    fn not_blf() {
+        let mut reader = BlfReader::new(Cursor::new(vec![0; FILE_HEADER_SIZE]));
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,324 @@
+//! Linux can-utils `candump -l` log files.
+//!
+//! ```text
+//! (1700000000.001000) can0 18FEF100#0102030405060708
+//! (1700000000.002000) can0 7DF#R8
+//! (1700000000.003000) can1 1A4##1000102030405060708090A0B
+//! (1700000000.004000) can0 20000020#0000000000000000
+//! (1700000000.005000) can0 7E0#020100 T
+//! ```
+//!
+//! Ids with 3 digits are standard, 8 digits are extended.  `##` is followed by the `CANFD_*` flags nibble.  The optional
+//! trailing `T` marks a frame sent by this host.
+use std::{
+    io::{BufRead, Lines, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    formats::{socketcan, Interfaces, LogReader, LogWriter},
+    packet::{Packet, PacketState},
+};
+
+/// Streaming candump reader.  Times are relative to the first frame, which is the [`CandumpReader::start`].
+pub struct CandumpReader<R> {
+    lines: Lines<R>,
+    interfaces: Interfaces,
+    start: Option<Duration>,
+}
+
+impl<R: BufRead> CandumpReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_interfaces(reader, Interfaces::default())
+    }
+
+    pub fn with_interfaces(reader: R, interfaces: Interfaces) -> Self {
+        CandumpReader {
+            lines: reader.lines(),
+            interfaces,
+            start: None,
+        }
+    }
+
+    /// Wall clock time of the first frame.
+    pub fn start(&self) -> Option<SystemTime> {
+        self.start.map(|s| UNIX_EPOCH + s)
+    }
+}
+
+impl<R: BufRead> Iterator for CandumpReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return Some(parse_line(&line, &self.interfaces).map(|p| {
+                let time = p.time().unwrap_or_default();
+                let start = *self.start.get_or_insert(time);
+                p.with_time(time.saturating_sub(start))
+            }));
+        }
+    }
+}
+
+impl<R: BufRead> LogReader for CandumpReader<R> {
+    fn start(&self) -> Option<SystemTime> {
+        CandumpReader::start(self)
+    }
+}
+
+/// Parse one line.  The time is returned as written, seconds since the epoch.
+pub fn parse_line(line: &str, interfaces: &Interfaces) -> Result<Packet> {
+    let mut parts = line.split_whitespace();
+    let mut next = |what| {
+        parts
+            .next()
+            .ok_or_else(|| anyhow!("Missing {what}: {line}"))
+    };
+    let time = next("time")?;
+    let time = parse_time(time).ok_or_else(|| anyhow!("Invalid time: {time:?}"))?;
+    let channel = interfaces.channel(next("interface")?);
+    let frame = next("frame")?;
+    let echo = next("direction").is_ok_and(|d| d == "T");
+
+    let (id, data) = frame
+        .split_once('#')
+        .ok_or_else(|| anyhow!("Invalid frame: {frame:?}"))?;
+    let mut can_id = u32::from_str_radix(id, 16).map_err(|e| anyhow!("Invalid id: {e} {id:?}"))?;
+    if id.len() > 3 && can_id & socketcan::CAN_ERR_FLAG == 0 {
+        can_id |= socketcan::CAN_EFF_FLAG;
+    }
+    let (fd, data) = if let Some(fd) = data.strip_prefix('#') {
+        let flags = fd
+            .get(..1)
+            .ok_or_else(|| anyhow!("Missing FD flags: {frame:?}"))?;
+        (Some(u8::from_str_radix(flags, 16)?), parse_hex(&fd[1..])?)
+    } else if let Some(dlc) = data.strip_prefix('R') {
+        can_id |= socketcan::CAN_RTR_FLAG;
+        let dlc = dlc.get(..1).map_or(Ok(0), |d| u8::from_str_radix(d, 16))?;
+        (None, vec![0; dlc as usize])
+    } else {
+        (None, parse_hex(data)?)
+    };
+    let packet = socketcan::decode(can_id, &data, fd, time, channel)?;
+    Ok(match packet.state {
+        PacketState::RX { time, channel } if echo => Packet {
+            state: PacketState::Echo { time, channel },
+            ..packet
+        },
+        _ => packet,
+    })
+}
+
+/// `(1700000000.123456)`
+fn parse_time(time: &str) -> Option<Duration> {
+    let time = time.strip_prefix('(')?.strip_suffix(')')?;
+    let (secs, frac) = time.split_once('.').unwrap_or((time, "0"));
+    let nanos: u32 = format!("{frac:0<9}").get(..9)?.parse().ok()?;
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+/// Hex bytes, optionally separated with `.`.
+fn parse_hex(data: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = data.bytes().filter(|b| *b != b'.').collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("Odd number of hex digits: {data:?}"));
+    }
+    digits
+        .chunks(2)
+        .map(|b| {
+            let b = std::str::from_utf8(b)?;
+            u8::from_str_radix(b, 16).map_err(|e| anyhow!("Invalid data: {e} {b:?}"))
+        })
+        .collect()
+}
+
+/// Format one line.  `time` is seconds since the epoch.
+pub fn format_line(p: &Packet, time: Duration, interfaces: &Interfaces) -> String {
+    let can_id = socketcan::can_id(p);
+    let id = if can_id & (socketcan::CAN_EFF_FLAG | socketcan::CAN_ERR_FLAG) != 0 {
+        format!(
+            "{:08X}",
+            can_id & socketcan::CAN_EFF_MASK | can_id & socketcan::CAN_ERR_FLAG
+        )
+    } else {
+        format!("{:03X}", can_id & socketcan::CAN_SFF_MASK)
+    };
+    let data = crate::packet::as_hex_nospace(&socketcan::frame_data(p));
+    let frame = match (&p.state, p.fd) {
+        (PacketState::Remote { dlc: 0, .. }, _) => format!("{id}#R"),
+        (PacketState::Remote { dlc, .. }, _) => format!("{id}#R{dlc:X}"),
+        (_, Some(fd)) if p.is_data() => format!("{id}##{:X}{data}", socketcan::fd_flags(fd)),
+        _ => format!("{id}#{data}"),
+    };
+    let channel = interfaces.name(p.channel().unwrap_or_default());
+    let echo = if matches!(p.state, PacketState::Echo { .. }) {
+        " T"
+    } else {
+        ""
+    };
+    format!(
+        "({}.{:06}) {channel} {frame}{echo}",
+        time.as_secs(),
+        time.subsec_micros()
+    )
+}
+
+/// Streaming candump writer.  Packet times are written relative to `start`.
+pub struct CandumpWriter<W: Write> {
+    writer: W,
+    interfaces: Interfaces,
+    start: Duration,
+    last: Duration,
+}
+
+impl<W: Write> CandumpWriter<W> {
+    pub fn new(writer: W, start: SystemTime) -> Self {
+        Self::with_interfaces(writer, start, Interfaces::default())
+    }
+
+    pub fn with_interfaces(writer: W, start: SystemTime, interfaces: Interfaces) -> Self {
+        CandumpWriter {
+            writer,
+            interfaces,
+            start: start.duration_since(UNIX_EPOCH).unwrap_or_default(),
+            last: Duration::ZERO,
+        }
+    }
+}
+
+impl<W: Write> LogWriter for CandumpWriter<W> {
+    /// Packets that have not been sent yet have no time, so they are logged with the time of the previous packet.
+    fn write(&mut self, packet: &Packet) -> Result<()> {
+        if let Some(time) = packet.time() {
+            self.last = time;
+        }
+        let line = format_line(packet, self.start + self.last, &self.interfaces);
+        writeln!(self.writer, "{line}")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::packet::{ErrorClass, FdFlags, IdType};
+
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<()> {
+        let interfaces = Interfaces::default();
+        let p = parse_line(
+            "(1700000000.123456) can0 18FEF100#0102030405060708",
+            &interfaces,
+        )?;
+        assert_eq!(0x18FEF100, p.id);
+        assert_eq!(IdType::Extended, p.id_type);
+        assert_eq!(Some(Duration::new(1700000000, 123_456_000)), p.time());
+        assert_eq!([1, 2, 3, 4, 5, 6, 7, 8][..], p.payload[..]);
+
+        let p = parse_line("(1700000000.5) vcan1 7E8#02.41.0C", &interfaces)?;
+        assert_eq!(IdType::Standard, p.id_type);
+        assert_eq!(Some(1), p.channel());
+        assert_eq!([2, 0x41, 0x0C][..], p.payload[..]);
+
+        let p = parse_line("(1700000000.000000) can0 7DF#R8", &interfaces)?;
+        assert_eq!(
+            "      0.0000 0 7DF Rx r 8",
+            p.with_time(Duration::ZERO).to_string()
+        );
+
+        let p = parse_line("(1700000000.000000) can0 1A4##3112233", &interfaces)?;
+        assert_eq!(
+            Some(FdFlags {
+                brs: true,
+                esi: true
+            }),
+            p.fd
+        );
+        assert_eq!([0x11, 0x22, 0x33][..], p.payload[..]);
+
+        let p = parse_line(
+            "(1700000000.000000) can0 20000020#0000000000000000",
+            &interfaces,
+        )?;
+        assert!(p.errors().unwrap().contains(ErrorClass::Ack));
+
+        let p = parse_line("(1700000000.000000) can0 7E0#020100 T", &interfaces)?;
+        assert!(p.is_tx());
+
+        assert!(parse_line("(1700000000.000000) can0 7E0#020", &interfaces).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn interfaces() {
+        let configured = Interfaces(vec!["peak".into(), "kvaser".into()]);
+        assert_eq!(1, configured.channel("kvaser"));
+        assert_eq!(3, configured.channel("can3"));
+        assert_eq!("kvaser", configured.name(1));
+        assert_eq!("can2", configured.name(2));
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let packets = [
+            Packet::new_rx(0x18FEF100, &[1, 2, 3], Duration::from_millis(1), 0),
+            Packet::new_rx(0x7E8, &[], Duration::from_millis(2), 1).with_id_type(IdType::Standard),
+            Packet::new_remote_rx(0x7DF, 8, Duration::from_millis(3), 0)
+                .with_id_type(IdType::Standard),
+            Packet::new_rx_fd(
+                0x18DA00F1,
+                &[0x55; 12],
+                FdFlags {
+                    brs: true,
+                    esi: false,
+                },
+                Duration::from_millis(4),
+                2,
+            )?,
+            Packet::new_error(
+                [ErrorClass::BusOff].into_iter().collect(),
+                &[],
+                Duration::from_millis(5),
+                0,
+            ),
+        ];
+        let mut buf = Vec::new();
+        let mut writer = CandumpWriter::new(&mut buf, start);
+        for p in &packets {
+            writer.write(p)?;
+        }
+        writer.finish()?;
+        let text = String::from_utf8(buf.clone())?;
+        assert!(text.starts_with("(1700000000.001000) can0 18FEF100#010203\n"));
+        assert!(text.contains("(1700000000.002000) can1 7E8#\n"));
+
+        let mut reader = CandumpReader::new(Cursor::new(buf));
+        let read: Vec<Packet> = reader.by_ref().collect::<Result<_>>()?;
+        assert_eq!(Some(start + Duration::from_millis(1)), reader.start());
+        for (a, b) in packets.iter().zip(read.iter()) {
+            let a = a
+                .clone()
+                .with_time(a.time().unwrap() - Duration::from_millis(1));
+            assert_eq!(a.to_string(), b.to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+//! Streaming conversion between log formats, with channel and ID filters and timestamp adjustment.
+use std::{
+    io::{self, ErrorKind, Write},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    formats::{Format, LogReader},
+    packet::{Packet, Timestamp},
+};
+
+/// Which packets to keep and how to adjust their times.
+#[derive(Debug, Clone, Default)]
+pub struct Convert {
+    /// Keep only these channels.  Empty keeps all.
+    pub channels: Vec<u32>,
+    /// Keep only these IDs.  Empty keeps all.
+    pub ids: Vec<u32>,
+    /// Seconds added to each timestamp.  Negative moves packets earlier, stopping at 0.
+    pub shift: f64,
+    /// Make the first kept packet time 0, and move the start of the measurement to it.
+    pub rebase: bool,
+    /// Start of the measurement when the input does not have one.
+    pub start: Option<SystemTime>,
+}
+
+/// The result of [`Convert::run`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Converted {
+    /// Packets written
+    pub written: usize,
+    /// Records of the input that couldn't be read
+    pub skipped: usize,
+}
+
+impl Convert {
+    fn keep(&self, packet: &Packet) -> bool {
+        (self.channels.is_empty() || packet.channel().is_some_and(|c| self.channels.contains(&c)))
+            && (self.ids.is_empty() || self.ids.contains(&packet.id))
+    }
+
+    /// The next packet to keep.  Records that can't be read are counted in `skipped`.
+    fn next(&self, reader: &mut dyn LogReader, skipped: &mut usize) -> Result<Option<Packet>> {
+        for packet in &mut *reader {
+            match packet {
+                Ok(p) if self.keep(&p) => return Ok(Some(p)),
+                Ok(_) => {}
+                Err(e) if is_bad_record(&e) => *skipped += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copy `reader` to `writer` in `format`, one packet at a time.  Records that can't be read are skipped and
+    /// counted, so one bad record doesn't truncate the output.  Fails if reading the input or writing fails.
+    pub fn run(
+        &self,
+        reader: &mut dyn LogReader,
+        format: Format,
+        writer: impl Write,
+    ) -> Result<Converted> {
+        let shift_by = Duration::try_from_secs_f64(self.shift.abs())
+            .map_err(|e| anyhow!("Invalid shift {}: {e}", self.shift))?;
+        let mut converted = Converted::default();
+        let first = self.next(reader, &mut converted.skipped)?;
+        // some formats only know their start after the first packet
+        let start = reader
+            .start()
+            .or(self.start)
+            .unwrap_or_else(SystemTime::now);
+        let base = match (&first, self.rebase) {
+            (Some(p), true) => p.time().unwrap_or_default(),
+            _ => Duration::ZERO,
+        };
+
+        let start = start + base;
+        let mut writer = format.writer(writer, start)?;
+        let mut packet = first;
+        while let Some(p) = packet {
+            let p = match p.timestamp() {
+                Some(t) => p.with_timestamp(Timestamp {
+                    elapsed: shift(t.elapsed.saturating_sub(base), shift_by, self.shift < 0.0),
+                    anchor: Some(start),
+                    ..t
+                }),
+                None => p,
+            };
+            writer.write(&p)?;
+            converted.written += 1;
+            packet = self.next(reader, &mut converted.skipped)?;
+        }
+        writer.finish()?;
+        Ok(converted)
+    }
+}
+
+/// Whether an error is a record that can't be parsed, rather than a failure to read the input.  A truncated file
+/// ends with a bad record.
+fn is_bad_record(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_none_or(|e| matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof))
+}
+
+fn shift(time: Duration, by: Duration, earlier: bool) -> Duration {
+    if earlier {
+        time.saturating_sub(by)
+    } else {
+        time + by
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn filter_and_rebase() -> Result<()> {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let packets = [
+            Packet::new_rx(0x100, &[1], Duration::from_secs(1), 0),
+            Packet::new_rx(0x200, &[2], Duration::from_secs(2), 1),
+            Packet::new_rx(0x100, &[3], Duration::from_secs(3), 1),
+            Packet::new_rx(0x100, &[4], Duration::from_secs(4), 1),
+        ];
+        let mut asc = Vec::new();
+        {
+            let mut writer = Format::Asc.writer(&mut asc, start)?;
+            packets.iter().try_for_each(|p| writer.write(p))?;
+            writer.finish()?;
+        }
+
+        let convert = Convert {
+            channels: vec![1],
+            ids: vec![0x100],
+            shift: -0.5,
+            rebase: true,
+            ..Default::default()
+        };
+        let mut reader = Format::Asc.reader(Cursor::new(asc));
+        let mut blf = Vec::new();
+        assert_eq!(
+            Converted {
+                written: 2,
+                skipped: 0
+            },
+            convert.run(reader.as_mut(), Format::Blf, &mut blf)?
+        );
+
+        let mut reader = Format::Blf.reader(Cursor::new(blf));
+        let packets = reader.by_ref().collect::<Result<Vec<_>>>()?;
+        assert_eq!(Some(start + Duration::from_secs(3)), reader.start());
+        assert_eq!(
+            vec![
+                (Duration::ZERO, vec![3]),
+                (Duration::from_millis(500), vec![4])
+            ],
+            packets
+                .into_iter()
+                .map(|p| (p.time().unwrap(), p.payload.to_vec()))
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_bad_records() -> Result<()> {
+        let asc = "date Mon Jan 15 10:23:45.123 2024
+base hex  timestamps absolute
+Begin Triggerblock Mon Jan 15 10:23:45.123 2024
+   0.001000 1 100 Rx d 1 01
+   0.002000 1 200 Rx d 8 02
+   0.003000 1 300 Rx d 1 03
+End TriggerBlock
+";
+        let mut reader = Format::Asc.reader(Cursor::new(asc));
+        let converted = Convert::default().run(reader.as_mut(), Format::Asc, Vec::new())?;
+        assert_eq!(
+            Converted {
+                written: 2,
+                skipped: 1
+            },
+            converted
+        );
+
+        let convert = Convert {
+            shift: f64::NAN,
+            ..Default::default()
+        };
+        let mut reader = Format::Asc.reader(Cursor::new(asc));
+        assert!(convert
+            .run(reader.as_mut(), Format::Asc, Vec::new())
+            .is_err());
+        Ok(())
+    }
+}
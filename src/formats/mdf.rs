@@ -0,0 +1,1225 @@
+//! ASAM MDF4 bus logging files, with `CAN_DataFrame`, `CAN_RemoteFrame` and `CAN_ErrorFrame` channel groups.
+//!
+//! The writer streams a single unsorted data group with one record per frame.  The output can't be seeked, so the file
+//! is marked unfinalized and the data block extends to the end of the file.  Tools such as asammdf finalize it on open.
+//!
+//! The reader follows the links of the file, so it needs [`Seek`].  It accepts sorted and unsorted data groups, data
+//! lists and compressed data blocks.  Frame bytes may be fixed length or VLSD signal data.  Packets are merged from all
+//! data groups in time order.
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Seek, SeekFrom, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::read::ZlibDecoder;
+
+use crate::{
+    formats::{LogReader, LogWriter},
+    packet::{
+        dlc_to_len, ErrorClass, ErrorClasses, FdFlags, IdType, Packet, PacketState, CANFD_MAX_LEN,
+    },
+};
+
+const BLOCK_HEADER: usize = 24;
+const ID_BLOCK: usize = 64;
+
+/// `id_unfin_flags`: cycle counters and the length of the last DT block must be updated.
+const UNFIN_CYCLE_COUNTERS: u16 = 0x01;
+const UNFIN_DT_LENGTH: u16 = 0x04;
+
+const CN_FIXED: u8 = 0;
+const CN_VLSD: u8 = 1;
+const CN_MASTER: u8 = 2;
+const CN_VIRTUAL_MASTER: u8 = 3;
+const SYNC_TIME: u8 = 1;
+
+const UINT_LE: u8 = 0;
+const FLOAT_LE: u8 = 4;
+const BYTE_ARRAY: u8 = 10;
+
+const CG_VLSD: u16 = 0x01;
+const CG_BUS_EVENT: u16 = 0x02;
+const CG_PLAIN_BUS_EVENT: u16 = 0x04;
+
+/// CAN_ErrorFrame.ErrorType
+const ACK_ERROR: u64 = 5;
+
+/// Maximum bytes read from a DT block at once.
+const CHUNK: usize = 1 << 20;
+/// Largest compressed block, uncompressed block or record read, so a corrupt length doesn't exhaust memory.
+const MAX_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    b.get(at..)
+        .and_then(|b| b.get(..2))
+        .map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    b.get(at..)
+        .and_then(|b| b.get(..4))
+        .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    b.get(at..)
+        .and_then(|b| b.get(..8))
+        .map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// A block without its data section, which may be large.
+struct Block {
+    id: [u8; 4],
+    /// file offset of the data section
+    data_offset: u64,
+    data_len: u64,
+    links: Vec<u64>,
+}
+
+impl Block {
+    fn link(&self, n: usize) -> u64 {
+        self.links.get(n).copied().unwrap_or_default()
+    }
+}
+
+fn read_block<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Block> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut header = [0; BLOCK_HEADER];
+    reader.read_exact(&mut header)?;
+    if &header[..2] != b"##" {
+        return Err(anyhow!("Invalid block at {offset}"));
+    }
+    let len = u64_at(&header, 8);
+    let link_count = u64_at(&header, 16);
+    if link_count > 1 << 20 {
+        return Err(anyhow!("Invalid link count {link_count} at {offset}"));
+    }
+    let mut links = vec![0; link_count as usize * 8];
+    reader.read_exact(&mut links)?;
+    let links: Vec<u64> = links.chunks(8).map(|l| u64_at(l, 0)).collect();
+    let data_offset = offset + BLOCK_HEADER as u64 + link_count * 8;
+    Ok(Block {
+        id: header[..4].try_into()?,
+        data_offset,
+        data_len: offset.saturating_add(len).saturating_sub(data_offset),
+        links,
+    })
+}
+
+fn read_data<R: Read + Seek>(reader: &mut R, block: &Block, max: usize) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(block.data_offset))?;
+    let mut data = vec![0; (block.data_len as usize).min(max)];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Contents of a TX or MD block.
+fn read_text<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<String> {
+    if offset == 0 {
+        return Ok(String::new());
+    }
+    let block = read_block(reader, offset)?;
+    let data = read_data(reader, &block, 1 << 16)?;
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+}
+
+/// Location of a channel's value within a record.
+#[derive(Debug, Clone, Copy, Default)]
+struct Field {
+    byte_offset: usize,
+    bit_offset: u32,
+    bit_count: u32,
+}
+
+impl Field {
+    fn uint(&self, record: &[u8]) -> u64 {
+        let bytes = (self.bit_offset + self.bit_count).div_ceil(8) as usize;
+        let mut value = [0u8; 16];
+        let n = bytes.min(16);
+        if let Some(b) = record.get(self.byte_offset..self.byte_offset + n) {
+            value[..n].copy_from_slice(b);
+        }
+        let value = u128::from_le_bytes(value)
+            .checked_shr(self.bit_offset)
+            .unwrap_or_default();
+        let mask = if self.bit_count >= 64 {
+            u64::MAX as u128
+        } else {
+            (1u128 << self.bit_count) - 1
+        };
+        (value & mask) as u64
+    }
+
+    fn bytes<'a>(&self, record: &'a [u8]) -> &'a [u8] {
+        let end = (self.byte_offset + self.bit_count as usize / 8).min(record.len());
+        record.get(self.byte_offset..end).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameType {
+    Data,
+    Remote,
+    Error,
+}
+
+/// How to decode the time of a record.
+#[derive(Debug, Clone, Copy)]
+struct Master {
+    field: Field,
+    float: bool,
+    /// linear conversion
+    offset: f64,
+    factor: f64,
+}
+
+/// The channels of one bus logging channel group.
+#[derive(Debug, Default)]
+struct Frame {
+    frame_type: Option<FrameType>,
+    master: Option<Master>,
+    bus: Option<Field>,
+    id: Option<Field>,
+    ide: Option<Field>,
+    dlc: Option<Field>,
+    len: Option<Field>,
+    data: Option<Field>,
+    /// VLSD signal data block, if the data bytes are stored separately
+    vlsd: Option<u64>,
+    dir: Option<Field>,
+    edl: Option<Field>,
+    brs: Option<Field>,
+    esi: Option<Field>,
+    error_type: Option<Field>,
+}
+
+/// A channel group, which may not be a bus logging frame.
+struct Group {
+    data_bytes: usize,
+    inval_bytes: usize,
+    vlsd: bool,
+    frame: Frame,
+}
+
+impl Group {
+    fn record_len(&self) -> usize {
+        self.data_bytes + self.inval_bytes
+    }
+}
+
+/// A data block to read.
+struct Chunk {
+    offset: u64,
+    len: u64,
+    compressed: bool,
+}
+
+/// The records of one data group, read a chunk at a time.
+struct Stream {
+    groups: HashMap<u64, Group>,
+    record_id_size: usize,
+    chunks: Vec<Chunk>,
+    next_chunk: usize,
+    /// offset in the current uncompressed DT chunk
+    read: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    /// cache of VLSD signal data blocks
+    signal_data: HashMap<u64, Vec<u8>>,
+}
+
+impl Stream {
+    /// Make `n` bytes available at `pos`.  False at the end of the data.
+    fn fill<R: Read + Seek>(&mut self, reader: &mut R, n: usize) -> Result<bool> {
+        while self.buf.len() - self.pos < n {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+            let Some(chunk) = self.chunks.get(self.next_chunk) else {
+                return Ok(false);
+            };
+            if chunk.compressed {
+                let compressed = read_chunk(reader, chunk)?;
+                ZlibDecoder::new(&compressed[..])
+                    .take(MAX_BLOCK_SIZE)
+                    .read_to_end(&mut self.buf)?;
+                self.next_chunk += 1;
+            } else {
+                let len = (chunk.len - self.read).min(CHUNK as u64);
+                reader.seek(SeekFrom::Start(chunk.offset + self.read))?;
+                let start = self.buf.len();
+                self.buf.resize(start + len as usize, 0);
+                let n = read_up_to(reader, &mut self.buf[start..])?;
+                self.buf.truncate(start + n);
+                self.read += len;
+                if n < len as usize || self.read >= chunk.len {
+                    self.next_chunk += 1;
+                    self.read = 0;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Next record, as its group and bytes.
+    fn next_record<R: Read + Seek>(&mut self, reader: &mut R) -> Result<Option<(u64, Vec<u8>)>> {
+        loop {
+            if !self.fill(reader, self.record_id_size)? {
+                return Ok(None);
+            }
+            let record_id = match self.record_id_size {
+                0 => 0,
+                1 => self.buf[self.pos] as u64,
+                2 => u16_at(&self.buf, self.pos) as u64,
+                4 => u32_at(&self.buf, self.pos) as u64,
+                _ => u64_at(&self.buf, self.pos),
+            };
+            let group = self
+                .groups
+                .get(&record_id)
+                .ok_or_else(|| anyhow!("Unknown record id {record_id}"))?;
+            let (vlsd, record_len) = (group.vlsd, group.record_len());
+            let is_frame = !vlsd && group.frame.frame_type.is_some();
+            let len = if vlsd {
+                if !self.fill(reader, self.record_id_size + 4)? {
+                    return Ok(None);
+                }
+                let len = u32_at(&self.buf, self.pos + self.record_id_size) as u64;
+                if len > MAX_BLOCK_SIZE {
+                    return Err(anyhow!("Invalid signal data length {len}"));
+                }
+                4 + len as usize
+            } else {
+                record_len
+            };
+            if !self.fill(reader, self.record_id_size + len)? {
+                // the last record of an unfinalized file may be incomplete
+                return Ok(None);
+            }
+            let start = self.pos + self.record_id_size;
+            self.pos = start + len;
+            if is_frame {
+                return Ok(Some((record_id, self.buf[start..start + len].to_vec())));
+            }
+        }
+    }
+
+    fn decode<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+        record_id: u64,
+        record: &[u8],
+    ) -> Result<Packet> {
+        let frame = &self.groups[&record_id].frame;
+        let uint = |field: Option<Field>| field.map(|f| f.uint(record));
+        let flag = |field: Option<Field>| uint(field).unwrap_or_default() != 0;
+
+        let time = match frame.master {
+            Some(m) => {
+                let raw = if m.float {
+                    match m.field.bit_count {
+                        32 => f32::from_bits(m.field.uint(record) as u32) as f64,
+                        _ => f64::from_bits(m.field.uint(record)),
+                    }
+                } else {
+                    m.field.uint(record) as f64
+                };
+                Duration::try_from_secs_f64((m.offset + m.factor * raw).max(0.0))
+                    .map_err(|_| anyhow!("Invalid time {raw}"))?
+            }
+            None => Duration::ZERO,
+        };
+        let channel = (uint(frame.bus).unwrap_or(1) as u32).saturating_sub(1);
+        let raw_id = uint(frame.id).unwrap_or_default() as u32;
+        let id = raw_id & 0x1FFF_FFFF;
+        let extended = match frame.ide {
+            Some(ide) => ide.uint(record) != 0,
+            None => raw_id & 0x8000_0000 != 0 || id > 0x7FF,
+        };
+        let id_type = if extended {
+            IdType::Extended
+        } else {
+            IdType::Standard
+        };
+        let dlc = uint(frame.dlc).unwrap_or_default() as u8;
+        let data = match (frame.vlsd, frame.data) {
+            (Some(sd), Some(field)) => {
+                let offset = field.uint(record) as usize;
+                let signal_data = match self.signal_data.entry(sd) {
+                    std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        e.insert(read_signal_data(reader, sd)?)
+                    }
+                };
+                let len = u32_at(signal_data, offset) as usize;
+                signal_data
+                    .get(offset.saturating_add(4)..)
+                    .and_then(|d| d.get(..len))
+                    .ok_or_else(|| anyhow!("Invalid signal data offset {offset}"))?
+                    .to_vec()
+            }
+            (None, Some(field)) => field.bytes(record).to_vec(),
+            _ => Vec::new(),
+        };
+        let len = uint(frame.len)
+            .map(|l| l as usize)
+            .unwrap_or_else(|| dlc_to_len(dlc))
+            .min(data.len());
+        let data = &data[..len];
+
+        let packet = match frame.frame_type {
+            Some(FrameType::Error) => {
+                let mut errors = ErrorClasses::default();
+                if uint(frame.error_type) == Some(ACK_ERROR) {
+                    errors.insert(ErrorClass::Ack);
+                }
+                return Ok(Packet::new_error(errors, data, time, channel));
+            }
+            Some(FrameType::Remote) => Packet::new_remote_rx(id, dlc, time, channel),
+            _ if flag(frame.edl) => {
+                let flags = FdFlags {
+                    brs: flag(frame.brs),
+                    esi: flag(frame.esi),
+                };
+                Packet::new_rx_fd(id, data, flags, time, channel)?
+            }
+            _ => Packet::new_rx(id, data, time, channel),
+        }
+        .with_id_type(id_type);
+        Ok(match packet.state {
+            PacketState::RX { time, channel } if flag(frame.dir) => Packet {
+                state: PacketState::Echo { time, channel },
+                ..packet
+            },
+            _ => packet,
+        })
+    }
+}
+
+fn read_up_to<R: Read>(reader: &mut R, mut buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while !buf.is_empty() {
+        match reader.read(buf)? {
+            0 => break,
+            r => {
+                n += r;
+                buf = &mut buf[r..];
+            }
+        }
+    }
+    Ok(n)
+}
+
+/// All of a data block, which must not be larger than [`MAX_BLOCK_SIZE`].
+fn read_chunk<R: Read + Seek>(reader: &mut R, chunk: &Chunk) -> Result<Vec<u8>> {
+    if chunk.len > MAX_BLOCK_SIZE {
+        return Err(anyhow!("Invalid data block length {}", chunk.len));
+    }
+    reader.seek(SeekFrom::Start(chunk.offset))?;
+    let mut bytes = vec![0; chunk.len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// All of a VLSD signal data block, uncompressed.
+fn read_signal_data<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for chunk in data_chunks(reader, offset, false)? {
+        let bytes = read_chunk(reader, &chunk)?;
+        let room = MAX_BLOCK_SIZE.saturating_sub(data.len() as u64);
+        if chunk.compressed {
+            ZlibDecoder::new(&bytes[..])
+                .take(room)
+                .read_to_end(&mut data)?;
+        } else if bytes.len() as u64 <= room {
+            data.extend(bytes);
+        } else {
+            return Err(anyhow!("Signal data larger than {MAX_BLOCK_SIZE}"));
+        }
+    }
+    Ok(data)
+}
+
+/// Data blocks of a data or signal data link, following lists.  A block linked twice is an error, so a loop of lists
+/// can't hang the reader.
+fn data_chunks<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    unfinalized: bool,
+) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    let mut pending = vec![offset];
+    let mut visited = HashSet::new();
+    while let Some(offset) = pending.pop() {
+        if offset == 0 {
+            continue;
+        }
+        if !visited.insert(offset) {
+            return Err(anyhow!("Data block at {offset} is linked twice"));
+        }
+        let block = read_block(reader, offset)?;
+        match &block.id {
+            b"##DT" | b"##SD" | b"##RD" => {
+                let mut len = block.data_len;
+                if unfinalized && len == 0 {
+                    // unknown length, extends to the end of the file
+                    len = reader
+                        .seek(SeekFrom::End(0))?
+                        .saturating_sub(block.data_offset);
+                }
+                chunks.push(Chunk {
+                    offset: block.data_offset,
+                    len,
+                    compressed: false,
+                });
+            }
+            b"##DZ" => {
+                let header = read_data(reader, &block, 24)?;
+                match header.get(2) {
+                    Some(0) if header.len() == 24 => {}
+                    Some(0) | None => return Err(anyhow!("Invalid DZ block at {offset}")),
+                    Some(_) => return Err(anyhow!("Unsupported transposed DZ block")),
+                }
+                chunks.push(Chunk {
+                    offset: block.data_offset + 24,
+                    len: u64_at(&header, 16),
+                    compressed: true,
+                });
+            }
+            b"##DL" => {
+                // next list, then the blocks of this list in order
+                pending.push(block.link(0));
+                pending.extend(block.links.iter().skip(1).rev());
+            }
+            b"##HL" => pending.push(block.link(0)),
+            id => {
+                return Err(anyhow!(
+                    "Unexpected data block {}",
+                    String::from_utf8_lossy(id)
+                ))
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+/// Channels of a channel group, including the children of composed channels, as (name, block).
+fn channels<R: Read + Seek>(reader: &mut R, first: u64) -> Result<Vec<(String, Block)>> {
+    let mut channels = Vec::new();
+    let mut pending = vec![first];
+    while let Some(offset) = pending.pop() {
+        if offset == 0 || channels.len() > 1 << 12 {
+            continue;
+        }
+        let block = read_block(reader, offset)?;
+        if &block.id != b"##CN" {
+            continue;
+        }
+        let name = read_text(reader, block.link(2))?;
+        pending.push(block.link(0));
+        // composition
+        pending.push(block.link(1));
+        channels.push((name, block));
+    }
+    Ok(channels)
+}
+
+fn frame_type(name: &str) -> Option<FrameType> {
+    if name.contains("CAN_DataFrame") {
+        Some(FrameType::Data)
+    } else if name.contains("CAN_RemoteFrame") {
+        Some(FrameType::Remote)
+    } else if name.contains("CAN_ErrorFrame") {
+        Some(FrameType::Error)
+    } else {
+        None
+    }
+}
+
+fn read_group<R: Read + Seek>(reader: &mut R, cg: &Block) -> Result<Group> {
+    let data = read_data(reader, cg, 32)?;
+    let flags = u16_at(&data, 16);
+    let mut frame = Frame {
+        frame_type: frame_type(&read_text(reader, cg.link(2))?),
+        ..Frame::default()
+    };
+    for (name, cn) in channels(reader, cg.link(1))? {
+        let d = read_data(reader, &cn, 24)?;
+        // type, sync type, data type, bit offset, byte offset and bit count
+        let Some(&[cn_type, sync_type, data_type, bit_offset]) =
+            d.get(..12).and_then(|d| d.get(..4))
+        else {
+            return Err(anyhow!("Invalid channel {name}"));
+        };
+        let field = Field {
+            byte_offset: u32_at(&d, 4) as usize,
+            bit_offset: bit_offset as u32,
+            bit_count: u32_at(&d, 8),
+        };
+        if matches!(cn_type, CN_MASTER | CN_VIRTUAL_MASTER) && sync_type == SYNC_TIME {
+            let (offset, factor) = linear(reader, cn.link(4))?;
+            frame.master = Some(Master {
+                field,
+                float: data_type == FLOAT_LE || data_type == FLOAT_LE + 1,
+                offset,
+                factor,
+            });
+            continue;
+        }
+        if frame.frame_type.is_none() {
+            frame.frame_type = frame_type(&name);
+        }
+        let slot = match name.rsplit('.').next().unwrap_or_default() {
+            "BusChannel" => &mut frame.bus,
+            "ID" => &mut frame.id,
+            "IDE" => &mut frame.ide,
+            "DLC" => &mut frame.dlc,
+            "DataLength" => &mut frame.len,
+            "DataBytes" => {
+                if cn_type == CN_VLSD {
+                    frame.vlsd = Some(cn.link(5));
+                }
+                &mut frame.data
+            }
+            "Dir" => &mut frame.dir,
+            "EDL" => &mut frame.edl,
+            "BRS" => &mut frame.brs,
+            "ESI" => &mut frame.esi,
+            "ErrorType" => &mut frame.error_type,
+            _ => continue,
+        };
+        *slot = Some(field);
+    }
+    // frames need an id, unless they are errors
+    if frame.id.is_none() && frame.frame_type != Some(FrameType::Error) {
+        frame.frame_type = None;
+    }
+    Ok(Group {
+        data_bytes: u32_at(&data, 24) as usize,
+        inval_bytes: u32_at(&data, 28) as usize,
+        vlsd: flags & CG_VLSD != 0,
+        frame,
+    })
+}
+
+/// (offset, factor) of an identity or linear conversion.
+fn linear<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<(f64, f64)> {
+    if offset == 0 {
+        return Ok((0.0, 1.0));
+    }
+    let cc = read_block(reader, offset)?;
+    let data = read_data(reader, &cc, 40)?;
+    match data.first() {
+        Some(1) => Ok((
+            f64::from_bits(u64_at(&data, 24)),
+            f64::from_bits(u64_at(&data, 32)),
+        )),
+        _ => Ok((0.0, 1.0)),
+    }
+}
+
+/// Streaming MDF4 reader.  Times are relative to the [`MdfReader::start`] in the file header.
+pub struct MdfReader<R> {
+    reader: R,
+    started: bool,
+    done: bool,
+    start: Option<SystemTime>,
+    streams: Vec<Stream>,
+    /// next packet of each stream
+    peeked: Vec<Option<Packet>>,
+}
+
+impl<R: Read + Seek> MdfReader<R> {
+    pub fn new(reader: R) -> Self {
+        MdfReader {
+            reader,
+            started: false,
+            done: false,
+            start: None,
+            streams: Vec::new(),
+            peeked: Vec::new(),
+        }
+    }
+
+    /// Wall clock time of the start of the measurement, from the file header.  Available after the first packet is
+    /// read.
+    pub fn start(&self) -> Option<SystemTime> {
+        self.start
+    }
+
+    fn open(&mut self) -> Result<()> {
+        let reader = &mut self.reader;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut id = [0; ID_BLOCK];
+        reader.read_exact(&mut id)?;
+        let unfinalized = match &id[..8] {
+            b"MDF     " => false,
+            b"UnFinMF " => true,
+            _ => return Err(anyhow!("Not an MDF file")),
+        };
+        if u16_at(&id, 28) < 400 {
+            return Err(anyhow!("Unsupported MDF version {}", u16_at(&id, 28)));
+        }
+        let hd = read_block(reader, ID_BLOCK as u64)?;
+        if &hd.id != b"##HD" {
+            return Err(anyhow!("Missing HD block"));
+        }
+        let hd_data = read_data(reader, &hd, 8)?;
+        self.start = Some(UNIX_EPOCH + Duration::from_nanos(u64_at(&hd_data, 0)));
+
+        // data and channel groups already read, so a loop of links can't hang the reader
+        let mut visited = HashSet::new();
+        let mut dg_offset = hd.link(0);
+        while dg_offset != 0 {
+            if !visited.insert(dg_offset) {
+                return Err(anyhow!("Data group at {dg_offset} is linked twice"));
+            }
+            let dg = read_block(reader, dg_offset)?;
+            let record_id_size = *read_data(reader, &dg, 1)?
+                .first()
+                .ok_or_else(|| anyhow!("Invalid data group at {dg_offset}"))?
+                as usize;
+            dg_offset = dg.link(0);
+            let mut groups = HashMap::new();
+            let mut cg_offset = dg.link(1);
+            while cg_offset != 0 {
+                if !visited.insert(cg_offset) {
+                    return Err(anyhow!("Channel group at {cg_offset} is linked twice"));
+                }
+                let cg = read_block(reader, cg_offset)?;
+                cg_offset = cg.link(0);
+                let record_id = u64_at(&read_data(reader, &cg, 8)?, 0);
+                groups.insert(record_id, read_group(reader, &cg)?);
+            }
+            if groups.values().all(|g| g.frame.frame_type.is_none()) {
+                continue;
+            }
+            self.streams.push(Stream {
+                groups,
+                record_id_size,
+                chunks: data_chunks(reader, dg.link(2), unfinalized)?,
+                next_chunk: 0,
+                read: 0,
+                buf: Vec::new(),
+                pos: 0,
+                signal_data: HashMap::new(),
+            });
+        }
+        self.peeked = (0..self.streams.len()).map(|_| None).collect();
+        for i in 0..self.streams.len() {
+            self.peeked[i] = self.read_stream(i)?;
+        }
+        Ok(())
+    }
+
+    fn read_stream(&mut self, i: usize) -> Result<Option<Packet>> {
+        let stream = &mut self.streams[i];
+        match stream.next_record(&mut self.reader)? {
+            Some((record_id, record)) => stream
+                .decode(&mut self.reader, record_id, &record)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for MdfReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if !self.started {
+            self.started = true;
+            if let Err(e) = self.open() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        // earliest packet of all data groups
+        let i = self
+            .peeked
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| Some((i, p.as_ref()?.time())))
+            .min_by_key(|(_, time)| *time)?
+            .0;
+        let packet = self.peeked[i].take();
+        match self.read_stream(i) {
+            Ok(next) => self.peeked[i] = next,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        packet.map(Ok)
+    }
+}
+
+impl<R: Read + Seek> LogReader for MdfReader<R> {
+    fn start(&self) -> Option<SystemTime> {
+        MdfReader::start(self)
+    }
+}
+
+/// Builds the metadata blocks of a file.
+struct Builder {
+    buf: Vec<u8>,
+}
+
+impl Builder {
+    /// Append a block with zeroed links, returning its file offset.
+    fn block(&mut self, id: &[u8; 4], links: usize, data: &[u8]) -> u64 {
+        let offset = self.buf.len() as u64;
+        let len = BLOCK_HEADER + links * 8 + data.len();
+        self.buf.extend_from_slice(b"##");
+        self.buf.extend_from_slice(&id[2..]);
+        self.buf.extend_from_slice(&[0; 4]);
+        self.buf.extend_from_slice(&(len as u64).to_le_bytes());
+        self.buf.extend_from_slice(&(links as u64).to_le_bytes());
+        self.buf.resize(self.buf.len() + links * 8, 0);
+        self.buf.extend_from_slice(data);
+        // blocks are 8 byte aligned
+        self.buf.resize(self.buf.len().next_multiple_of(8), 0);
+        offset
+    }
+
+    fn link(&mut self, block: u64, n: usize, target: u64) {
+        let at = block as usize + BLOCK_HEADER + n * 8;
+        self.buf[at..at + 8].copy_from_slice(&target.to_le_bytes());
+    }
+
+    fn text(&mut self, id: &[u8; 4], text: &str) -> u64 {
+        let mut data = text.as_bytes().to_vec();
+        data.push(0);
+        self.block(id, 0, &data)
+    }
+
+    /// Link blocks with their first link.
+    fn chain(&mut self, blocks: &[u64]) {
+        for pair in blocks.windows(2) {
+            self.link(pair[0], 0, pair[1]);
+        }
+    }
+
+    fn channel(&mut self, name: &str, cn_type: u8, data_type: u8, field: Field) -> u64 {
+        let mut data = vec![
+            cn_type,
+            if cn_type == CN_MASTER { SYNC_TIME } else { 0 },
+            data_type,
+            field.bit_offset as u8,
+        ];
+        data.extend_from_slice(&(field.byte_offset as u32).to_le_bytes());
+        data.extend_from_slice(&field.bit_count.to_le_bytes());
+        // flags, invalidation bit, precision, reserved, attachments, ranges and limits
+        data.resize(72, 0);
+        let cn = self.block(b"##CN", 8, &data);
+        let name = self.text(b"##TX", name);
+        self.link(cn, 2, name);
+        cn
+    }
+
+    /// Bus logging channel group: a time master and a composed frame channel.
+    fn group(
+        &mut self,
+        name: &str,
+        record_id: u64,
+        source: u64,
+        fields: &[(&str, u8, Field)],
+    ) -> u64 {
+        let data_bytes = fields
+            .iter()
+            .map(|(_, _, f)| f.byte_offset + (f.bit_offset + f.bit_count).div_ceil(8) as usize)
+            .max()
+            .unwrap_or_default();
+        let mut data = Vec::new();
+        data.extend_from_slice(&record_id.to_le_bytes());
+        // cycle count, to be updated when the file is finalized
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&(CG_BUS_EVENT | CG_PLAIN_BUS_EVENT).to_le_bytes());
+        data.extend_from_slice(&(b'.' as u16).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(data_bytes as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        let cg = self.block(b"##CG", 6, &data);
+        let acq_name = self.text(b"##TX", name);
+        self.link(cg, 2, acq_name);
+        self.link(cg, 3, source);
+
+        let time = Field {
+            byte_offset: 0,
+            bit_offset: 0,
+            bit_count: 64,
+        };
+        let master = self.channel("t", CN_MASTER, FLOAT_LE, time);
+        let composed = Field {
+            byte_offset: 8,
+            bit_offset: 0,
+            bit_count: (data_bytes as u32 - 8) * 8,
+        };
+        let frame = self.channel(name, CN_FIXED, BYTE_ARRAY, composed);
+        let children: Vec<u64> = fields
+            .iter()
+            .map(|(child, data_type, field)| {
+                self.channel(&format!("{name}.{child}"), CN_FIXED, *data_type, *field)
+            })
+            .collect();
+        self.chain(&[master, frame]);
+        self.link(frame, 1, children[0]);
+        self.chain(&children);
+        self.link(cg, 1, master);
+        cg
+    }
+}
+
+fn field(byte_offset: usize, bit_offset: u32, bit_count: u32) -> Field {
+    Field {
+        byte_offset,
+        bit_offset,
+        bit_count,
+    }
+}
+
+/// Record layouts after the 8 byte time.  Flags are bits of byte 13.
+const BUS: Field = Field {
+    byte_offset: 8,
+    bit_offset: 0,
+    bit_count: 8,
+};
+const ID: Field = Field {
+    byte_offset: 9,
+    bit_offset: 0,
+    bit_count: 29,
+};
+const FLAGS: usize = 13;
+const DLC: Field = Field {
+    byte_offset: 14,
+    bit_offset: 0,
+    bit_count: 4,
+};
+const LEN: Field = Field {
+    byte_offset: 15,
+    bit_offset: 0,
+    bit_count: 8,
+};
+const DATA_RECORD: usize = 16 + CANFD_MAX_LEN;
+const REMOTE_RECORD: usize = 16;
+const ERROR_RECORD: usize = 16 + 8 + 1;
+
+const DATA_FRAME: u8 = 1;
+const REMOTE_FRAME: u8 = 2;
+const ERROR_FRAME: u8 = 3;
+
+/// Streaming MDF 4.10 writer.
+pub struct MdfWriter<W: Write> {
+    writer: W,
+    last: Duration,
+}
+
+impl<W: Write> MdfWriter<W> {
+    /// `start` is the wall clock time of the start of the measurement.
+    pub fn new(mut writer: W, start: SystemTime) -> Result<Self> {
+        let start_ns = start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let mut id = Vec::with_capacity(ID_BLOCK);
+        id.extend_from_slice(b"UnFinMF 4.10    logger  ");
+        id.extend_from_slice(&[0; 4]);
+        id.extend_from_slice(&410u16.to_le_bytes());
+        id.resize(60, 0);
+        id.extend_from_slice(&(UNFIN_CYCLE_COUNTERS | UNFIN_DT_LENGTH).to_le_bytes());
+        id.extend_from_slice(&0u16.to_le_bytes());
+
+        let mut b = Builder { buf: id };
+        let mut hd_data = start_ns.to_le_bytes().to_vec();
+        hd_data.resize(32, 0);
+        let hd = b.block(b"##HD", 6, &hd_data);
+
+        let mut fh_data = start_ns.to_le_bytes().to_vec();
+        fh_data.resize(16, 0);
+        let fh = b.block(b"##FH", 2, &fh_data);
+        let comment = b.text(
+            b"##MD",
+            concat!(
+                "<FHcomment><TX>created</TX><tool_id>logger</tool_id>",
+                "<tool_vendor>SolidDesignNet</tool_vendor><tool_version>",
+                env!("CARGO_PKG_VERSION"),
+                "</tool_version></FHcomment>"
+            ),
+        );
+        b.link(fh, 1, comment);
+        b.link(hd, 1, fh);
+
+        // 1 byte record ids
+        let dg = b.block(b"##DG", 4, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        b.link(hd, 0, dg);
+
+        // bus type CAN
+        let si = b.block(b"##SI", 3, &[2, 2, 0, 0, 0, 0, 0, 0]);
+        let si_name = b.text(b"##TX", "CAN");
+        b.link(si, 0, si_name);
+
+        let flag = |bit| field(FLAGS, bit, 1);
+        let data = b.group(
+            "CAN_DataFrame",
+            DATA_FRAME as u64,
+            si,
+            &[
+                ("BusChannel", UINT_LE, BUS),
+                ("ID", UINT_LE, ID),
+                ("IDE", UINT_LE, flag(0)),
+                ("Dir", UINT_LE, flag(1)),
+                ("EDL", UINT_LE, flag(2)),
+                ("BRS", UINT_LE, flag(3)),
+                ("ESI", UINT_LE, flag(4)),
+                ("DLC", UINT_LE, DLC),
+                ("DataLength", UINT_LE, LEN),
+                (
+                    "DataBytes",
+                    BYTE_ARRAY,
+                    field(16, 0, CANFD_MAX_LEN as u32 * 8),
+                ),
+            ],
+        );
+        let remote = b.group(
+            "CAN_RemoteFrame",
+            REMOTE_FRAME as u64,
+            si,
+            &[
+                ("BusChannel", UINT_LE, BUS),
+                ("ID", UINT_LE, ID),
+                ("IDE", UINT_LE, flag(0)),
+                ("Dir", UINT_LE, flag(1)),
+                ("DLC", UINT_LE, DLC),
+                ("DataLength", UINT_LE, LEN),
+            ],
+        );
+        let error = b.group(
+            "CAN_ErrorFrame",
+            ERROR_FRAME as u64,
+            si,
+            &[
+                ("BusChannel", UINT_LE, BUS),
+                ("ID", UINT_LE, ID),
+                ("IDE", UINT_LE, flag(0)),
+                ("Dir", UINT_LE, flag(1)),
+                ("DLC", UINT_LE, DLC),
+                ("DataLength", UINT_LE, LEN),
+                ("DataBytes", BYTE_ARRAY, field(16, 0, 64)),
+                ("ErrorType", UINT_LE, field(24, 0, 8)),
+            ],
+        );
+        b.chain(&[data, remote, error]);
+        b.link(dg, 1, data);
+
+        // data block of unknown length, which is the rest of the file
+        let dt = b.buf.len() as u64;
+        b.buf.extend_from_slice(b"##DT");
+        b.buf.extend_from_slice(&[0; 4]);
+        b.buf
+            .extend_from_slice(&(BLOCK_HEADER as u64).to_le_bytes());
+        b.buf.extend_from_slice(&0u64.to_le_bytes());
+        b.link(dg, 2, dt);
+
+        writer.write_all(&b.buf)?;
+        Ok(MdfWriter {
+            writer,
+            last: Duration::ZERO,
+        })
+    }
+}
+
+impl<W: Write> LogWriter for MdfWriter<W> {
+    /// Packets that have not been sent yet have no time, so they are logged with the time of the previous packet.
+    fn write(&mut self, packet: &Packet) -> Result<()> {
+        if let Some(time) = packet.time() {
+            self.last = time;
+        }
+        let (record_id, size) = match packet.state {
+            PacketState::Remote { .. } => (REMOTE_FRAME, REMOTE_RECORD),
+            PacketState::Error { .. } | PacketState::Status { .. } => (ERROR_FRAME, ERROR_RECORD),
+            _ => (DATA_FRAME, DATA_RECORD),
+        };
+        let mut record = vec![0; 1 + size];
+        record[0] = record_id;
+        let r = &mut record[1..];
+        r[..8].copy_from_slice(&self.last.as_secs_f64().to_le_bytes());
+        r[BUS.byte_offset] = (packet.channel().unwrap_or_default() + 1) as u8;
+        r[ID.byte_offset..ID.byte_offset + 4].copy_from_slice(&packet.id.to_le_bytes());
+        let fd = packet.fd.filter(|_| packet.is_data());
+        r[FLAGS] = packet.is_extended() as u8
+            | (packet.is_tx() as u8) << 1
+            | (fd.is_some() as u8) << 2
+            | (fd.is_some_and(|f| f.brs) as u8) << 3
+            | (fd.is_some_and(|f| f.esi) as u8) << 4;
+        match packet.state {
+            PacketState::Remote { dlc, .. } => {
+                r[DLC.byte_offset] = dlc;
+                r[LEN.byte_offset] = dlc_to_len(dlc) as u8;
+            }
+            PacketState::Error { errors, .. } | PacketState::Status { errors, .. } => {
+                let len = packet.payload.len().min(8);
+                r[DLC.byte_offset] = len as u8;
+                r[LEN.byte_offset] = len as u8;
+                r[16..16 + len].copy_from_slice(&packet.payload[..len]);
+                if errors.contains(ErrorClass::Ack) {
+                    r[24] = ACK_ERROR as u8;
+                }
+            }
+            _ => {
+                let len = packet.payload.len().min(CANFD_MAX_LEN);
+                r[DLC.byte_offset] = packet.dlc();
+                r[LEN.byte_offset] = len as u8;
+                r[16..16 + len].copy_from_slice(&packet.payload[..len]);
+            }
+        }
+        self.writer.write_all(&record)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let start = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        let packets = [
+            Packet::new_rx(
+                0x18FEF100,
+                &[1, 2, 3, 4, 5, 6, 7, 8],
+                Duration::from_millis(1),
+                0,
+            ),
+            Packet {
+                state: PacketState::Echo {
+                    time: Duration::from_millis(2).into(),
+                    channel: 1,
+                },
+                ..Packet::new(0x7E0, &[2, 1, 0]).with_id_type(IdType::Standard)
+            },
+            Packet::new_remote_rx(0x7DF, 8, Duration::from_millis(3), 0)
+                .with_id_type(IdType::Standard),
+            Packet::new_rx_fd(
+                0x18DA00F1,
+                &[0x55; 48],
+                FdFlags {
+                    brs: true,
+                    esi: false,
+                },
+                Duration::from_millis(4),
+                2,
+            )?,
+            Packet::new_error(
+                [ErrorClass::Ack].into_iter().collect(),
+                &[0, 4],
+                Duration::from_millis(5),
+                0,
+            ),
+        ];
+        let mut buf = Vec::new();
+        let mut writer = MdfWriter::new(&mut buf, start)?;
+        for p in &packets {
+            writer.write(p)?;
+        }
+        writer.finish()?;
+        assert_eq!(b"UnFinMF ", &buf[..8]);
+
+        let mut reader = MdfReader::new(Cursor::new(buf));
+        let read: Vec<Packet> = reader.by_ref().collect::<Result<_>>()?;
+        assert_eq!(Some(start), reader.start());
+        assert_eq!(packets.len(), read.len());
+        for (a, b) in packets.iter().zip(read.iter()) {
+            assert_eq!(a.to_string(), b.to_string());
+            assert_eq!(a.channel(), b.channel());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_record() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut writer = MdfWriter::new(&mut buf, UNIX_EPOCH)?;
+        writer.write(&Packet::new_rx(0x18FEF100, &[1], Duration::ZERO, 0))?;
+        writer.write(&Packet::new_rx(0x18FEF100, &[2], Duration::ZERO, 0))?;
+        buf.truncate(buf.len() - 10);
+        let read: Vec<Packet> = MdfReader::new(Cursor::new(buf)).collect::<Result<_>>()?;
+        assert_eq!(1, read.len());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut writer = MdfWriter::new(&mut buf, UNIX_EPOCH)?;
+        writer.write(&Packet::new_rx(0x18FEF100, &[1], Duration::ZERO, 0))?;
+        let find = |buf: &[u8], id: &[u8]| buf.windows(4).position(|w| w == id).unwrap();
+        let fails = |buf: Vec<u8>| {
+            MdfReader::new(Cursor::new(buf))
+                .collect::<Result<Vec<_>>>()
+                .is_err()
+        };
+
+        // a channel without its data section
+        let mut short = buf.clone();
+        let cn = find(&short, b"##CN");
+        short[cn + 8..cn + 16].copy_from_slice(&(BLOCK_HEADER as u64 + 8 * 8).to_le_bytes());
+        assert!(fails(short));
+
+        // the data of the group is a block appended to the file
+        let dg = find(&buf, b"##DG");
+        let append = |block: &[u8]| {
+            let mut buf = buf.clone();
+            let at = buf.len().next_multiple_of(8);
+            buf.resize(at, 0);
+            buf.extend_from_slice(block);
+            buf[dg + BLOCK_HEADER + 16..dg + BLOCK_HEADER + 24]
+                .copy_from_slice(&(at as u64).to_le_bytes());
+            (buf, at as u64)
+        };
+        let block = |id: &[u8; 4], links: &[u64], data: &[u8]| {
+            let mut block = id.to_vec();
+            block.extend_from_slice(&[0; 4]);
+            let len = BLOCK_HEADER + links.len() * 8 + data.len();
+            block.extend_from_slice(&(len as u64).to_le_bytes());
+            block.extend_from_slice(&(links.len() as u64).to_le_bytes());
+            links
+                .iter()
+                .for_each(|l| block.extend_from_slice(&l.to_le_bytes()));
+            block.extend_from_slice(data);
+            block
+        };
+
+        // a list that links to itself
+        let at = buf.len().next_multiple_of(8) as u64;
+        let (looped, _) = append(&block(b"##DL", &[at, at], &[0; 8]));
+        assert!(fails(looped));
+
+        // a compressed block that claims to be huge
+        let mut dz = vec![b'D', b'T', 0, 0, 0, 0, 0, 0];
+        dz.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        dz.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        let (huge, _) = append(&block(b"##DZ", &[], &dz));
+        assert!(fails(huge));
+        Ok(())
+    }
+
+    #[test]
+    fn not_mdf() {
+        let mut reader = MdfReader::new(Cursor::new(vec![0; 128]));
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,320 @@
+//! Readers and writers for CAN log files.
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+
+use crate::packet::{ErrorClass, Packet, PacketState, Timestamp};
+
+pub mod asc;
+pub mod blf;
+pub mod candump;
+pub mod convert;
+pub mod mdf;
+pub mod pcap;
+pub mod socketcan;
+pub mod trc;
+
+/// Supported log file formats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Vector ASC
+    #[default]
+    Asc,
+    /// Linux can-utils `candump -l`
+    Candump,
+    /// Vector BLF, zlib compressed
+    Blf,
+    /// pcapng with SocketCAN records, for Wireshark.  Legacy pcap files can also be read.
+    Pcapng,
+    /// PEAK PCAN-View TRC
+    Trc,
+    /// ASAM MDF4 bus logging
+    Mdf4,
+}
+
+impl Format {
+    /// Guess the format from the file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Format> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "asc" => Some(Format::Asc),
+            "log" | "candump" => Some(Format::Candump),
+            "blf" => Some(Format::Blf),
+            "pcapng" | "pcap" => Some(Format::Pcapng),
+            "trc" => Some(Format::Trc),
+            "mf4" | "mdf" => Some(Format::Mdf4),
+            _ => None,
+        }
+    }
+
+    /// Identify the format of a file from its first bytes, falling back to the extension for text files without a
+    /// recognizable header.
+    pub fn detect(path: impl AsRef<Path>) -> Result<Format> {
+        let path = path.as_ref();
+        let mut head = Vec::with_capacity(64);
+        File::open(path)?.take(64).read_to_end(&mut head)?;
+        Format::sniff(&head)
+            .or_else(|| Format::from_path(path))
+            .ok_or_else(|| anyhow!("Unknown log format: {}", path.display()))
+    }
+
+    fn sniff(head: &[u8]) -> Option<Format> {
+        if head.starts_with(b"LOGG") {
+            return Some(Format::Blf);
+        }
+        if head.starts_with(b"MDF     ") || head.starts_with(b"UnFinMF ") {
+            return Some(Format::Mdf4);
+        }
+        if let Some(magic) = head.get(..4) {
+            let magic = u32::from_le_bytes(magic.try_into().ok()?);
+            if [0xA1B2_C3D4, 0xA1B2_3C4D, 0x0A0D_0D0A].contains(&magic)
+                || [0xA1B2_C3D4u32, 0xA1B2_3C4D].contains(&magic.swap_bytes())
+            {
+                return Some(Format::Pcapng);
+            }
+        }
+        let text = String::from_utf8_lossy(head);
+        let text = text.trim_start();
+        if text.starts_with(";$FILEVERSION") || text.starts_with(";##") {
+            Some(Format::Trc)
+        } else if ["date ", "base ", "Begin "]
+            .iter()
+            .any(|s| text.starts_with(s))
+        {
+            Some(Format::Asc)
+        } else if text.starts_with('(') {
+            Some(Format::Candump)
+        } else {
+            None
+        }
+    }
+
+    /// Packets are anchored to the start of the measurement when the file has one.
+    pub fn reader<'a>(
+        self,
+        reader: impl BufRead + Seek + Send + 'a,
+    ) -> Box<dyn LogReader + Send + 'a> {
+        match self {
+            Format::Asc => anchored(asc::AscReader::new(reader)),
+            Format::Candump => anchored(candump::CandumpReader::new(reader)),
+            Format::Blf => anchored(blf::BlfReader::new(reader)),
+            Format::Pcapng => anchored(pcap::PcapReader::new(reader)),
+            Format::Trc => anchored(trc::TrcReader::new(reader)),
+            Format::Mdf4 => anchored(mdf::MdfReader::new(reader)),
+        }
+    }
+
+    /// `start` is the wall clock time of the start of the measurement.  Packets with a different wall clock anchor are
+    /// rebased to it.
+    pub fn writer<'a>(
+        self,
+        writer: impl Write + 'a,
+        start: SystemTime,
+    ) -> Result<Box<dyn LogWriter + 'a>> {
+        Ok(match self {
+            Format::Asc => rebased(asc::AscWriter::new(writer, start)?, start),
+            Format::Candump => rebased(candump::CandumpWriter::new(writer, start), start),
+            Format::Blf => rebased(blf::BlfWriter::new(writer, start)?, start),
+            Format::Pcapng => rebased(pcap::PcapWriter::new(writer, start)?, start),
+            Format::Trc => rebased(trc::TrcWriter::new(writer, start)?, start),
+            Format::Mdf4 => rebased(mdf::MdfWriter::new(writer, start)?, start),
+        })
+    }
+}
+
+/// Sets the wall clock anchor of each packet to the start of the log.
+struct Anchored<R>(R);
+
+fn anchored<'a>(reader: impl LogReader + Send + 'a) -> Box<dyn LogReader + Send + 'a> {
+    Box::new(Anchored(reader))
+}
+
+impl<R: LogReader> Iterator for Anchored<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let packet = self.0.next()?;
+        Some(packet.map(|p| match (self.0.start(), p.timestamp()) {
+            (Some(start), Some(t)) => p.with_timestamp(Timestamp {
+                anchor: Some(start),
+                ..t
+            }),
+            _ => p,
+        }))
+    }
+}
+
+impl<R: LogReader> LogReader for Anchored<R> {
+    fn start(&self) -> Option<SystemTime> {
+        self.0.start()
+    }
+}
+
+/// Makes the times of anchored packets relative to the start of the log.
+struct Rebased<W> {
+    writer: W,
+    start: SystemTime,
+}
+
+fn rebased<'a>(writer: impl LogWriter + 'a, start: SystemTime) -> Box<dyn LogWriter + 'a> {
+    Box::new(Rebased { writer, start })
+}
+
+impl<W: LogWriter> LogWriter for Rebased<W> {
+    fn write(&mut self, packet: &Packet) -> Result<()> {
+        match packet.timestamp() {
+            Some(t) if t.anchor.is_some_and(|a| a != self.start) => self
+                .writer
+                .write(&packet.clone().with_timestamp(t.rebase(self.start))),
+            _ => self.writer.write(packet),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.finish()
+    }
+}
+
+/// Open a log file of any supported format, detected by [`Format::detect`].
+pub fn open(path: impl AsRef<Path>) -> Result<Box<dyn LogReader + Send>> {
+    let format = Format::detect(&path)?;
+    Ok(format.reader(BufReader::new(File::open(path)?)))
+}
+
+/// Maps interface names to channels.  Configured names map to their position.  Otherwise the trailing number of the
+/// name is the channel, so `can1` and `vcan1` are both channel 1.
+#[derive(Debug, Clone, Default)]
+pub struct Interfaces(pub Vec<String>);
+
+impl Interfaces {
+    pub fn channel(&self, name: &str) -> u32 {
+        self.find(name).unwrap_or_default()
+    }
+
+    /// `None` if the name is not configured and has no trailing number.
+    pub fn find(&self, name: &str) -> Option<u32> {
+        if let Some(channel) = self.0.iter().position(|n| n == name) {
+            return Some(channel as u32);
+        }
+        let digits = name.trim_start_matches(|c: char| !c.is_ascii_digit());
+        digits.parse().ok()
+    }
+
+    pub fn name(&self, channel: u32) -> String {
+        self.0
+            .get(channel as usize)
+            .cloned()
+            .unwrap_or_else(|| format!("can{channel}"))
+    }
+}
+
+/// Streaming log reader.
+pub trait LogReader: Iterator<Item = Result<Packet>> {
+    /// Wall clock time of the start of the measurement, if the file has one.  Some formats only know it after the first
+    /// packet is read.
+    fn start(&self) -> Option<SystemTime>;
+}
+
+/// Streaming log writer.
+pub trait LogWriter {
+    fn write(&mut self, packet: &Packet) -> Result<()>;
+
+    /// Write any trailer and flush.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Flag of [`pack_errors`] for the error classes in the low bits, one per position in [`ErrorClass::ALL`].  Above the
+/// 29 bits of a frame id, so it isn't confused with the id that other writers put in the same field.
+const ERROR_CLASSES: u32 = 0x4000_0000;
+/// Flag of [`pack_errors`] for a status report rather than an error frame
+const ERROR_STATUS: u32 = 0x2000_0000;
+
+/// The error classes of an error or status packet, and which it is, for formats that only have an id for them.
+pub(crate) fn pack_errors(packet: &Packet) -> u32 {
+    let errors = packet.errors().unwrap_or_default();
+    let packed = ErrorClass::ALL
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| errors.contains(**c))
+        .fold(ERROR_CLASSES, |packed, (bit, _)| packed | 1 << bit);
+    if matches!(packet.state, PacketState::Status { .. }) {
+        packed | ERROR_STATUS
+    } else {
+        packed
+    }
+}
+
+/// The error or status packet of [`pack_errors`].  An error frame without classes if `packed` is from another writer.
+pub(crate) fn unpack_errors(packed: u32, data: &[u8], time: Duration, channel: u32) -> Packet {
+    if packed & ERROR_CLASSES == 0 {
+        return Packet::new_error(Default::default(), data, time, channel);
+    }
+    let errors = ErrorClass::ALL
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| packed & 1 << bit != 0)
+        .map(|(_, c)| *c)
+        .collect();
+    if packed & ERROR_STATUS != 0 {
+        Packet {
+            payload: data.into(),
+            ..Packet::new_status(errors, time, channel)
+        }
+    } else {
+        Packet::new_error(errors, data, time, channel)
+    }
+}
+
+// Howard Hinnant's civil calendar algorithms.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn detect() -> Result<()> {
+        let packet = Packet::new_rx(0x18FEF100, &[1, 2, 3], Duration::from_millis(1), 0);
+        for format in Format::value_variants() {
+            let mut buf = Vec::new();
+            {
+                let mut writer = format.writer(&mut buf, SystemTime::now())?;
+                writer.write(&packet)?;
+                writer.finish()?;
+            }
+            assert_eq!(Some(*format), Format::sniff(&buf), "{format:?}");
+        }
+        assert_eq!(Some(Format::Mdf4), Format::from_path("x.MF4"));
+        assert_eq!(None, Format::sniff(b"garbage"));
+        Ok(())
+    }
+}
@@ -0,0 +1,560 @@
+//! pcapng capture files with `LINKTYPE_CAN_SOCKETCAN` records, for Wireshark.
+//!
+//! The writer creates one interface per channel, named by [`Interfaces`], with nanosecond timestamps.  Frames sent by
+//! this host are marked outbound.  The reader also accepts legacy pcap files.
+//!
+//! Each record is a SocketCAN frame with a big endian `can_id`:
+//!
+//! ```text
+//! can_id(4) len(1) flags(1) reserved(2) data(8 or 64)
+//! ```
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    formats::{socketcan, Interfaces, LogReader, LogWriter},
+    packet::{Packet, PacketState, CANFD_MAX_LEN, CAN_MAX_LEN},
+};
+
+pub const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const PCAP_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_NANOS: u32 = 0xA1B2_3C4D;
+
+const OPT_END: u16 = 0;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+const EPB_INBOUND: u32 = 1;
+const EPB_OUTBOUND: u32 = 2;
+
+/// SocketCAN frame header before the data.
+const FRAME_HEADER: usize = 8;
+
+/// Little or big endian field access.
+#[derive(Debug, Clone, Copy)]
+struct Endian(bool);
+
+impl Endian {
+    fn u16(self, b: &[u8], at: usize) -> u16 {
+        let b = [b[at], b[at + 1]];
+        if self.0 {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        }
+    }
+
+    fn u32(self, b: &[u8], at: usize) -> u32 {
+        let b = [b[at], b[at + 1], b[at + 2], b[at + 3]];
+        if self.0 {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Interface {
+    link_type: u16,
+    /// timestamp units per second
+    resolution: u64,
+    channel: u32,
+}
+
+enum Kind {
+    Pcap { endian: Endian, resolution: u64 },
+    Pcapng { endian: Endian },
+}
+
+/// Streaming pcap and pcapng reader.  Times are relative to the first frame, which is the [`PcapReader::start`].
+pub struct PcapReader<R> {
+    reader: R,
+    kind: Option<Kind>,
+    interfaces: Vec<Interface>,
+    names: Interfaces,
+    start: Option<Duration>,
+    done: bool,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_interfaces(reader, Interfaces::default())
+    }
+
+    /// `names` maps interface names to channels.  Interfaces without a usable name use their index.
+    pub fn with_interfaces(reader: R, names: Interfaces) -> Self {
+        PcapReader {
+            reader,
+            kind: None,
+            interfaces: Vec::new(),
+            names,
+            start: None,
+            done: false,
+        }
+    }
+
+    /// Wall clock time of the first frame.
+    pub fn start(&self) -> Option<SystemTime> {
+        self.start.map(|s| UNIX_EPOCH + s)
+    }
+
+    /// Fill `buf`, or return false at the end of the file.
+    fn read_exact_or_eof(&mut self, buf: &mut [u8]) -> Result<bool> {
+        match self.reader.read_exact(buf) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            r => r.map(|_| true).map_err(|e| e.into()),
+        }
+    }
+
+    fn read_vec(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut v = vec![0; len];
+        self.reader.read_exact(&mut v)?;
+        Ok(v)
+    }
+
+    /// Read the first block, or the legacy pcap header.
+    fn read_header(&mut self) -> Result<()> {
+        let mut header = [0; 12];
+        self.reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into()?);
+        for (le, magic) in [(true, magic), (false, magic.swap_bytes())] {
+            let resolution = match magic {
+                PCAP_MICROS => 1_000_000,
+                PCAP_NANOS => 1_000_000_000,
+                _ => continue,
+            };
+            let endian = Endian(le);
+            let rest = self.read_vec(12)?;
+            let link_type = endian.u32(&rest, 8) as u16;
+            if link_type != LINKTYPE_CAN_SOCKETCAN {
+                return Err(anyhow!("Unsupported link type {link_type}"));
+            }
+            self.kind = Some(Kind::Pcap { endian, resolution });
+            return Ok(());
+        }
+        if magic != SECTION_HEADER {
+            return Err(anyhow!("Not a pcap or pcapng file"));
+        }
+        self.section(header)
+    }
+
+    /// Section header block.  Starts a new set of interfaces.
+    fn section(&mut self, header: [u8; 12]) -> Result<()> {
+        let endian = Endian(u32::from_le_bytes(header[8..].try_into()?) == BYTE_ORDER_MAGIC);
+        let len = endian.u32(&header, 4) as usize;
+        self.read_vec(len.saturating_sub(header.len()))?;
+        self.kind = Some(Kind::Pcapng { endian });
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    /// Next frame, skipping unsupported records.  `None` at the end of the file.
+    fn next_frame(&mut self) -> Result<Option<Option<Packet>>> {
+        match self.kind {
+            Some(Kind::Pcap { endian, resolution }) => {
+                let mut header = [0; 16];
+                if !self.read_exact_or_eof(&mut header)? {
+                    return Ok(None);
+                }
+                let secs = endian.u32(&header, 0) as u64;
+                let frac = endian.u32(&header, 4) as u64;
+                let data = self.read_vec(endian.u32(&header, 8) as usize)?;
+                let time = Duration::from_secs(secs) + units(frac, resolution);
+                decode(&data, time, 0, false).map(Some)
+            }
+            Some(Kind::Pcapng { endian }) => {
+                let mut header = [0; 12];
+                if !self.read_exact_or_eof(&mut header)? {
+                    return Ok(None);
+                }
+                let block_type = endian.u32(&header, 0);
+                if block_type == SECTION_HEADER {
+                    self.section(header)?;
+                    return Ok(Some(None));
+                }
+                let len = endian.u32(&header, 4) as usize;
+                if len < header.len() + 4 || !len.is_multiple_of(4) {
+                    return Err(anyhow!("Invalid block length {len}"));
+                }
+                let mut block = header[8..].to_vec();
+                block.extend(self.read_vec(len - header.len())?);
+                let body = &block[..block.len() - 4];
+                match block_type {
+                    INTERFACE_DESCRIPTION => {
+                        self.interface(endian, body);
+                        Ok(Some(None))
+                    }
+                    ENHANCED_PACKET => self.enhanced_packet(endian, body).map(Some),
+                    _ => Ok(Some(None)),
+                }
+            }
+            None => unreachable!("header not read"),
+        }
+    }
+
+    fn interface(&mut self, endian: Endian, body: &[u8]) {
+        let link_type = endian.u16(body, 0);
+        let mut interface = Interface {
+            link_type,
+            resolution: 1_000_000,
+            channel: self.interfaces.len() as u32,
+        };
+        for (code, value) in options(endian, body.get(8..).unwrap_or_default()) {
+            match code {
+                IF_NAME => {
+                    let name = String::from_utf8_lossy(value);
+                    if let Some(channel) = self.names.find(name.trim_end_matches('\0')) {
+                        interface.channel = channel;
+                    }
+                }
+                IF_TSRESOL if !value.is_empty() => {
+                    let exponent = (value[0] & 0x7F) as u32;
+                    interface.resolution = if value[0] & 0x80 == 0 {
+                        10u64.saturating_pow(exponent)
+                    } else {
+                        2u64.saturating_pow(exponent)
+                    };
+                }
+                _ => {}
+            }
+        }
+        self.interfaces.push(interface);
+    }
+
+    fn enhanced_packet(&self, endian: Endian, body: &[u8]) -> Result<Option<Packet>> {
+        if body.len() < 20 {
+            return Err(anyhow!("Truncated enhanced packet block"));
+        }
+        let interface = self
+            .interfaces
+            .get(endian.u32(body, 0) as usize)
+            .ok_or_else(|| anyhow!("Unknown interface"))?;
+        if interface.link_type != LINKTYPE_CAN_SOCKETCAN {
+            return Ok(None);
+        }
+        let timestamp = (endian.u32(body, 4) as u64) << 32 | endian.u32(body, 8) as u64;
+        let captured = endian.u32(body, 12) as usize;
+        let data = body
+            .get(20..20 + captured)
+            .ok_or_else(|| anyhow!("Truncated packet data"))?;
+        let options_start = 20 + captured.next_multiple_of(4);
+        let outbound = options(endian, body.get(options_start..).unwrap_or_default())
+            .filter(|(code, value)| *code == EPB_FLAGS && value.len() == 4)
+            .any(|(_, value)| endian.u32(value, 0) & 3 == EPB_OUTBOUND);
+        let time = units(timestamp, interface.resolution);
+        decode(data, time, interface.channel, outbound)
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.kind.is_none() {
+            if let Err(e) = self.read_header() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        loop {
+            match self.next_frame() {
+                Ok(Some(Some(p))) => {
+                    let time = p.time().unwrap_or_default();
+                    let start = *self.start.get_or_insert(time);
+                    return Some(Ok(p.with_time(time.saturating_sub(start))));
+                }
+                Ok(Some(None)) => continue,
+                Ok(None) => return None,
+                Err(e) => {
+                    // blocks can't be resynchronized
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> LogReader for PcapReader<R> {
+    fn start(&self) -> Option<SystemTime> {
+        PcapReader::start(self)
+    }
+}
+
+/// `value` in units of 1/`resolution` seconds.
+fn units(value: u64, resolution: u64) -> Duration {
+    let nanos = value as u128 * 1_000_000_000 / resolution.max(1) as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Block options as (code, value).
+fn options(endian: Endian, mut b: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if b.len() < 4 {
+            return None;
+        }
+        let code = endian.u16(b, 0);
+        let len = endian.u16(b, 2) as usize;
+        if code == OPT_END || b.len() < 4 + len {
+            return None;
+        }
+        let value = &b[4..4 + len];
+        b = &b[(4 + len.next_multiple_of(4)).min(b.len())..];
+        Some((code, value))
+    })
+}
+
+/// Decode a SocketCAN record.
+fn decode(data: &[u8], time: Duration, channel: u32, outbound: bool) -> Result<Option<Packet>> {
+    if data.len() < FRAME_HEADER {
+        return Err(anyhow!("Truncated frame"));
+    }
+    let can_id = u32::from_be_bytes(data[..4].try_into()?);
+    let len = data[4] as usize;
+    let flags = data[5];
+    let body = &data[FRAME_HEADER..];
+    let fd = (body.len() > CAN_MAX_LEN || flags & socketcan::CANFD_FDF != 0).then_some(flags);
+    let payload = if can_id & socketcan::CAN_RTR_FLAG != 0 {
+        vec![0; len]
+    } else {
+        body.get(..len)
+            .ok_or_else(|| anyhow!("Truncated frame data"))?
+            .to_vec()
+    };
+    let packet = socketcan::decode(can_id, &payload, fd, time, channel)?;
+    Ok(Some(match packet.state {
+        PacketState::RX { time, channel } if outbound => Packet {
+            state: PacketState::Echo { time, channel },
+            ..packet
+        },
+        _ => packet,
+    }))
+}
+
+/// Encode a SocketCAN record.  Classic frames are 16 bytes, FD frames are 72.
+fn encode(p: &Packet) -> Vec<u8> {
+    let data = socketcan::frame_data(p);
+    let (len, flags, size) = match (&p.state, p.fd) {
+        (PacketState::Remote { dlc, .. }, _) => (*dlc, 0, CAN_MAX_LEN),
+        (_, Some(fd)) if p.is_data() => (
+            data.len() as u8,
+            socketcan::CANFD_FDF | socketcan::fd_flags(fd),
+            CANFD_MAX_LEN,
+        ),
+        _ => (data.len() as u8, 0, CAN_MAX_LEN),
+    };
+    let mut frame = Vec::with_capacity(FRAME_HEADER + size);
+    frame.extend_from_slice(&socketcan::can_id(p).to_be_bytes());
+    frame.extend_from_slice(&[len, flags, 0, 0]);
+    frame.extend_from_slice(&data);
+    frame.resize(FRAME_HEADER + size, 0);
+    frame
+}
+
+/// Streaming pcapng writer.  Packet times are written relative to `start`.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    names: Interfaces,
+    /// channel to interface id
+    interfaces: HashMap<u32, u32>,
+    start: Duration,
+    last: Duration,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(writer: W, start: SystemTime) -> Result<Self> {
+        Self::with_interfaces(writer, start, Interfaces::default())
+    }
+
+    pub fn with_interfaces(writer: W, start: SystemTime, names: Interfaces) -> Result<Self> {
+        let mut w = PcapWriter {
+            writer,
+            names,
+            interfaces: HashMap::new(),
+            start: start.duration_since(UNIX_EPOCH).unwrap_or_default(),
+            last: Duration::ZERO,
+        };
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // unknown section length
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        w.block(SECTION_HEADER, &body)?;
+        Ok(w)
+    }
+
+    fn block(&mut self, block_type: u32, body: &[u8]) -> Result<()> {
+        let padding = body.len().next_multiple_of(4) - body.len();
+        let len = (12 + body.len() + padding) as u32;
+        self.writer.write_all(&block_type.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&[0; 3][..padding])?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Interface id of a channel, writing its description on first use.
+    fn interface(&mut self, channel: u32) -> Result<u32> {
+        if let Some(id) = self.interfaces.get(&channel) {
+            return Ok(*id);
+        }
+        let id = self.interfaces.len() as u32;
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_CAN_SOCKETCAN.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // no snap length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut body, IF_NAME, self.names.name(channel).as_bytes());
+        // nanoseconds
+        option(&mut body, IF_TSRESOL, &[9]);
+        option(&mut body, OPT_END, &[]);
+        self.block(INTERFACE_DESCRIPTION, &body)?;
+        self.interfaces.insert(channel, id);
+        Ok(id)
+    }
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+impl<W: Write> LogWriter for PcapWriter<W> {
+    /// Packets that have not been sent yet have no time, so they are logged with the time of the previous packet.
+    fn write(&mut self, packet: &Packet) -> Result<()> {
+        if let Some(time) = packet.time() {
+            self.last = time;
+        }
+        let id = self.interface(packet.channel().unwrap_or_default())?;
+        let timestamp = (self.start + self.last).as_nanos() as u64;
+        let frame = encode(packet);
+        let mut body = Vec::with_capacity(32 + frame.len());
+        body.extend_from_slice(&id.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        body.extend_from_slice(&frame);
+        let direction = if packet.is_tx() {
+            EPB_OUTBOUND
+        } else {
+            EPB_INBOUND
+        };
+        option(&mut body, EPB_FLAGS, &direction.to_le_bytes());
+        option(&mut body, OPT_END, &[]);
+        self.block(ENHANCED_PACKET, &body)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::packet::{ErrorClass, FdFlags, IdType};
+
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let packets = [
+            Packet::new_rx(0x18FEF100, &[1, 2, 3], Duration::from_nanos(1_000_001), 0),
+            Packet {
+                state: PacketState::Echo {
+                    time: Duration::from_millis(2).into(),
+                    channel: 1,
+                },
+                ..Packet::new(0x7E0, &[2, 1, 0]).with_id_type(IdType::Standard)
+            },
+            Packet::new_remote_rx(0x7DF, 8, Duration::from_millis(3), 0)
+                .with_id_type(IdType::Standard),
+            Packet::new_rx_fd(
+                0x18DA00F1,
+                &[0x55; 20],
+                FdFlags {
+                    brs: true,
+                    esi: false,
+                },
+                Duration::from_millis(4),
+                2,
+            )?,
+            Packet::new_error(
+                [ErrorClass::Ack].into_iter().collect(),
+                &[],
+                Duration::from_millis(5),
+                1,
+            ),
+        ];
+        let mut buf = Vec::new();
+        let mut writer = PcapWriter::new(&mut buf, start)?;
+        for p in &packets {
+            writer.write(p)?;
+        }
+        writer.finish()?;
+
+        let mut reader = PcapReader::new(Cursor::new(buf));
+        let read: Vec<Packet> = reader.by_ref().collect::<Result<_>>()?;
+        assert_eq!(
+            Some(start + Duration::from_nanos(1_000_001)),
+            reader.start()
+        );
+        assert_eq!(packets.len(), read.len());
+        for (a, b) in packets.iter().zip(read.iter()) {
+            let a = a
+                .clone()
+                .with_time(a.time().unwrap() - Duration::from_nanos(1_000_001));
+            assert_eq!(a.to_string(), b.to_string());
+            assert_eq!(a.channel(), b.channel());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_pcap() -> Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&PCAP_MICROS.to_le_bytes());
+        buf.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0]);
+        buf.extend_from_slice(&(LINKTYPE_CAN_SOCKETCAN as u32).to_le_bytes());
+        buf.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        buf.extend_from_slice(&250_000u32.to_le_bytes());
+        buf.extend_from_slice(&16u32.to_le_bytes());
+        buf.extend_from_slice(&16u32.to_le_bytes());
+        buf.extend_from_slice(&[0x80, 0xFE, 0xF1, 0x00, 3, 0, 0, 0, 1, 2, 3, 0, 0, 0, 0, 0]);
+
+        let mut reader = PcapReader::new(Cursor::new(buf));
+        let p = reader.next().unwrap()?;
+        assert_eq!(0xFEF100, p.id);
+        assert_eq!(IdType::Extended, p.id_type);
+        assert_eq!([1, 2, 3][..], p.payload[..]);
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::new(1_700_000_000, 250_000_000)),
+            reader.start()
+        );
+        assert!(reader.next().is_none());
+        Ok(())
+    }
+}
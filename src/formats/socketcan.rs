@@ -0,0 +1,286 @@
+//! Linux SocketCAN frame encoding, shared by the SocketCAN connection and the candump and pcap formats.  See linux/can.h and linux/can/error.h.
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::{
+    bus_state::{BusState, ErrorState},
+    packet::{ErrorClass, ErrorClasses, FdFlags, IdType, Packet, PacketState, Payload},
+};
+
+pub const CAN_EFF_FLAG: u32 = 0x8000_0000;
+pub const CAN_RTR_FLAG: u32 = 0x4000_0000;
+pub const CAN_ERR_FLAG: u32 = 0x2000_0000;
+pub const CAN_SFF_MASK: u32 = 0x0000_07FF;
+pub const CAN_EFF_MASK: u32 = 0x1FFF_FFFF;
+pub const CAN_ERR_MASK: u32 = 0x1FFF_FFFF;
+
+pub const CANFD_BRS: u8 = 0x01;
+pub const CANFD_ESI: u8 = 0x02;
+pub const CANFD_FDF: u8 = 0x04;
+
+/// Length of an error frame's data.
+pub const CAN_ERR_DLC: usize = 8;
+
+const CAN_ERR_TX_TIMEOUT: u32 = 0x001;
+const CAN_ERR_LOSTARB: u32 = 0x002;
+const CAN_ERR_CRTL: u32 = 0x004;
+const CAN_ERR_PROT: u32 = 0x008;
+const CAN_ERR_TRX: u32 = 0x010;
+const CAN_ERR_ACK: u32 = 0x020;
+const CAN_ERR_BUSOFF: u32 = 0x040;
+const CAN_ERR_BUSERROR: u32 = 0x080;
+const CAN_ERR_RESTARTED: u32 = 0x100;
+/// data[6] and data[7] are the TX and RX error counters
+const CAN_ERR_CNT: u32 = 0x200;
+
+// data[1] when CAN_ERR_CRTL is set
+const CAN_ERR_CRTL_OVERFLOW: u8 = 0x03;
+const CAN_ERR_CRTL_WARNING: u8 = 0x0C;
+const CAN_ERR_CRTL_PASSIVE: u8 = 0x30;
+const CAN_ERR_CRTL_ACTIVE: u8 = 0x40;
+
+const CLASSES: [(u32, ErrorClass); 8] = [
+    (CAN_ERR_TX_TIMEOUT, ErrorClass::TxTimeout),
+    (CAN_ERR_LOSTARB, ErrorClass::ArbitrationLost),
+    (CAN_ERR_PROT, ErrorClass::Protocol),
+    (CAN_ERR_TRX, ErrorClass::Transceiver),
+    (CAN_ERR_ACK, ErrorClass::Ack),
+    (CAN_ERR_BUSOFF, ErrorClass::BusOff),
+    (CAN_ERR_BUSERROR, ErrorClass::BusError),
+    (CAN_ERR_RESTARTED, ErrorClass::Restarted),
+];
+
+/// Decode the `CAN_ERR_*` classes of an error frame.
+pub fn error_classes(class: u32, data: &[u8]) -> ErrorClasses {
+    let mut errors: ErrorClasses = CLASSES
+        .iter()
+        .filter(|(bit, _)| class & bit != 0)
+        .map(|(_, error)| *error)
+        .collect();
+    if class & CAN_ERR_CRTL != 0 {
+        let crtl = data.get(1).copied().unwrap_or_default();
+        if crtl & CAN_ERR_CRTL_OVERFLOW != 0 {
+            errors.insert(ErrorClass::Overrun);
+        }
+        if crtl & CAN_ERR_CRTL_WARNING != 0 {
+            errors.insert(ErrorClass::ErrorWarning);
+        }
+        if crtl & CAN_ERR_CRTL_PASSIVE != 0 {
+            errors.insert(ErrorClass::ErrorPassive);
+        }
+        if crtl & !(CAN_ERR_CRTL_OVERFLOW | CAN_ERR_CRTL_WARNING | CAN_ERR_CRTL_PASSIVE) != 0 {
+            errors.insert(ErrorClass::Controller);
+        }
+    }
+    errors
+}
+
+/// Controller state reported by an error frame: the `CAN_ERR_CRTL` state, bus off, restarts and, with `CAN_ERR_CNT`,
+/// the error counters.  `None` if the frame doesn't report the state.
+pub fn bus_state(class: u32, data: &[u8]) -> Option<BusState> {
+    let counters = match data.get(6..8) {
+        Some(&[tx, rx]) if class & CAN_ERR_CNT != 0 => Some((tx, rx)),
+        _ => None,
+    };
+    let crtl = if class & CAN_ERR_CRTL != 0 {
+        data.get(1).copied().unwrap_or_default()
+    } else {
+        0
+    };
+    let error_state = if class & CAN_ERR_BUSOFF != 0 {
+        ErrorState::BusOff
+    } else if crtl & CAN_ERR_CRTL_PASSIVE != 0 {
+        ErrorState::Passive
+    } else if crtl & CAN_ERR_CRTL_WARNING != 0 {
+        ErrorState::Warning
+    } else if crtl & CAN_ERR_CRTL_ACTIVE != 0 || class & CAN_ERR_RESTARTED != 0 {
+        ErrorState::Active
+    } else {
+        let (tx, rx) = counters?;
+        ErrorState::from_counters(tx, rx)
+    };
+    Some(BusState {
+        error_state,
+        tx_errors: counters.map(|(tx, _)| tx),
+        rx_errors: counters.map(|(_, rx)| rx),
+    })
+}
+
+/// Encode error classes as the `CAN_ERR_*` class and data of an error frame.  `data` is the original error data, if any.
+pub fn error_frame(errors: ErrorClasses, data: &[u8]) -> (u32, [u8; CAN_ERR_DLC]) {
+    let mut class = CLASSES
+        .iter()
+        .filter(|(_, error)| errors.contains(*error))
+        .fold(0, |class, (bit, _)| class | bit);
+    let mut frame = [0; CAN_ERR_DLC];
+    let len = data.len().min(CAN_ERR_DLC);
+    frame[..len].copy_from_slice(&data[..len]);
+    for (error, bits) in [
+        (ErrorClass::Overrun, CAN_ERR_CRTL_OVERFLOW),
+        (ErrorClass::ErrorWarning, CAN_ERR_CRTL_WARNING),
+        (ErrorClass::ErrorPassive, CAN_ERR_CRTL_PASSIVE),
+        (ErrorClass::Controller, CAN_ERR_CRTL_ACTIVE),
+    ] {
+        if errors.contains(error) {
+            class |= CAN_ERR_CRTL;
+            frame[1] |= bits;
+        }
+    }
+    (class, frame)
+}
+
+/// `can_id` with the `CAN_*_FLAG` bits.  Status reports are encoded as error frames.
+pub fn can_id(p: &Packet) -> u32 {
+    match p.state {
+        PacketState::Error { errors, .. } | PacketState::Status { errors, .. } => {
+            CAN_ERR_FLAG | error_frame(errors, &p.payload).0
+        }
+        _ => {
+            let id = if p.is_extended() {
+                CAN_EFF_FLAG | (p.id & CAN_EFF_MASK)
+            } else {
+                p.id & CAN_SFF_MASK
+            };
+            if p.is_remote() {
+                id | CAN_RTR_FLAG
+            } else {
+                id
+            }
+        }
+    }
+}
+
+/// Frame data.  Error frames are always 8 bytes.  Remote frames have no data.
+pub fn frame_data(p: &Packet) -> Payload {
+    match p.state {
+        PacketState::Error { errors, .. } | PacketState::Status { errors, .. } => {
+            error_frame(errors, &p.payload).1.into()
+        }
+        PacketState::Remote { .. } => Payload::default(),
+        _ => p.payload.clone(),
+    }
+}
+
+/// `CANFD_*` flags of an FD frame.
+pub fn fd_flags(fd: FdFlags) -> u8 {
+    (if fd.brs { CANFD_BRS } else { 0 }) | (if fd.esi { CANFD_ESI } else { 0 })
+}
+
+/// Decode a frame.  `fd` is `None` for classic frames.  The DLC of a remote frame is the length of `data`.
+pub fn decode(
+    can_id: u32,
+    data: &[u8],
+    fd: Option<u8>,
+    time: Duration,
+    channel: u32,
+) -> Result<Packet> {
+    let (id, id_type) = if can_id & CAN_EFF_FLAG != 0 {
+        (can_id & CAN_EFF_MASK, IdType::Extended)
+    } else {
+        (can_id & CAN_SFF_MASK, IdType::Standard)
+    };
+    if can_id & CAN_ERR_FLAG != 0 {
+        let errors = error_classes(can_id & CAN_ERR_MASK, data);
+        return Ok(Packet::new_error(errors, data, time, channel));
+    }
+    let packet = if let Some(flags) = fd {
+        let flags = FdFlags {
+            brs: flags & CANFD_BRS != 0,
+            esi: flags & CANFD_ESI != 0,
+        };
+        Packet::new_rx_fd(id, data, flags, time, channel)?
+    } else if can_id & CAN_RTR_FLAG != 0 {
+        Packet::new_remote_rx(id, data.len() as u8, time, channel)
+    } else {
+        Packet::new_rx(id, data, time, channel)
+    };
+    Ok(packet.with_id_type(id_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_errors() {
+        let errors = error_classes(0x004 | 0x020, &[0, 0x20, 0, 0, 0, 0, 0x80, 0]);
+        assert!(errors.contains(ErrorClass::Ack));
+        assert!(errors.contains(ErrorClass::ErrorPassive));
+        assert!(!errors.contains(ErrorClass::Controller));
+        assert!(!errors.contains(ErrorClass::BusOff));
+
+        let errors = error_classes(0x040, &[]);
+        assert_eq!("BusOff", errors.to_string());
+    }
+
+    #[test]
+    fn decode_bus_state() {
+        let passive = bus_state(0x004 | 0x200, &[0, 0x20, 0, 0, 0, 0, 0x80, 0]).unwrap();
+        assert_eq!(ErrorState::Passive, passive.error_state);
+        assert_eq!(
+            (Some(0x80), Some(0)),
+            (passive.tx_errors, passive.rx_errors)
+        );
+        // the counters alone give the state
+        let warning = bus_state(0x008 | 0x200, &[0, 0, 0, 0, 0, 0, 0, 100]).unwrap();
+        assert_eq!(ErrorState::Warning, warning.error_state);
+        let active = bus_state(0x004, &[0, 0x40, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(BusState::default(), active);
+        assert_eq!(
+            ErrorState::BusOff,
+            bus_state(0x040, &[]).unwrap().error_state
+        );
+        // a protocol error doesn't change the state
+        assert_eq!(None, bus_state(0x008, &[0; 8]));
+    }
+
+    #[test]
+    fn encode_errors() {
+        let errors: ErrorClasses = [
+            ErrorClass::Ack,
+            ErrorClass::ErrorPassive,
+            ErrorClass::Overrun,
+        ]
+        .into_iter()
+        .collect();
+        let (class, data) = error_frame(errors, &[]);
+        assert_eq!(CAN_ERR_ACK | CAN_ERR_CRTL, class);
+        assert_eq!(errors, error_classes(class, &data));
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let time = Duration::from_millis(5);
+        for p in [
+            Packet::new_rx(0x18FEF100, &[1, 2, 3], time, 1),
+            Packet::new_rx(0x7E8, &[2, 0x41, 0x0C], time, 0).with_id_type(IdType::Standard),
+            Packet::new_remote_rx(0x7DF, 8, time, 0).with_id_type(IdType::Standard),
+            Packet::new_rx_fd(
+                0x1A4,
+                &[7; 20],
+                FdFlags {
+                    brs: true,
+                    esi: true,
+                },
+                time,
+                2,
+            )?
+            .with_id_type(IdType::Standard),
+        ] {
+            let mut data = frame_data(&p).to_vec();
+            if let PacketState::Remote { dlc, .. } = p.state {
+                data.resize(dlc as usize, 0);
+            }
+            let decoded = decode(
+                can_id(&p),
+                &data,
+                p.fd.map(fd_flags),
+                time,
+                p.channel().unwrap(),
+            )?;
+            assert_eq!(p.to_string(), decoded.to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+//! PEAK PCAN-View TRC trace files, versions 1.0 to 2.1.  The writer creates version 2.1.
+//!
+//! ```text
+//! ;$FILEVERSION=2.1
+//! ;$STARTTIME=45321.5012345
+//! ;$COLUMNS=N,O,T,B,I,d,R,L,D
+//! ;
+//!       1         1.500 DT 1      0300 Rx -  8    00 01 02 03 04 05 06 07
+//!       2         2.000 FB 1  18DA00F1 Tx -  9    00 01 02 03 04 05 06 07 08 09 0A 0B
+//!       3         2.500 RR 1      07DF Rx -  8
+//! ```
+//!
+//! Times are milliseconds since the `STARTTIME`, which is days since 1899-12-30.  Ids with 4 digits are standard, 8
+//! digits are extended.  TRC buses start at 1.  The id of an `ER` line written here holds the error classes, and
+//! whether it is a status report, where PCAN-View writes `-`.
+use std::{
+    io::{BufRead, Lines, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    formats::{pack_errors, unpack_errors, LogReader, LogWriter},
+    packet::{dlc_to_len, FdFlags, IdType, Packet, PacketState},
+};
+
+/// Days from 1899-12-30 to 1970-01-01.
+const EPOCH_DAYS: f64 = 25_569.0;
+const DAY: f64 = 86_400.0;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Version {
+    V1_0,
+    V1_1,
+    V1_2,
+    V1_3,
+    /// 2.x with the `$COLUMNS` layout
+    V2(Vec<char>),
+}
+
+/// Streaming TRC reader.  Status, event and warning lines are skipped.
+pub struct TrcReader<R> {
+    lines: Lines<R>,
+    version: Version,
+    start: Option<SystemTime>,
+}
+
+impl<R: BufRead> TrcReader<R> {
+    pub fn new(reader: R) -> Self {
+        TrcReader {
+            lines: reader.lines(),
+            version: Version::V1_0,
+            start: None,
+        }
+    }
+
+    /// Wall clock time of the start of the trace, from the `$STARTTIME` header.
+    pub fn start(&self) -> Option<SystemTime> {
+        self.start
+    }
+
+    fn header(&mut self, line: &str) {
+        if let Some(version) = line.strip_prefix(";$FILEVERSION=") {
+            self.version = match version.trim() {
+                "1.1" => Version::V1_1,
+                "1.2" => Version::V1_2,
+                "1.3" => Version::V1_3,
+                v if v.starts_with('2') => Version::V2("NOTIdlD".chars().collect()),
+                _ => Version::V1_0,
+            };
+        } else if let Some(start) = line.strip_prefix(";$STARTTIME=") {
+            self.start = start
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|days| *days >= EPOCH_DAYS)
+                .and_then(|days| Duration::try_from_secs_f64((days - EPOCH_DAYS) * DAY).ok())
+                .map(|since| UNIX_EPOCH + since);
+        } else if let Some(columns) = line.strip_prefix(";$COLUMNS=") {
+            self.version = Version::V2(
+                columns
+                    .split(',')
+                    .filter_map(|c| c.trim().chars().next())
+                    .collect(),
+            );
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TrcReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if line.starts_with(';') {
+                self.header(&line);
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_line(&line, &self.version) {
+                Ok(Some(packet)) => return Some(Ok(packet)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> LogReader for TrcReader<R> {
+    fn start(&self) -> Option<SystemTime> {
+        TrcReader::start(self)
+    }
+}
+
+/// Fields of one line, before they are interpreted.
+#[derive(Default)]
+struct Fields<'a> {
+    offset: &'a str,
+    kind: &'a str,
+    bus: Option<&'a str>,
+    id: &'a str,
+    dir: Option<&'a str>,
+    dlc: Option<&'a str>,
+    len: Option<&'a str>,
+    data: Vec<&'a str>,
+}
+
+fn parse_line(line: &str, version: &Version) -> Result<Option<Packet>> {
+    let mut tokens = line.split_whitespace();
+    let mut next = |what| {
+        tokens
+            .next()
+            .ok_or_else(|| anyhow!("Missing {what}: {line}"))
+    };
+    let mut f = Fields::default();
+    match version {
+        Version::V2(columns) => {
+            for column in columns {
+                match column {
+                    'N' => {
+                        next("number")?;
+                    }
+                    'O' => f.offset = next("offset")?,
+                    'T' => {
+                        f.kind = next("type")?;
+                        // status, error counter and event lines have other layouts
+                        if matches!(f.kind, "ST" | "EC" | "EV") {
+                            return Ok(None);
+                        }
+                    }
+                    'B' => f.bus = Some(next("bus")?),
+                    'I' => f.id = next("id")?,
+                    'd' => f.dir = Some(next("direction")?),
+                    'R' => {
+                        next("reserved")?;
+                    }
+                    'L' => f.dlc = Some(next("DLC")?),
+                    'l' => f.len = Some(next("length")?),
+                    // data is last
+                    _ => {}
+                }
+            }
+        }
+        _ => {
+            next("number")?;
+            f.offset = next("offset")?;
+            if matches!(version, Version::V1_2 | Version::V1_3) {
+                f.bus = Some(next("bus")?);
+            }
+            f.kind = if *version == Version::V1_0 {
+                "Rx"
+            } else {
+                next("type")?
+            };
+            if f.kind == "Warng" {
+                return Ok(None);
+            }
+            f.id = next("id")?;
+            if *version == Version::V1_3 {
+                next("reserved")?;
+            }
+            f.len = Some(next("length")?);
+        }
+    }
+    f.data = tokens.collect();
+    decode(&f).map(Some)
+}
+
+fn decode(f: &Fields) -> Result<Packet> {
+    let offset = f
+        .offset
+        .parse::<f64>()
+        .map_err(|e| anyhow!("Invalid offset: {e} {:?}", f.offset))?;
+    let time = Duration::try_from_secs_f64(offset / 1000.0)
+        .map_err(|e| anyhow!("Invalid offset: {e} {:?}", f.offset))?;
+    let channel = match f.bus {
+        Some(bus) => bus
+            .parse::<u32>()
+            .map_err(|e| anyhow!("Invalid bus: {e} {bus:?}"))?
+            .saturating_sub(1),
+        None => 0,
+    };
+    let len = match (f.len, f.dlc) {
+        (Some(len), _) => len.parse::<usize>()?,
+        (None, Some(dlc)) => dlc_to_len(dlc.parse()?),
+        (None, None) => 0,
+    };
+    let bytes = |len: usize| -> Result<Vec<u8>> {
+        f.data
+            .iter()
+            .take(len)
+            .map(|b| u8::from_str_radix(b, 16).map_err(|e| anyhow!("Invalid data: {e} {b:?}")))
+            .collect()
+    };
+    if matches!(f.kind, "ER" | "Error") {
+        let data = bytes(f.data.len())?;
+        // `-` from PCAN-View
+        let packed = u32::from_str_radix(f.id, 16).unwrap_or_default();
+        return Ok(unpack_errors(packed, &data, time, channel));
+    }
+    let id_type = if f.id.len() > 4 {
+        IdType::Extended
+    } else {
+        IdType::Standard
+    };
+    let id = u32::from_str_radix(f.id, 16).map_err(|e| anyhow!("Invalid id: {e} {:?}", f.id))?;
+    let tx = f.dir.unwrap_or(f.kind) == "Tx";
+    let fd = |brs, esi| Some(FdFlags { brs, esi });
+    let (remote, fd) = match f.kind {
+        "RR" => (true, None),
+        "FD" => (false, fd(false, false)),
+        "FB" => (false, fd(true, false)),
+        "FE" => (false, fd(false, true)),
+        "BI" => (false, fd(true, true)),
+        _ => (f.data.first() == Some(&"RTR"), None),
+    };
+    let packet = if remote {
+        Packet::new_remote_rx(id, len as u8, time, channel)
+    } else {
+        let data = bytes(len)?;
+        if data.len() != len {
+            return Err(anyhow!("Expected {len} data bytes, found {}", data.len()));
+        }
+        match fd {
+            Some(flags) => Packet::new_rx_fd(id, &data, flags, time, channel)?,
+            None => Packet::new_rx(id, &data, time, channel),
+        }
+    };
+    let packet = packet.with_id_type(id_type);
+    Ok(match packet.state {
+        PacketState::RX { time, channel } if tx => Packet {
+            state: PacketState::Echo { time, channel },
+            ..packet
+        },
+        _ => packet,
+    })
+}
+
+/// Streaming TRC 2.1 writer.
+pub struct TrcWriter<W: Write> {
+    writer: W,
+    count: u64,
+    last: Duration,
+}
+
+impl<W: Write> TrcWriter<W> {
+    /// `start` is the wall clock time of the start of the trace.
+    pub fn new(mut writer: W, start: SystemTime) -> Result<Self> {
+        let secs = start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        writeln!(writer, ";$FILEVERSION=2.1")?;
+        writeln!(writer, ";$STARTTIME={:.10}", EPOCH_DAYS + secs / DAY)?;
+        writeln!(writer, ";$COLUMNS=N,O,T,B,I,d,R,L,D")?;
+        writeln!(writer, ";")?;
+        writeln!(writer, ";   Message   Time    Type    ID     Rx/Tx")?;
+        writeln!(writer, ";   Number    Offset  |  Bus  [hex]  |  Reserved")?;
+        writeln!(
+            writer,
+            ";   |         [ms]    |  |    |      |  |  Data Length Code"
+        )?;
+        writeln!(
+            writer,
+            ";   |         |       |  |    |      |  |  |    Data [hex] ..."
+        )?;
+        writeln!(writer, ";   |         |       |  |    |      |  |  |    |")?;
+        writeln!(
+            writer,
+            ";---+-- ------+------ +- +- --+----- +- +- +--- +- -- -- -- -- -- -- --"
+        )?;
+        Ok(TrcWriter {
+            writer,
+            count: 0,
+            last: Duration::ZERO,
+        })
+    }
+}
+
+impl<W: Write> LogWriter for TrcWriter<W> {
+    /// Packets that have not been sent yet have no time, so they are logged with the time of the previous packet.
+    fn write(&mut self, packet: &Packet) -> Result<()> {
+        if let Some(time) = packet.time() {
+            self.last = time;
+        }
+        self.count += 1;
+        let id = if packet.is_extended() {
+            format!("{:08X}", packet.id)
+        } else {
+            format!("{:04X}", packet.id)
+        };
+        let (kind, id, dlc, data) = match (&packet.state, packet.fd) {
+            (PacketState::Remote { dlc, .. }, _) => ("RR", id, *dlc, String::new()),
+            (PacketState::Error { .. } | PacketState::Status { .. }, _) => (
+                "ER",
+                format!("{:08X}", pack_errors(packet)),
+                packet.payload.len() as u8,
+                packet.payload_str(),
+            ),
+            (_, Some(fd)) => {
+                let kind = match (fd.brs, fd.esi) {
+                    (false, false) => "FD",
+                    (true, false) => "FB",
+                    (false, true) => "FE",
+                    (true, true) => "BI",
+                };
+                (kind, id, packet.dlc(), packet.payload_str())
+            }
+            _ => ("DT", id, packet.payload.len() as u8, packet.payload_str()),
+        };
+        let dir = if packet.is_tx() { "Tx" } else { "Rx" };
+        let line = format!(
+            "{:>7} {:>13.3} {kind} {:<2} {id:>8} {dir} -  {dlc:<4} {data}",
+            self.count,
+            self.last.as_secs_f64() * 1000.0,
+            packet.channel().unwrap_or_default() + 1,
+        );
+        writeln!(self.writer, "{}", line.trim_end())?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::packet::ErrorClass;
+
+    #[test]
+    fn versions() -> Result<()> {
+        let v1_1 = ";$FILEVERSION=1.1
+;$STARTTIME=43031.6295611227
+     1)      1059.9  Rx         0300  8  00 01 02 03 04 05 06 07
+     2)      1060.0  Tx     18FEF100  3  01 02 03
+     3)      1061.0  Rx         07DF  8  RTR
+     4)      1062.0  Warng  FFFFFFFF  4  00 00 00 08  BUSHEAVY
+";
+        let packets: Vec<Packet> = TrcReader::new(Cursor::new(v1_1)).collect::<Result<_>>()?;
+        assert_eq!(3, packets.len());
+        assert_eq!(IdType::Standard, packets[0].id_type);
+        assert_eq!(1_059_900, packets[0].time().unwrap().as_micros());
+        assert!(packets[1].is_tx());
+        assert!(packets[1].is_extended());
+        assert!(packets[2].is_remote());
+
+        let v1_3 = ";$FILEVERSION=1.3
+     1)      1059.900 2  Rx         0300 -  2    00 01
+";
+        let p = TrcReader::new(Cursor::new(v1_3)).next().unwrap()?;
+        assert_eq!(Some(1), p.channel());
+        assert_eq!([0, 1][..], p.payload[..]);
+
+        let v2_0 = ";$FILEVERSION=2.0
+;$COLUMNS=N,O,T,I,d,l,D
+      1      1059.900 FB     0300 Rx 12   00 01 02 03 04 05 06 07 08 09 0A 0B
+      2      1060.000 ST     Rx  00 00 00 04
+";
+        let packets: Vec<Packet> = TrcReader::new(Cursor::new(v2_0)).collect::<Result<_>>()?;
+        assert_eq!(1, packets.len());
+        assert_eq!(
+            Some(FdFlags {
+                brs: true,
+                esi: false
+            }),
+            packets[0].fd
+        );
+        assert_eq!(12, packets[0].payload.len());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let packets = [
+            Packet::new_rx(0x18FEF100, &[1, 2, 3], Duration::from_millis(1), 0),
+            Packet {
+                state: PacketState::Echo {
+                    time: Duration::from_millis(2).into(),
+                    channel: 1,
+                },
+                ..Packet::new(0x7E0, &[2, 1, 0]).with_id_type(IdType::Standard)
+            },
+            Packet::new_remote_rx(0x7DF, 8, Duration::from_millis(3), 0)
+                .with_id_type(IdType::Standard),
+            Packet::new_rx_fd(
+                0x18DA00F1,
+                &[0x55; 20],
+                FdFlags {
+                    brs: true,
+                    esi: true,
+                },
+                Duration::from_millis(4),
+                2,
+            )?,
+            Packet::new_error(
+                [ErrorClass::Ack, ErrorClass::Protocol]
+                    .into_iter()
+                    .collect(),
+                &[1, 2],
+                Duration::from_millis(5),
+                0,
+            ),
+            Packet::new_status(
+                [ErrorClass::ErrorPassive].into_iter().collect(),
+                Duration::from_millis(6),
+                1,
+            ),
+        ];
+        let mut buf = Vec::new();
+        let mut writer = TrcWriter::new(&mut buf, start)?;
+        for p in &packets {
+            writer.write(p)?;
+        }
+        writer.finish()?;
+        let text = String::from_utf8(buf.clone())?;
+        assert!(text.contains("      1         1.000 DT 1  18FEF100 Rx -  3    01 02 03\n"));
+
+        let mut reader = TrcReader::new(Cursor::new(buf));
+        let read: Vec<Packet> = reader.by_ref().collect::<Result<_>>()?;
+        let delta = reader
+            .start()
+            .unwrap()
+            .duration_since(start)
+            .unwrap_or_else(|e| e.duration());
+        assert!(delta < Duration::from_millis(1));
+        assert_eq!(packets.len(), read.len());
+        for (a, b) in packets.iter().zip(read.iter()) {
+            assert_eq!(a.to_string(), b.to_string());
+            assert_eq!(a.errors(), b.errors());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn errors() -> Result<()> {
+        // PCAN-View doesn't give the classes
+        let log = ";$FILEVERSION=2.1
+;$COLUMNS=N,O,T,B,I,d,R,L,D
+      1         1.500 ER 1         - Rx -  5    04 01 05 00 00
+      2        -2.000 DT 1      0300 Rx -  1    00
+";
+        let packets: Vec<Result<Packet>> = TrcReader::new(Cursor::new(log)).collect();
+        let p = packets[0].as_ref().unwrap();
+        assert_eq!(Some(Default::default()), p.errors());
+        assert_eq!(5, p.payload.len());
+        assert!(packets[1].is_err());
+        Ok(())
+    }
+}
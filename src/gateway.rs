@@ -0,0 +1,482 @@
+//! Forwards packets between two connections, such as an ECU and the vehicle, with rules to block or modify them.
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    connection::{Connection, IdMask},
+    packet::{IdType, Packet, PacketState, Payload},
+};
+
+/// How long the echo of a forwarded packet is remembered after the send returns, so it is not forwarded back.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a [`Rule`] does to the packets it matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Forward the packet as it is now, skipping the later rules.
+    Pass,
+    /// Don't forward the packet.
+    Drop,
+    /// Replace the id.
+    Id(u32),
+    /// Overwrite the payload from `offset`.  Bytes past the end of the payload are ignored.
+    Data { offset: usize, bytes: Vec<u8> },
+    /// Replace the J1939 source address.
+    Source(u8),
+    /// Replace the J1939 destination address.  PDU2 packets are broadcast, so they are unchanged.
+    Destination(u8),
+}
+
+/// Applies `action` to the packets matching `ids`.  A direction's rules are checked in order, each against the packet
+/// as changed by the rules before it.  Packets that reach the end of the rules are forwarded.
+///
+/// Parsed from `<id>[/<mask>]:<action>`, in hex, where the action is `drop`, `pass`, `id=<id>`, `sa=<address>`,
+/// `da=<address>` or `data@<offset>=<bytes>`.  For example `18FEF100/FFFF00:drop` or `18EA00F9/FF00FF:sa=FA`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub ids: IdMask,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn new(ids: IdMask, action: Action) -> Self {
+        Rule { ids, action }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = |s: &str| u32::from_str_radix(s, 16).with_context(|| format!("Invalid hex {s}"));
+        let byte = |s: &str| u8::from_str_radix(s, 16).with_context(|| format!("Invalid byte {s}"));
+        let (ids, action) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Rule {s} is not <id>[/<mask>]:<action>"))?;
+        let ids = match ids.split_once('/') {
+            Some((id, mask)) => IdMask::new(hex(id)?, hex(mask)?),
+            None => IdMask::exact(hex(ids)?),
+        };
+        let action = match action.split_once('=') {
+            None if action == "drop" => Action::Drop,
+            None if action == "pass" => Action::Pass,
+            Some(("id", id)) => Action::Id(hex(id)?),
+            Some(("sa", sa)) => Action::Source(byte(sa)?),
+            Some(("da", da)) => Action::Destination(byte(da)?),
+            Some((data, bytes)) if data.starts_with("data@") => {
+                if !bytes.is_ascii() || bytes.len() % 2 != 0 {
+                    return Err(anyhow!("Invalid bytes {bytes}"));
+                }
+                Action::Data {
+                    offset: data["data@".len()..].parse()?,
+                    bytes: (0..bytes.len())
+                        .step_by(2)
+                        .map(|i| byte(&bytes[i..i + 2]))
+                        .collect::<Result<_>>()?,
+                }
+            }
+            _ => return Err(anyhow!("Unknown action {action}")),
+        };
+        Ok(Rule { ids, action })
+    }
+}
+
+/// Counts for one direction of a [`Gateway`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForwardStats {
+    /// Packets sent on the other connection, including modified ones
+    pub forwarded: u64,
+    /// Forwarded packets changed by a rule
+    pub modified: u64,
+    /// Packets dropped by a rule
+    pub dropped: u64,
+    /// Packets the other connection failed to send
+    pub failed: u64,
+}
+
+impl Display for ForwardStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "forwarded {} modified {} dropped {} failed {}",
+            self.forwarded, self.modified, self.dropped, self.failed
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GatewayStats {
+    pub a_to_b: ForwardStats,
+    pub b_to_a: ForwardStats,
+}
+
+/// Forwards the data packets of connection `a` to `b` and of `b` to `a`, with a list of [`Rule`]s for each direction.
+/// Error and status packets are not forwarded.  Clones share the rules and statistics.
+///
+/// Each direction sends one packet at a time and waits for [`Connection::send`] to return its echo, so it forwards at
+/// most one packet per round trip of the destination adapter.  Packets received meanwhile wait in the subscription,
+/// and are dropped by its overflow policy if they outpace the adapter.
+#[derive(Clone)]
+pub struct Gateway {
+    a_to_b: Arc<Direction>,
+    b_to_a: Arc<Direction>,
+}
+
+#[derive(Default)]
+struct Direction {
+    rules: Vec<Rule>,
+    forwarded: AtomicU64,
+    modified: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    /// Packets being sent and the echoes of those sent recently
+    sent: Mutex<VecDeque<Sent>>,
+    /// Signaled when a send returns
+    done: Condvar,
+    sequence: AtomicU64,
+}
+
+/// A packet forwarded by a [`Direction`], to recognize its echo on the destination.
+struct Sent {
+    sequence: u64,
+    packet: Packet,
+    /// When the send returned, and the echo it returned.  `None` while sending.
+    echo: Option<(Instant, Packet)>,
+}
+
+impl Gateway {
+    pub fn new(a_to_b: Vec<Rule>, b_to_a: Vec<Rule>) -> Self {
+        let direction = |rules| {
+            Arc::new(Direction {
+                rules,
+                ..Default::default()
+            })
+        };
+        Gateway {
+            a_to_b: direction(a_to_b),
+            b_to_a: direction(b_to_a),
+        }
+    }
+
+    /// Forward until `running` is cleared or either connection closes.
+    pub fn run(&self, a: &dyn Connection, b: &dyn Connection, running: &AtomicBool) {
+        let stop = AtomicBool::new(false);
+        let running = || running.load(Ordering::Relaxed) && !stop.load(Ordering::Relaxed);
+        thread::scope(|s| {
+            s.spawn(|| {
+                forward(a, b, &self.a_to_b, &self.b_to_a, &running);
+                stop.store(true, Ordering::Relaxed);
+            });
+            forward(b, a, &self.b_to_a, &self.a_to_b, &running);
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+
+    pub fn stats(&self) -> GatewayStats {
+        GatewayStats {
+            a_to_b: self.a_to_b.stats(),
+            b_to_a: self.b_to_a.stats(),
+        }
+    }
+}
+
+/// Forward the packets of `from` to `to`, except the echoes of the packets sent to `from` by the other direction.
+fn forward(
+    from: &dyn Connection,
+    to: &dyn Connection,
+    out: &Direction,
+    back: &Direction,
+    running: &dyn Fn() -> bool,
+) {
+    for packet in from.iter() {
+        if !running() {
+            break;
+        }
+        let Some(packet) = packet.filter(|p| p.is_data()) else {
+            continue;
+        };
+        // also the packets the adapter marks as its own, sent on `from` by someone else
+        if back.is_echo(&packet) || matches!(packet.state, PacketState::Echo { .. }) {
+            continue;
+        }
+        let Some((packet, modified)) = out.apply(packet) else {
+            out.dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        let sequence = out.sending(&packet);
+        let result = to.send(&packet);
+        out.sent(sequence, result.as_ref().ok().cloned());
+        if result.is_ok() {
+            out.forwarded.fetch_add(1, Ordering::Relaxed);
+            if modified {
+                out.modified.fetch_add(1, Ordering::Relaxed);
+            }
+        } else {
+            out.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Direction {
+    /// The packet to send, and whether a rule changed it.  `None` if a rule drops it.
+    fn apply(&self, packet: Packet) -> Option<(Packet, bool)> {
+        let mut packet = Packet {
+            state: PacketState::TX,
+            ..packet
+        };
+        let mut modified = false;
+        for rule in &self.rules {
+            if !rule.ids.matches(packet.id) {
+                continue;
+            }
+            match &rule.action {
+                Action::Pass => break,
+                Action::Drop => return None,
+                Action::Id(id) => {
+                    packet.id = *id;
+                    if *id > 0x7FF {
+                        packet.id_type = IdType::Extended;
+                    }
+                }
+                Action::Data { offset, bytes } => {
+                    let mut payload = packet.payload.to_vec();
+                    payload
+                        .iter_mut()
+                        .skip(*offset)
+                        .zip(bytes)
+                        .for_each(|(b, new)| *b = *new);
+                    packet.payload = Payload::from(payload);
+                }
+                Action::Source(sa) => packet.id = packet.id & !0xFF | *sa as u32,
+                Action::Destination(da) => {
+                    // PDU1 has a destination address
+                    if (packet.id >> 16) & 0xFF < 0xF0 {
+                        packet.id = packet.id & !0xFF00 | (*da as u32) << 8;
+                    }
+                }
+            }
+            modified = true;
+        }
+        Some((packet, modified))
+    }
+
+    /// Remember `packet` while it is sent.
+    fn sending(&self, packet: &Packet) -> u64 {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        self.sent.lock().unwrap().push_back(Sent {
+            sequence,
+            packet: packet.clone(),
+            echo: None,
+        });
+        sequence
+    }
+
+    /// Keep the echo the send returned, or forget a packet that failed to send.
+    fn sent(&self, sequence: u64, echo: Option<Packet>) {
+        let mut sent = self.sent.lock().unwrap();
+        if let Some(i) = sent.iter().position(|s| s.sequence == sequence) {
+            match echo {
+                Some(echo) => sent[i].echo = Some((Instant::now(), echo)),
+                None => {
+                    sent.remove(i);
+                }
+            }
+        }
+        self.done.notify_all();
+    }
+
+    /// Whether `packet` is the echo returned by a send, which is then forgotten.  The echo is received before the
+    /// send returns, so packets like one still being sent wait for its result.  A packet from the bus that only
+    /// looks like a forwarded one has a different time, and is forwarded.
+    fn is_echo(&self, packet: &Packet) -> bool {
+        let like = |p: &Packet| {
+            p.id == packet.id && p.id_type == packet.id_type && p.payload == packet.payload
+        };
+        let mut sent = self.sent.lock().unwrap();
+        loop {
+            while sent.front().is_some_and(|s| {
+                s.echo
+                    .as_ref()
+                    .is_some_and(|(t, _)| t.elapsed() > ECHO_TIMEOUT)
+            }) {
+                sent.pop_front();
+            }
+            let echo = sent.iter().position(|s| {
+                s.echo
+                    .as_ref()
+                    .is_some_and(|(_, echo)| echo.time() == packet.time() && like(echo))
+            });
+            if let Some(i) = echo {
+                sent.remove(i);
+                return true;
+            }
+            if !sent.iter().any(|s| s.echo.is_none() && like(&s.packet)) {
+                return false;
+            }
+            let (guard, wait) = self.done.wait_timeout(sent, ECHO_TIMEOUT).unwrap();
+            if wait.timed_out() {
+                return false;
+            }
+            sent = guard;
+        }
+    }
+
+    fn stats(&self) -> ForwardStats {
+        ForwardStats {
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+            modified: self.modified.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimulatedConnection;
+
+    #[test]
+    fn parse() -> Result<()> {
+        assert_eq!(
+            Rule::new(IdMask::new(0x18FEF100, 0xFFFF00), Action::Drop),
+            "18FEF100/FFFF00:drop".parse()?
+        );
+        assert_eq!(
+            Rule::new(IdMask::exact(0x7E0), Action::Id(0x7E1)),
+            "7E0:id=7E1".parse()?
+        );
+        assert_eq!(Action::Source(0xFA), "0/0:sa=FA".parse::<Rule>()?.action);
+        assert_eq!(
+            Action::Data {
+                offset: 2,
+                bytes: vec![0xFF, 0x01]
+            },
+            "0/0:data@2=FF01".parse::<Rule>()?.action
+        );
+        assert!("18FEF100".parse::<Rule>().is_err());
+        assert!("18FEF100:boom".parse::<Rule>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn apply() {
+        let direction = Direction {
+            rules: vec![
+                "18FECA00/FFFF00:drop".parse().unwrap(),
+                "18FEF1F9:pass".parse().unwrap(),
+                "18EA0000/FF0000:da=00".parse().unwrap(),
+                "18EA0000/FF0000:sa=FA".parse().unwrap(),
+                "0/0:data@1=AABBCC".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert!(direction
+            .apply(Packet::new(0x18FECA00, &[1, 2, 3]))
+            .is_none());
+        let (p, modified) = direction.apply(Packet::new(0x18FEF1F9, &[1, 2])).unwrap();
+        assert!(!modified);
+        assert_eq!(p.payload, [1, 2]);
+        let (p, modified) = direction
+            .apply(Packet::new(0x18EAFFF9, &[0xEC, 0xFE]))
+            .unwrap();
+        assert!(modified);
+        assert_eq!(0x18EA00FA, p.id);
+        assert_eq!(p.payload, [0xEC, 0xAA]);
+        // broadcast has no destination address
+        let (p, _) = direction.apply(Packet::new(0x18FEF100, &[])).unwrap();
+        assert_eq!(0x18FEF100, p.id);
+    }
+
+    #[test]
+    fn echo() {
+        let direction = Direction::default();
+        let packet = Packet::new(0x18FEF100, &[1]);
+        let at = |ms| Packet::new_rx(0x18FEF100, &[1], Duration::from_millis(ms), 0);
+
+        let sequence = direction.sending(&packet);
+        direction.sent(sequence, Some(at(5)));
+        // the same frame from the bus is not the echo
+        assert!(!direction.is_echo(&at(6)));
+        assert!(direction.is_echo(&at(5)));
+        assert!(!direction.is_echo(&at(5)));
+
+        // a failed send is forgotten
+        let sequence = direction.sending(&packet);
+        direction.sent(sequence, None);
+        assert!(direction.sent.lock().unwrap().is_empty());
+
+        // the echo can arrive before the send returns
+        let sequence = direction.sending(&packet);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                direction.sent(sequence, Some(at(7)));
+            });
+            assert!(direction.is_echo(&at(7)));
+        });
+    }
+
+    #[test]
+    fn bridge() -> Result<()> {
+        let a = SimulatedConnection::silent();
+        let b = SimulatedConnection::silent();
+        let gateway = Gateway::new(
+            vec![
+                "18FECA00/FFFF00:drop".parse()?,
+                "18FEF100/FFFF00:sa=3D".parse()?,
+            ],
+            vec![],
+        );
+        let running = AtomicBool::new(true);
+        let (on_a, on_b) = thread::scope(|s| {
+            s.spawn(|| gateway.run(&a, &b, &running));
+            // let the gateway subscribe
+            thread::sleep(Duration::from_millis(50));
+            let on_a = a.iter_for(Duration::from_millis(300));
+            let on_b = b.iter_for(Duration::from_millis(300));
+            let sent = a
+                .send(&Packet::new(0x18FECA00, &[1]))
+                .and_then(|_| a.send(&Packet::new(0x18FEF100, &[2])))
+                .and_then(|_| b.send(&Packet::new(0x18EFFF00, &[3])));
+            // forwarded packets race the packets sent directly, so compare in payload order
+            let received = |i: Box<dyn Iterator<Item = Packet> + Send + Sync>| {
+                let mut received: Vec<_> = i.map(|p| (p.payload[0], p.id)).collect();
+                received.sort();
+                received
+            };
+            let received = (received(on_a), received(on_b));
+            running.store(false, Ordering::Relaxed);
+            sent.map(|_| received)
+        })?;
+        // the echoes of forwarded packets are not forwarded back
+        assert_eq!(vec![(2, 0x18FEF13D), (3, 0x18EFFF00)], on_b);
+        assert_eq!(
+            vec![(1, 0x18FECA00), (2, 0x18FEF100), (3, 0x18EFFF00)],
+            on_a
+        );
+        let stats = gateway.stats();
+        assert_eq!(
+            ForwardStats {
+                forwarded: 1,
+                modified: 1,
+                dropped: 1,
+                failed: 0
+            },
+            stats.a_to_b
+        );
+        assert_eq!(1, stats.b_to_a.forwarded);
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
-use std::{fmt::*, ops::Deref, str::FromStr, time::Duration};
+use std::{fmt::*, ops::Deref, str::FromStr};
 
 use anyhow::Result;
 
-use crate::packet::Packet;
+use crate::packet::{Packet, Timestamp};
 
 #[derive(Clone)]
 pub struct J1939Packet {
@@ -48,7 +48,7 @@ impl Deref for J1939Packet {
 
 impl J1939Packet {
     pub fn new_packet(
-        time: Option<Duration>,
+        time: Option<Timestamp>,
         channel: u32,
         priority: u8,
         pgn: u32,
@@ -128,6 +128,7 @@ impl Display for J1939Packet {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use std::time::Duration;
 
     #[test]
     fn test_j1939packet_display() {
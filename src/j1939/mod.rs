@@ -1,13 +1,14 @@
 use std::{collections::HashMap, time::Duration};
 
-use anyhow::{Context, Result};
 use clap_num::maybe_hex;
 
 pub mod j1939_packet;
 pub mod pgn;
 
+#[cfg(feature = "async")]
+use crate::async_connection::AsyncConnection;
 use crate::{
-    connection::Connection, j1939::j1939_packet::J1939Packet, packet::Packet, j1939::pgn::Pgn, CanContext
+    connection::{Connection, IdMask}, error::{Error, Result}, j1939::j1939_packet::J1939Packet, packet::Packet, j1939::pgn::Pgn, CanContext
 };
 use clap::Parser;
 use zerocopy::*;
@@ -62,7 +63,9 @@ impl J1939 {
                 println!("{s}");
                 Ok(())
             }
-            J1939::AddressClaim { sa } => todo!(),
+            J1939::AddressClaim { sa } => Err(Error::Unsupported(
+                "Address claim is not supported yet".into(),
+            )),
         }
     }
 
@@ -73,18 +76,22 @@ impl J1939 {
         sa: u8,
         da: u8,
         pgn: u32,
-    ) -> Result<Option<J1939Packet>, anyhow::Error> {
-        let iter = connection.iter_for(duration);
+    ) -> Result<Option<J1939Packet>> {
+        let mut response_id = pgn << 8 | (da as u32);
+        if pgn < 0xF000 {
+            response_id |= (sa as u32) << 8;
+        }
+        let mut filter = vec![IdMask::new(response_id, 0xFFFFFF)];
+        if transport_protocol {
+            filter.extend(TpReceiver::new(sa, false).masks());
+        }
+        let iter = connection.subscribe_for(filter.into(), duration);
         let packet = Packet::new(
             0x18EA0000 | ((da as u32) << 8) | (sa as u32),
             pgn.to_le_bytes()[0..3].into(),
         );
         connection.send(&packet)?;
 
-        let mut response_id = pgn << 8 | (da as u32);
-        if pgn < 0xF000 {
-            response_id |= (sa as u32) << 8;
-        }
         let predicate = |p: &J1939Packet| p.id() & 0xFFFFFF == response_id;
 
         let packet = if transport_protocol {
@@ -145,7 +152,12 @@ impl J1939 {
         fn into_j1939packet(p: Packet) -> J1939Packet {
             p.into()
         }
-        let mut cts_iter = connection.iter_for(J1939::T3).map(into_j1939packet);
+        let subscribe_cts = || {
+            connection
+                .subscribe_for(IdMask::new(rx_id, 0xFFFFFF).into(), J1939::T3)
+                .map(into_j1939packet)
+        };
+        let mut cts_iter = subscribe_cts();
         let control_id = 0x18EC0000 | ((packet.dest() as u32) << 8) | (packet.source() as u32);
         let data_id = 0x18EB0000 | ((packet.dest() as u32) << 8) | (packet.source() as u32);
         let rts = Packet::new(
@@ -163,51 +175,33 @@ impl J1939 {
         loop {
             let cts = cts_iter
                 .find(|p| p.id() & 0xFFFFFF == rx_id)
-                .context("CTS not received.")?;
-            if cts.payload[0] == 0x13 {
+                .ok_or_else(|| Error::Timeout(format!("CTS for PGN {pgn:04X}")))?;
+            match cts.payload[..] {
                 // end of message
-                break;
-            }
-            if {
-                let this = &cts;
-                &this.payload
-            }[0] == 0xFF
-            {
-                todo!();
-                //Err("Aborted")
-            }
-            let to_send = {
-                let this = &cts;
-                &this.payload
-            }[1];
-            let next = {
-                let this = &cts;
-                &this.payload
-            }[2];
-            for seq in next..(next + to_send) {
-                let start = (seq as usize - 1) * 7;
-                let end = Ord::min(
-                    start + 7,
-                    {
-                        let this = &packet;
-                        &this.payload
+                [0x13, ..] => break,
+                [0xFF, reason, ..] => return Err(Error::Aborted { pgn, reason }),
+                // sequence numbers from 1 to count
+                [0x11, to_send, next, ..]
+                    if next > 0 && next as usize + to_send as usize <= count as usize + 1 =>
+                {
+                    for seq in next as usize..next as usize + to_send as usize {
+                        let start = (seq - 1) * 7;
+                        let end = Ord::min(start + 7, packet.payload.len());
+                        let dt = Packet::new(
+                            data_id,
+                            &[&[seq as u8], &packet.payload[start..end]].concat(),
+                        );
+                        connection.send(&dt)?;
                     }
-                    .len(),
-                );
-                let dt = Packet::new(
-                    data_id,
-                    &[
-                        &[seq],
-                        &{
-                            let this = &packet;
-                            &this.payload
-                        }[start..end],
-                    ]
-                    .concat(),
-                );
-                connection.send(&dt)?;
+                }
+                _ => {
+                    return Err(Error::Protocol(format!(
+                        "Unexpected CTS {:X?} for PGN {pgn:04X}",
+                        &cts.payload[..]
+                    )))
+                }
             }
-            cts_iter = connection.iter_for(J1939::T3).map(into_j1939packet);
+            cts_iter = subscribe_cts();
         }
         Ok(())
     }
@@ -218,44 +212,74 @@ impl J1939 {
         passive: bool,
         iter: &'a mut dyn Iterator<Item = J1939Packet>,
     ) -> impl Iterator<Item = J1939Packet> + 'a {
-        let ds_control_p = 0xEC0000 | (addr as u32) << 8;
-        let ds_data_p = 0xEB0000 | (addr as u32) << 8;
-        let bam_control_p = 0xECFF00;
-        let bam_data_p = 0xEBFF00;
-
-        let mut bam: HashMap<u8, TPDescriptor> = HashMap::new();
-        let mut ds: HashMap<u8, TPDescriptor> = HashMap::new();
-
+        let mut tp = TpReceiver::new(addr, passive);
         iter.flat_map(move |p| {
-            let mut r = if p.id() & 0xFFFF00 == bam_control_p {
-                J1939::control(connection, &mut bam, true, &p)
-                    .expect("Unable to handle control message {p}");
-                Vec::new()
-            } else if p.id() & 0xFFFF00 == ds_control_p {
-                J1939::control(connection, &mut ds, passive, &p)
-                    .expect("Unable to handle control message {p}");
-                Vec::new()
-            } else if p.id() & 0xFFFF00 == bam_data_p {
-                J1939::data(connection, &mut bam, true, &p)
-                    .expect("Unable to handle data message {p}")
-            } else if p.id() & 0xFFFF00 == ds_data_p {
-                J1939::data(connection, &mut ds, false, &p)
-                    .expect("Unable to handle data message {p}")
-            } else {
-                Vec::new()
-            };
-
-            r.insert(0, p);
+            let mut replies = Vec::new();
+            let r = tp.receive(p, &mut replies);
+            replies.into_iter().for_each(|reply| {
+                // the sender times out the session
+                if let Err(e) = connection.send(&reply.into()) {
+                    eprintln!("Unable to send transport protocol reply: {e}");
+                }
+            });
             r.into_iter()
         })
     }
 
+    /// Async variant of [`J1939::request`].
+    #[cfg(feature = "async")]
+    pub async fn request_async(
+        connection: &dyn AsyncConnection,
+        duration: Duration,
+        transport_protocol: bool,
+        sa: u8,
+        da: u8,
+        pgn: u32,
+    ) -> Result<Option<J1939Packet>> {
+        use futures_util::StreamExt;
+
+        let mut stream = connection.stream_for(duration);
+        let packet = Packet::new(
+            0x18EA0000 | ((da as u32) << 8) | (sa as u32),
+            pgn.to_le_bytes()[0..3].into(),
+        );
+        connection.send(&packet).await?;
+
+        let mut response_id = pgn << 8 | (da as u32);
+        if pgn < 0xF000 {
+            response_id |= (sa as u32) << 8;
+        }
+        let predicate = |p: &J1939Packet| p.id() & 0xFFFFFF == response_id;
+
+        let mut tp = transport_protocol.then(|| TpReceiver::new(sa, false));
+        while let Some(p) = stream.next().await {
+            let received = match &mut tp {
+                Some(tp) => {
+                    let mut replies = Vec::new();
+                    let received = tp.receive(p.into(), &mut replies);
+                    for reply in replies {
+                        connection.send(&reply.into()).await?;
+                    }
+                    received
+                }
+                None => vec![p.into()],
+            };
+            if let Some(p) = received.into_iter().find(predicate) {
+                return Ok(Some(p));
+            }
+        }
+        Ok(None)
+    }
+
     fn control(
-        connection: &dyn Connection,
+        replies: &mut Vec<J1939Packet>,
         table: &mut HashMap<u8, TPDescriptor>,
         passive: bool,
         p: &J1939Packet,
-    ) -> Result<()> {
+    ) {
+        if p.payload.len() < 8 {
+            return;
+        }
         let command = {
             let this = &p;
             &this.payload
@@ -309,21 +333,23 @@ impl J1939 {
                     }
                     .as_bytes(),
                 );
-                connection.send(&cts.into())?;
+                replies.push(cts);
             }
         } else if command == 0xFF {
             // cancel
             table.remove(&p.source());
         }
-        Ok(())
     }
 
     fn data(
-        connection: &dyn Connection,
+        replies: &mut Vec<J1939Packet>,
         table: &mut HashMap<u8, TPDescriptor>,
         passive: bool,
         p: &J1939Packet,
-    ) -> Result<Vec<J1939Packet>> {
+    ) -> Vec<J1939Packet> {
+        if p.payload.is_empty() {
+            return Vec::new();
+        }
         let d = table.get_mut(&p.source());
         let r = match d {
             Some(d) => {
@@ -345,7 +371,7 @@ impl J1939 {
                     d.data.truncate(d.size as usize);
 
                     let packet = J1939Packet::new_packet(
-                        p.time(),
+                        p.timestamp(),
                         0,
                         p.priority(),
                         d.pgn,
@@ -370,7 +396,7 @@ impl J1939 {
                             }
                             .as_bytes(),
                         );
-                        connection.send(&eom.clone().into())?;
+                        replies.push(eom.clone());
 
                         vec![eom, packet]
                     } else {
@@ -386,7 +412,65 @@ impl J1939 {
         if !r.is_empty() {
             table.remove(&p.source());
         }
-        Ok(r)
+        r
+    }
+}
+
+/// Transport protocol sessions to and from one address, for [`J1939::receive_tp`] and [`J1939::request_async`].
+struct TpReceiver {
+    ds_control_p: u32,
+    ds_data_p: u32,
+    passive: bool,
+    bam: HashMap<u8, TPDescriptor>,
+    ds: HashMap<u8, TPDescriptor>,
+}
+
+impl TpReceiver {
+    const BAM_CONTROL_P: u32 = 0xECFF00;
+    const BAM_DATA_P: u32 = 0xEBFF00;
+
+    fn new(addr: u8, passive: bool) -> Self {
+        TpReceiver {
+            ds_control_p: 0xEC0000 | (addr as u32) << 8,
+            ds_data_p: 0xEB0000 | (addr as u32) << 8,
+            passive,
+            bam: HashMap::new(),
+            ds: HashMap::new(),
+        }
+    }
+
+    /// Transport protocol packets this receiver handles.
+    fn masks(&self) -> [IdMask; 4] {
+        [
+            Self::BAM_CONTROL_P,
+            self.ds_control_p,
+            Self::BAM_DATA_P,
+            self.ds_data_p,
+        ]
+        .map(|p| IdMask::new(p, 0xFFFF00))
+    }
+
+    /// Returns `p` followed by any completed messages.  CTS and EOM to send are added to `replies`.
+    fn receive(&mut self, p: J1939Packet, replies: &mut Vec<J1939Packet>) -> Vec<J1939Packet> {
+        let mut r = if !p.is_data() {
+            // remote, error and status frames pass through
+            Vec::new()
+        } else if p.id() & 0xFFFF00 == Self::BAM_CONTROL_P {
+            J1939::control(replies, &mut self.bam, true, &p);
+            Vec::new()
+        } else if p.id() & 0xFFFF00 == self.ds_control_p {
+            J1939::control(replies, &mut self.ds, self.passive, &p);
+            Vec::new()
+        } else if p.id() & 0xFFFF00 == Self::BAM_DATA_P {
+            J1939::data(replies, &mut self.bam, true, &p)
+        } else if p.id() & 0xFFFF00 == self.ds_data_p {
+            J1939::data(replies, &mut self.ds, false, &p)
+        } else {
+            Vec::new()
+        };
+
+        r.insert(0, p);
+        r
     }
 }
 
@@ -441,7 +525,7 @@ struct J1939_21TpBAM {
 mod tests {
     use std::thread;
 
-    use anyhow::Ok;
+    use anyhow::{Ok, Result};
 
     use crate::sim::SimulatedConnection;
 
@@ -466,6 +550,59 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn request_unpowered_ecu() -> Result<()> {
+        let connection = SimulatedConnection::silent();
+        for transport_protocol in [false, true] {
+            let start = std::time::Instant::now();
+            let rx = J1939::request(
+                &connection,
+                Duration::from_millis(100),
+                transport_protocol,
+                0xF9,
+                0,
+                0xFEEC,
+            )?;
+            assert!(rx.is_none());
+            assert!(start.elapsed() < Duration::from_millis(500));
+        }
+        Ok(())
+    }
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn request_async_unpowered_ecu() -> Result<()> {
+        let connection = SimulatedConnection::silent();
+        let rx = J1939::request_async(
+            &connection,
+            Duration::from_millis(100),
+            true,
+            0xF9,
+            0,
+            0xFEEC,
+        )
+        .await?;
+        assert!(rx.is_none());
+        Ok(())
+    }
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn request_async_ds() -> Result<()> {
+        use anyhow::Context;
+        let connection = SimulatedConnection::new(None)?;
+        let mut responder = connection.clone();
+        let mut requests = connection.iter_for(Duration::from_secs(2));
+        let payload = [&[0, 0, 0, 1], "Something".as_bytes()].concat();
+        let tx = J1939Packet::new(0x18D3F900, &payload);
+        thread::spawn(move || {
+            if requests.any(|p| p.id == 0x18EA00F9) {
+                let _ = J1939::send(&mut responder, &tx);
+            }
+        });
+        let rx = J1939::request_async(&connection, Duration::from_secs(2), true, 0xF9, 0, 0xD300)
+            .await?;
+        assert_eq!(payload, rx.context("No response")?.data());
+        Ok(())
+    }
+    #[test]
     pub fn send14_ds() -> Result<()> {
         let mut rx_connection = Box::new(SimulatedConnection::new(None)?);
         let mut tx_connection = rx_connection.clone();
@@ -1,19 +1,34 @@
-use std::time::{Duration, Instant, SystemTime};
-
-use anyhow::Result;
+use std::{
+    fs::File,
+    io::BufWriter,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Result};
 use clap::*;
 use clap_num::maybe_hex;
-use connection::Connection;
+use connection::{Connection, ConnectionFactory};
 use slcan::Slcan;
 
+#[cfg(feature = "async")]
+pub mod async_connection;
+pub mod bus_state;
 pub mod connection;
+pub mod error;
+pub mod formats;
+pub mod gateway;
 pub mod j1939;
+pub mod multiconnection;
 pub mod packet;
 pub mod pushbus;
+pub mod reconnect;
 pub mod sim;
 pub mod slcan;
+pub mod stats;
 pub mod uds;
 
+pub use error::Error;
 use j1939::J1939;
 use uds::Uds;
 
@@ -27,7 +42,15 @@ pub mod socketcanconnection;
 #[cfg(target_os = "linux")]
 use socketcanconnection::SocketCanConnection;
 
-use crate::{j1939::j1939_packet::J1939Packet, packet::Packet, sim::SimulatedConnection};
+use crate::{
+    formats::{convert::Convert, Format},
+    gateway::{Gateway, Rule},
+    j1939::j1939_packet::J1939Packet,
+    multiconnection::MultiConnection,
+    packet::{IdType, Packet},
+    reconnect::{Backoff, Reconnecting},
+    sim::SimulatedConnection,
+};
 
 /// Simple CAN tool for sending and receiving CAN packets over various adapters.
 /// This struct
@@ -36,7 +59,8 @@ use crate::{j1939::j1939_packet::J1939Packet, packet::Packet, sim::SimulatedConn
 #[command(version,about = "CAN tool", long_about = None)]
 pub struct CanCan {
     /// For a list of possible connections, "cancan list log".  Available connection strings will vary depending on the machine.
-    pub connection: String,
+    /// Join connections with '+' to use them as channels 0, 1, ...: "socketcan can0 + socketcan can1".
+    pub connection: Option<String>,
 
     #[arg(long="sa", short('s'), default_value = "0xF9",value_parser=maybe_hex::<u8>)]
     /// Adapter Address (used for packets send and transport protocol)
@@ -57,6 +81,10 @@ pub struct CanCan {
     #[arg(long, short('v'), default_value = "false")]
     pub verbose: bool,
 
+    #[arg(long)]
+    /// Connect again when the adapter is lost, such as an unplugged USB adapter or an interface going down
+    pub reconnect: bool,
+
     #[clap(subcommand)]
     command: CanCommand,
 }
@@ -75,22 +103,65 @@ pub struct CanContext {
 /// Subcommands for CAN operations.
 #[derive(Subcommand, Debug, Clone)]
 enum CanCommand {
-    /// Dump Vector ASC compatible log to stdout.
-    Log,
+    /// Dump log to stdout, including remote, error and status frames.
+    Log {
+        /// Log file format
+        #[arg(long, short('f'), value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Convert a log file to another format.  No connection is needed: "cancan convert in.blf out.asc".
+    Convert {
+        /// Input log file.  The format is detected from the file.
+        input: String,
+        /// Output log file, or "-" for stdout
+        output: String,
+        /// Output format.  Defaults to the output file extension.
+        #[arg(long, short('f'), value_enum)]
+        format: Option<Format>,
+        /// Only convert these channels, comma separated from 0
+        #[arg(long, short('c'), value_delimiter = ',')]
+        channel: Vec<u32>,
+        /// Only convert these IDs, comma separated (dec or 0xhex)
+        #[arg(long, short('i'), value_delimiter = ',', value_parser=maybe_hex::<u32>)]
+        id: Vec<u32>,
+        /// Seconds added to each timestamp.  May be negative.
+        #[arg(long, allow_hyphen_values = true, default_value_t = 0.0, value_parser = parse_shift)]
+        shift: f64,
+        /// Start the timestamps at the first converted packet
+        #[arg(long)]
+        rebase: bool,
+    },
     /// Used for testing.  Requires another instance to send or ping this source address.
     Server,
     /// Latency test. Ping [da] with as many requests as it will respond to.
     Ping,
     /// Bandwidth test.  Send as much data to [da] with as many requests as it will respond to.
     Bandwidth,
+    /// Print traffic, errors, drops, bus load and controller state every second.
+    Stats,
+    /// Forward packets between this connection (a) and another (b), printing the counts every second.
+    Bridge {
+        /// The other connection, like the main one: "socketcan can1"
+        to: String,
+        /// Rule for packets from a to b, checked in order: <id>[/<mask>]:<action>, in hex.  The action is drop, pass,
+        /// id=<id>, sa=<address>, da=<address> or data@<offset>=<bytes>
+        #[arg(long)]
+        a_to_b: Vec<Rule>,
+        /// Rule for packets from b to a
+        #[arg(long)]
+        b_to_a: Vec<Rule>,
+    },
     /// Send arbitrary CAN message
     Send {
-        /// ID 29 bit (dec or 0xhex)
+        /// ID 29 bit, or 11 bit with --standard (dec or 0xhex)
         #[arg( value_parser=maybe_hex::<u32>)]
         id: u32,
         /// Payload (dec or 0xhex u64)
         #[arg( value_parser=maybe_hex::<u64>)]
         payload: u64,
+        /// Use an 11 bit standard ID, such as 0x7E0 for OBD-II
+        #[arg(long)]
+        standard: bool,
     },
     /// Read the VIN.
     Vin,
@@ -122,8 +193,13 @@ pub enum ConnectionDescriptor {
     List {},
     /// Simulation - TODO
     Sim {
+        /// Log file to replay in a loop: ASC, candump, BLF, pcapng, TRC or MDF4.  The format is detected from the file
         //#[arg(long, short('f'))]
         file: Option<String>,
+
+        /// No other nodes on the bus, so only sent packets are received.  For testing timeouts
+        #[arg(long)]
+        silent: bool,
     },
     /// SAE J2534 - TODO
     J2534 {},
@@ -137,6 +213,10 @@ pub enum ConnectionDescriptor {
         /// speed: '500000', '250000'
         #[arg(long, short('s'), default_value = "500000")]
         speed: u64,
+
+        /// Set the interface listen only, where it can be set, and don't send
+        #[arg(long, short('l'))]
+        listen_only: bool,
     },
     /// SLCAN interface.
     SLCAN {
@@ -148,6 +228,10 @@ pub enum ConnectionDescriptor {
 
         // CAN bus speed expressed in kbaud - 10, 20, 50, 100, 125, 250, 500, 800, 1000
         speed: u32,
+
+        /// Open the channel listen only ('L'), so the adapter doesn't acknowledge frames, and don't send
+        #[arg(long, short('l'))]
+        listen_only: bool,
     },
     /// TMC RP1210 interface for Windows.
     #[cfg(windows)]
@@ -167,29 +251,44 @@ pub enum ConnectionDescriptor {
 
         #[arg(long, short('a'), default_value = "0xF9",value_parser=maybe_hex::<u8>)]
         address: u8,
+
+        /// Receive only: don't claim the address or send
+        #[arg(long, short('l'))]
+        listen_only: bool,
     },
 }
 
 /// Entry point for connecting to a CAN bus based on the ConnectionDescriptor.
 impl ConnectionDescriptor {
     /// The call
-    pub fn connect(&self) -> Result<Box<dyn Connection>> {
+    pub fn connect(&self) -> error::Result<Box<dyn Connection>> {
         let connection = self;
         match &connection {
             ConnectionDescriptor::List {} => list_all(),
-            ConnectionDescriptor::Sim { file } => {
+            ConnectionDescriptor::Sim { silent: true, .. } => {
+                Ok(Box::new(SimulatedConnection::silent()))
+            }
+            ConnectionDescriptor::Sim { file, .. } => {
                 Ok(Box::new(SimulatedConnection::new(file.clone())?))
             }
-            ConnectionDescriptor::J2534 {} => todo!(),
-            #[cfg(target_os = "linux")]
-            ConnectionDescriptor::SocketCan { dev, speed } => {
-                Ok(Box::new(SocketCanConnection::new(dev, *speed)?) as Box<dyn Connection>)
+            ConnectionDescriptor::J2534 {} => {
+                Err(Error::Unsupported("J2534 is not supported yet".into()))
             }
+            #[cfg(target_os = "linux")]
+            ConnectionDescriptor::SocketCan {
+                dev,
+                speed,
+                listen_only,
+            } => Ok(
+                Box::new(SocketCanConnection::new(dev, *speed, *listen_only)?)
+                    as Box<dyn Connection>,
+            ),
             ConnectionDescriptor::SLCAN {
                 verbose,
                 port,
                 speed,
-            } => Ok(Box::new(Slcan::new(*verbose, port, *speed)?)),
+                listen_only,
+            } => Ok(Box::new(Slcan::new(*verbose, port, *speed, *listen_only)?)),
             #[cfg(windows)]
             ConnectionDescriptor::RP1210 {
                 id,
@@ -197,6 +296,7 @@ impl ConnectionDescriptor {
                 connection_string,
                 app_packetize,
                 address: source_address,
+                listen_only,
             } => {
                 {
                     let mut cs = rp1210::CONNECTION_STRING.write().unwrap();
@@ -204,12 +304,55 @@ impl ConnectionDescriptor {
                     let mut ap = rp1210::APP_PACKETIZATION.write().unwrap();
                     *ap = *app_packetize;
                 }
-                Ok(Box::new(Rp1210::new(id, *device, *source_address)?) as Box<dyn Connection>)
+                Ok(
+                    Box::new(Rp1210::new(id, *device, *source_address, *listen_only)?)
+                        as Box<dyn Connection>,
+                )
             }
         }
     }
 }
 
+/// A connection string, such as "socketcan can0", to create connections from.
+struct CommandLine(String);
+
+impl ConnectionFactory for CommandLine {
+    fn create(&self) -> error::Result<Box<dyn Connection>> {
+        ConnectionDescriptor::parse_from(std::iter::once("").chain(self.0.split(" "))).connect()
+    }
+
+    fn command_line(&self) -> String {
+        self.0.clone()
+    }
+
+    fn name(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// Connect to one connection, or to several joined with '+' as the channels of a [`MultiConnection`].  With
+/// `reconnect`, each is created again when it is lost.
+fn connect(descriptors: &str, reconnect: bool) -> Result<Box<dyn Connection>> {
+    let mut connections = descriptors
+        .split('+')
+        .map(|d| {
+            let command_line = CommandLine(d.trim().to_string());
+            if reconnect {
+                Ok(Box::new(Reconnecting::new(
+                    Box::new(command_line),
+                    Backoff::default(),
+                )?) as Box<dyn Connection>)
+            } else {
+                command_line.create()
+            }
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+    if connections.len() == 1 {
+        return Ok(connections.remove(0));
+    }
+    Ok(Box::new(MultiConnection::new(connections)?))
+}
+
 /// List all available connection types and exit.
 pub fn list_all() -> ! {
     for pd in connection::enumerate_connections().unwrap() {
@@ -228,9 +371,30 @@ pub fn list_all() -> ! {
 pub fn main() -> Result<()> {
     let can_can = CanCan::parse();
 
-    let connection =
-        ConnectionDescriptor::parse_from(std::iter::once("").chain(can_can.connection.split(" ")))
-            .connect()?;
+    if let CanCommand::Convert {
+        input,
+        output,
+        format,
+        channel,
+        id,
+        shift,
+        rebase,
+    } = can_can.command
+    {
+        let convert = Convert {
+            channels: channel,
+            ids: id,
+            shift,
+            rebase,
+            ..Default::default()
+        };
+        return convert_file(&input, &output, format, &convert);
+    }
+
+    let Some(connection) = &can_can.connection else {
+        bail!("Missing connection.  For a list of possible connections, \"cancan list log\".");
+    };
+    let connection = connect(connection, can_can.reconnect)?;
 
     let cli = &mut CanContext {
         can_can,
@@ -243,18 +407,34 @@ pub fn main() -> Result<()> {
         CanCommand::Ping => {
             ping(cli)?;
         }
-        CanCommand::Send { id, payload } => {
-            send(cli, id, &payload.to_be_bytes())?;
+        CanCommand::Send {
+            id,
+            payload,
+            standard,
+        } => {
+            let id_type = if standard {
+                IdType::Standard
+            } else {
+                IdType::Extended
+            };
+            send(cli, id, id_type, &payload.to_be_bytes())?;
         }
         CanCommand::Bandwidth => {
             bandwidth(cli)?;
         }
+        CanCommand::Stats => {
+            stats(cli)?;
+        }
+        CanCommand::Bridge { to, a_to_b, b_to_a } => {
+            bridge(cli, &to, a_to_b, b_to_a)?;
+        }
         CanCommand::Vin => {
             vin(cli)?;
         }
-        CanCommand::Log => {
-            log(cli)?;
+        CanCommand::Log { format } => {
+            log(cli, format)?;
         }
+        CanCommand::Convert { .. } => unreachable!("converted without a connection"),
         CanCommand::Uds { uds } => {
             uds.execute_and_report(cli)?;
         }
@@ -268,9 +448,45 @@ pub fn main() -> Result<()> {
     Ok(())
 }
 
+/// Convert `input` to `output`, which may be "-" for stdout.
+fn convert_file(
+    input: &str,
+    output: &str,
+    format: Option<Format>,
+    convert: &Convert,
+) -> Result<()> {
+    let format = format
+        .or_else(|| Format::from_path(output))
+        .ok_or_else(|| anyhow!("Unknown format for {output}.  Use --format."))?;
+    let mut reader = formats::open(input)?;
+    let converted = if output == "-" {
+        convert.run(reader.as_mut(), format, std::io::stdout().lock())?
+    } else {
+        let writer = BufWriter::new(File::create(output)?);
+        convert.run(reader.as_mut(), format, writer)?
+    };
+    eprintln!(
+        "converted {} packets from {input} to {output}",
+        converted.written
+    );
+    if converted.skipped > 0 {
+        eprintln!("skipped {} bad records", converted.skipped);
+    }
+    Ok(())
+}
+
+/// Seconds for `convert --shift`, which must be a finite number.
+fn parse_shift(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() => Ok(seconds),
+        Ok(seconds) => Err(format!("{seconds} is not a number of seconds")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 /// Send an arbitrary CAN packet.
-fn send(can_can: &mut CanContext, id: u32, payload: &[u8]) -> Result<()> {
-    let packet = Packet::new(id, payload);
+fn send(can_can: &mut CanContext, id: u32, id_type: IdType, payload: &[u8]) -> Result<()> {
+    let packet = Packet::new(id, payload).with_id_type(id_type);
     can_can.connection.send(&packet)?;
     Ok(())
 }
@@ -340,6 +556,57 @@ fn ping(cli: &mut CanContext) -> Result<()> {
     }
 }
 
+/// Print per-second summaries of the connection statistics.
+fn stats(can_can: &mut CanContext) -> Result<()> {
+    let connection = can_can.connection.as_ref();
+    let unsupported = || anyhow!("Statistics are not supported by this connection");
+    let mut last = connection.stats().ok_or_else(unsupported)?;
+    let mut last_time = Instant::now();
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let now = connection.stats().ok_or_else(unsupported)?;
+        let now_time = Instant::now();
+        let elapsed = now_time - last_time;
+        let delta = now.since(&last);
+        let rate = |count: u64| count as f64 / elapsed.as_secs_f64();
+        let load = delta
+            .load(elapsed)
+            .map_or("-".to_string(), |load| format!("{:.1}%", 100.0 * load));
+        let state = connection
+            .bus_state()
+            .map_or(String::new(), |state| format!("  state {state}"));
+        println!(
+            "rx {:.0} frames/s {:.0} B/s  tx {:.0} frames/s {:.0} B/s  errors {}  dropped {}  load {load}{state}",
+            rate(delta.rx_frames),
+            rate(delta.rx_bytes),
+            rate(delta.tx_frames),
+            rate(delta.tx_bytes),
+            delta.errors,
+            delta.dropped,
+        );
+        last = now;
+        last_time = now_time;
+    }
+}
+
+fn bridge(can_can: &mut CanContext, to: &str, a_to_b: Vec<Rule>, b_to_a: Vec<Rule>) -> Result<()> {
+    let other = connect(to, can_can.can_can.reconnect)?;
+    let gateway = Gateway::new(a_to_b, b_to_a);
+    let running = AtomicBool::new(true);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            gateway.run(can_can.connection.as_ref(), other.as_ref(), &running);
+            running.store(false, Ordering::Relaxed);
+        });
+        while running.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_secs(1));
+            let stats = gateway.stats();
+            println!("a->b {}  b->a {}", stats.a_to_b, stats.b_to_a);
+        }
+    });
+    Ok(())
+}
+
 /// Server for bandwidth and ping tests.
 fn server(cli: &mut CanContext) -> Result<()> {
     let sa = cli.can_can.source_address;
@@ -457,17 +724,18 @@ fn vin(can_can: &mut CanContext) -> Result<()> {
     Ok(())
 }
 
-/// Dump Vector ASC compatible log to stdout.
-fn log(can_can: &mut CanContext) -> Result<()> {
+/// Dump log to stdout.
+fn log(can_can: &mut CanContext, format: Format) -> Result<()> {
     let connection = can_can.connection.as_mut();
     let mut iter = connection.iter().flatten().map(|p| p.into());
     let j1939_tp = can_can.can_can.j1939_tp;
     eprintln!("\n\nlog everything for the next 30 days tp:{j1939_tp}");
+    let mut writer = format.writer(std::io::stdout().lock(), SystemTime::now())?;
     if j1939_tp {
         J1939::receive_tp(connection, can_can.can_can.source_address, false, &mut iter)
-            .for_each(|p| println!("{p}"));
+            .try_for_each(|p| writer.write(&p))?;
     } else {
-        iter.for_each(|p| println!("{p}"));
+        iter.try_for_each(|p: J1939Packet| writer.write(&p))?;
     }
-    Ok(())
+    writer.finish()
 }
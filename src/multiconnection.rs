@@ -0,0 +1,321 @@
+//! Several connections used as the channels of one.
+use std::{
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::BinaryHeap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::Builder,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    connection::{Clock, Connection, Filter, IdMask},
+    error::{Error, Result},
+    packet::Packet,
+    pushbus::PushBus,
+    stats::Stats,
+};
+
+/// How long received packets are held so packets from slower adapters can be merged in front of them.  Packets later
+/// than this are passed on as they arrive.
+const REORDER: Duration = Duration::from_millis(20);
+/// Most packets taken from one connection before the others are polled.
+const BATCH: usize = 64;
+
+/// Combines connections, such as two SocketCAN interfaces and an SLCAN dongle, into one with a channel per
+/// connection, numbered from 0 in the order given.  Received packets are merged in [`Timestamp`](crate::packet::Timestamp)
+/// order, rebased to the clock of the `MultiConnection`.  [`Connection::send`] uses [`Packet::send_channel`].
+pub struct MultiConnection {
+    connections: Vec<Box<dyn Connection>>,
+    bus: PushBus<Packet>,
+    running: Arc<AtomicBool>,
+    clock: Clock,
+}
+
+impl MultiConnection {
+    pub fn new(connections: Vec<Box<dyn Connection>>) -> Result<MultiConnection> {
+        if connections.is_empty() {
+            return Err(Error::InvalidArgument(
+                "MultiConnection needs at least one connection".into(),
+            ));
+        }
+        let multi = MultiConnection {
+            connections,
+            bus: PushBus::new("multi").with_gap(Packet::new_gap),
+            running: Arc::new(AtomicBool::new(true)),
+            clock: Clock::new(),
+        };
+        let iters = multi
+            .connections
+            .iter()
+            .enumerate()
+            .map(|(channel, c)| (channel as u32, c.iter()))
+            .collect();
+        let mut bus = multi.bus.clone();
+        let running = multi.running.clone();
+        let clock = multi.clock;
+        Builder::new()
+            .name("multi connection".into())
+            .spawn(move || {
+                run(iters, &bus, clock, &running);
+                bus.close();
+            })?;
+        Ok(multi)
+    }
+
+    fn connection(&self, channel: u32) -> Result<&dyn Connection> {
+        self.connections
+            .get(channel as usize)
+            .map(|c| c.as_ref())
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "No channel {channel}, there are {} connections",
+                    self.connections.len()
+                ))
+            })
+    }
+
+    /// A packet of `channel`, on the clock of this connection.
+    fn adopt(&self, packet: Packet, channel: u32) -> Packet {
+        adopt(packet, channel, self.clock)
+    }
+}
+
+fn adopt(packet: Packet, channel: u32, clock: Clock) -> Packet {
+    let packet = packet.with_channel(channel);
+    match packet.timestamp() {
+        Some(time) => packet.with_timestamp(time.rebase(clock.anchor())),
+        None => packet,
+    }
+}
+
+type Members = Vec<(u32, Box<dyn Iterator<Item = Option<Packet>> + Send + Sync>)>;
+
+/// Polls each connection in turn and passes on the packets in time order once they are [`REORDER`] old.
+fn run(mut iters: Members, bus: &PushBus<Packet>, clock: Clock, running: &AtomicBool) {
+    let mut merge = Merge::default();
+    while running.load(Ordering::Relaxed) && !(iters.is_empty() && merge.is_empty()) {
+        iters.retain_mut(|(channel, iter)| {
+            for _ in 0..BATCH {
+                match iter.next() {
+                    Some(Some(p)) => merge.add(adopt(p, *channel, clock)),
+                    Some(None) => break,
+                    // closed
+                    None => return false,
+                }
+            }
+            true
+        });
+        let ready = merge.ready(clock.now().elapsed, Instant::now());
+        if ready.is_empty() {
+            bus.push(None);
+        }
+        ready.into_iter().for_each(|p| bus.push(Some(p)));
+    }
+}
+
+/// Received packets waiting to be passed on in time order.
+#[derive(Default)]
+struct Merge {
+    pending: BinaryHeap<Reverse<Pending>>,
+    count: u64,
+}
+
+struct Pending {
+    time: Duration,
+    /// Keeps packets with the same time in the order they arrived
+    sequence: u64,
+    arrived: Instant,
+    packet: Packet,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.time, self.sequence).cmp(&(other.time, other.sequence))
+    }
+}
+
+impl Merge {
+    fn add(&mut self, packet: Packet) {
+        self.count += 1;
+        self.pending.push(Reverse(Pending {
+            time: packet.time().unwrap_or_default(),
+            sequence: self.count,
+            arrived: Instant::now(),
+            packet,
+        }));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Packets older than [`REORDER`] at time `now`, or that arrived before `arrived_by`, in time order.
+    fn ready(&mut self, now: Duration, arrived_by: Instant) -> Vec<Packet> {
+        let mut ready = Vec::new();
+        while let Some(Reverse(first)) = self.pending.peek() {
+            if first.time + REORDER > now && first.arrived + REORDER > arrived_by {
+                break;
+            }
+            let Reverse(first) = self.pending.pop().unwrap();
+            ready.push(first.packet);
+        }
+        ready
+    }
+}
+
+impl Connection for MultiConnection {
+    /// Send on the connection of the packet's channel.  The echo has the channel and is on the clock of this
+    /// connection.
+    fn send(&self, packet: &Packet) -> Result<Packet> {
+        let channel = packet.send_channel;
+        let echo = self.connection(channel)?.send(&Packet {
+            send_channel: 0,
+            ..packet.clone()
+        })?;
+        Ok(self.adopt(echo, channel))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        self.bus.iter()
+    }
+
+    fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus.iter_until(end)
+    }
+
+    fn subscribe(&self, filter: Filter) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        Box::new(self.bus.subscribe_filtered(move |p| filter.matches(p)))
+    }
+
+    fn subscribe_until(
+        &self,
+        filter: Filter,
+        end: Instant,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus
+            .subscribe_filtered(move |p| filter.matches(p))
+            .until(end)
+    }
+
+    /// The same filters on every connection.
+    fn set_filters(&self, filters: &[IdMask]) -> Result<()> {
+        self.connections
+            .iter()
+            .try_for_each(|c| c.set_filters(filters))
+    }
+
+    /// The counts of all connections, with the sum of their bitrates if all of them know it.  `None` if a
+    /// connection has no stats.
+    fn stats(&self) -> Option<Stats> {
+        let mut total = Stats {
+            dropped: self.bus.dropped(),
+            bitrate: Some(0),
+            ..Default::default()
+        };
+        for connection in &self.connections {
+            let stats = connection.stats()?;
+            total = Stats {
+                bitrate: total.bitrate.zip(stats.bitrate).map(|(a, b)| a + b),
+                ..total.plus(&stats)
+            };
+        }
+        Some(total)
+    }
+}
+
+impl Drop for MultiConnection {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.bus.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimulatedConnection;
+
+    fn silent() -> Box<dyn Connection> {
+        Box::new(SimulatedConnection::silent())
+    }
+
+    #[test]
+    fn channels() -> Result<()> {
+        let multi = MultiConnection::new(vec![silent(), silent()])?;
+        let iter = multi.iter_for(Duration::from_millis(500));
+        let echo = multi.send(&Packet::new(0x18FEF1F9, &[1]).with_channel(1))?;
+        assert_eq!(Some(1), echo.channel());
+        assert_eq!(Some(multi.clock.anchor()), echo.timestamp().unwrap().anchor);
+        multi.send(&Packet::new(0x18FEF1F9, &[0]))?;
+        assert!(multi
+            .send(&Packet::new(0x18FEF1F9, &[2]).with_channel(2))
+            .is_err());
+
+        let received: Vec<_> = iter.map(|p| (p.channel(), p.payload[0])).collect();
+        assert_eq!(vec![(Some(1), 1), (Some(0), 0)], received);
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<()> {
+        let multi = MultiConnection::new(vec![silent(), silent()])?;
+        multi.send(&Packet::new(0x18FEF1F9, &[1, 2]).with_channel(0))?;
+        multi.send(&Packet::new(0x18FEF1F9, &[3]).with_channel(1))?;
+        let stats = multi.stats().unwrap();
+        assert_eq!((2, 3), (stats.tx_frames, stats.tx_bytes));
+        let one = SimulatedConnection::silent()
+            .stats()
+            .unwrap()
+            .bitrate
+            .unwrap();
+        assert_eq!(Some(2 * one), stats.bitrate);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_in_time_order() {
+        let clock = Clock::new();
+        let at = |ms: u64, channel: u32| {
+            Packet::new_rx(
+                0x100,
+                &[ms as u8],
+                clock.now().elapsed + Duration::from_millis(ms),
+                channel,
+            )
+        };
+        let mut merge = Merge::default();
+        merge.add(at(5, 0));
+        merge.add(at(3, 1));
+        merge.add(at(5, 1));
+        merge.add(at(40, 0));
+        let now = clock.now().elapsed + Duration::from_millis(30);
+        let early = Instant::now();
+        let ready: Vec<_> = merge
+            .ready(now, early)
+            .iter()
+            .map(|p| (p.payload[0], p.channel().unwrap()))
+            .collect();
+        assert_eq!(vec![(3, 1), (5, 0), (5, 1)], ready);
+        assert!(!merge.is_empty());
+        // packets from a clock that runs ahead are passed on once they have waited
+        assert_eq!(1, merge.ready(now, early + REORDER).len());
+        assert!(merge.is_empty());
+    }
+}
@@ -1,117 +1,410 @@
-use std::{fmt::*, str::FromStr, time::Duration};
+use std::{
+    fmt::*,
+    ops::Deref,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Result;
 
+use crate::{error::Error, formats::asc};
+
+/// Largest payload of a classic CAN 2.0 frame.
+pub const CAN_MAX_LEN: usize = 8;
+/// Largest payload of a CAN FD frame.
+pub const CANFD_MAX_LEN: usize = 64;
+
+/// Payload lengths for the CAN FD DLC codes 9 through 15.
+const CANFD_LENGTHS: [usize; 7] = [12, 16, 20, 24, 32, 48, 64];
+
 /// A CAN packet.
 #[derive(Debug, Clone)]
 pub struct Packet {
     pub id: u32,
-    pub payload: Vec<u8>,
+    pub payload: Payload,
     pub state: PacketState,
+    /// `None` for classic CAN 2.0 frames.
+    pub fd: Option<FdFlags>,
+    pub id_type: IdType,
+    /// Channel to send on, for a connection with several, such as a
+    /// [`MultiConnection`](crate::multiconnection::MultiConnection).  Set with [`Packet::with_channel`].
+    pub send_channel: u32,
+}
+
+/// 11 bit standard or 29 bit extended identifier.  J1939 uses extended identifiers, so that is the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdType {
+    Standard,
+    #[default]
+    Extended,
+}
+
+/// Flags carried by CAN FD frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FdFlags {
+    /// Bit rate switch. The data phase was sent at the data bitrate.
+    pub brs: bool,
+    /// Error state indicator. The transmitter was error passive.
+    pub esi: bool,
+}
+
+/// Frame data.  Up to [`CANFD_MAX_LEN`] bytes are stored inline, so received frames and the copies made for each
+/// [`PushBus`](crate::pushbus::PushBus) subscriber do not allocate.  Longer payloads, such as reassembled J1939
+/// transport protocol messages, share one immutable buffer.
+#[derive(Clone)]
+pub struct Payload(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline { len: u8, data: [u8; CANFD_MAX_LEN] },
+    Shared(Arc<[u8]>),
+}
+
+impl Payload {
+    pub fn new(data: &[u8]) -> Self {
+        if data.len() <= CANFD_MAX_LEN {
+            let mut inline = [0; CANFD_MAX_LEN];
+            inline[..data.len()].copy_from_slice(data);
+            Payload(Repr::Inline {
+                len: data.len() as u8,
+                data: inline,
+            })
+        } else {
+            Payload(Repr::Shared(data.into()))
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Inline { len, data } => &data[..*len as usize],
+            Repr::Shared(data) => data,
+        }
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::new(&[])
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Debug for Payload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Debug::fmt(self.as_slice(), f)
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Payload {}
+
+impl PartialEq<[u8]> for Payload {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl PartialEq<Vec<u8>> for Payload {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl PartialEq<Payload> for Vec<u8> {
+    fn eq(&self, other: &Payload) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for Payload {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl From<&[u8]> for Payload {
+    fn from(data: &[u8]) -> Self {
+        Payload::new(data)
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Payload {
+    fn from(data: [u8; N]) -> Self {
+        Payload::new(&data)
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Self {
+        Payload::new(&data)
+    }
+}
+
+/// Payload length for a DLC code.  Codes above 8 are only meaningful for CAN FD.
+pub fn dlc_to_len(dlc: u8) -> usize {
+    match dlc {
+        0..=8 => dlc as usize,
+        9..=15 => CANFD_LENGTHS[dlc as usize - 9],
+        _ => CANFD_MAX_LEN,
+    }
+}
+
+/// Smallest DLC code that can hold `len` bytes.
+pub fn len_to_dlc(len: usize) -> u8 {
+    if len <= CAN_MAX_LEN {
+        len as u8
+    } else {
+        9 + CANFD_LENGTHS
+            .iter()
+            .take_while(|l| **l < len)
+            .count()
+            .min(6) as u8
+    }
 }
 
+/// Parses one line of a Vector ASC log. See [`crate::formats::asc`] for whole files.
 impl FromStr for Packet {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let mut parts = s.split_whitespace();
-        let time = parts
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("Missing time"))?;
-        let channel = parts.next().ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing channel")
-        })?;
-        let id = parts
-            .next()
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing id"))?;
-        let xmit = parts
-            .next()
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing xmit"))?;
-        let base = parts
-            .next()
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing base"))?;
-        if base != "d" {
-            return Err(
-                std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing base").into(),
-            );
+        asc::parse_line(s, asc::Base::Hex)
+    }
+}
+
+/// Where a [`Timestamp`] was taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeSource {
+    /// The adapter or NIC clock.
+    Hardware,
+    /// The operating system receive time, such as SocketCAN `SO_TIMESTAMPNS`.
+    Kernel,
+    /// Read by this process when the frame was handled, or unknown, such as a log file.
+    #[default]
+    Host,
+}
+
+/// When a packet was seen on the bus.
+///
+/// Every [`Connection`](crate::connection::Connection) follows the same contract: `elapsed` is monotonic from the
+/// clock of the connection, and `anchor` is the wall clock time when that clock read zero.  Packets from different
+/// connections can be merged by their [`Timestamp::wall`] times.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub elapsed: Duration,
+    /// `None` when the wall clock time is unknown, such as a log file without a start time.
+    pub anchor: Option<SystemTime>,
+    pub source: TimeSource,
+}
+
+impl Timestamp {
+    pub fn new(elapsed: Duration, anchor: SystemTime, source: TimeSource) -> Self {
+        Timestamp {
+            elapsed,
+            anchor: Some(anchor),
+            source,
+        }
+    }
+
+    /// Wall clock time of the packet.
+    pub fn wall(&self) -> Option<SystemTime> {
+        self.anchor.map(|a| a + self.elapsed)
+    }
+
+    /// The same instant, measured from `anchor`.  Times before `anchor` are clamped to it.
+    pub fn rebase(self, anchor: SystemTime) -> Self {
+        let elapsed = match self.wall() {
+            Some(wall) => wall.duration_since(anchor).unwrap_or_default(),
+            None => self.elapsed,
+        };
+        Timestamp {
+            elapsed,
+            anchor: Some(anchor),
+            ..self
+        }
+    }
+}
+
+/// A host time without a wall clock anchor.
+impl From<Duration> for Timestamp {
+    fn from(elapsed: Duration) -> Self {
+        Timestamp {
+            elapsed,
+            ..Default::default()
         }
-        let len = parts.next().ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing length")
-        })?;
-        let payload = parts.collect::<Vec<&str>>().join(" ");
-        let time = time.parse::<f64>().map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid time: {e}"),
-            )
-        })?;
-        let channel = channel.parse::<u32>().map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid channel: {e}"),
-            )
-        })?;
-        let id = u32::from_str_radix(id.strip_suffix('x').unwrap(), 16).map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid id: {e}"))
-        })?;
-        let len = len.parse::<usize>().map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid length: {e} {len:?}"),
-            )
-        })?;
-        if payload.len() != len * 2 + (len - 1) {
-            return Err(anyhow::anyhow!(
-                "Payload length does not match length field: {} != {}",
-                payload.len(),
-                len * 2 + (len - 1)
-            ));
-        }
-        let payload_bytes = payload
-            .split_whitespace()
-            .map(|b| {
-                u8::from_str_radix(b, 16).map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Invalid payload byte: {e}"),
-                    )
-                })
-            })
-            .collect::<Result<Vec<u8>, std::io::Error>>()?;
-        let state = match xmit {
-            "Tx" => Ok(PacketState::TX),
-            "Rx" => Ok(PacketState::RX {
-                time: Duration::from_secs_f64(time),
-                channel,
-            }),
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid xmit value: {xmit}"),
-            )),
-        }?;
-        Ok(Packet {
-            id,
-            payload: payload_bytes,
-            state,
-        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum PacketState {
     TX,
-    RX { time: Duration, channel: u32 },
+    RX {
+        time: Timestamp,
+        channel: u32,
+    },
+    /// Transmitted by this adapter and seen on the bus, such as a Vector ASC `Tx` line.
+    Echo {
+        time: Timestamp,
+        channel: u32,
+    },
+    /// Remote transmission request.  The payload is empty, `dlc` is the requested length.
+    Remote {
+        time: Timestamp,
+        channel: u32,
+        dlc: u8,
+    },
+    /// Error frame.  The payload holds the adapter specific error data, if any.
+    Error {
+        time: Timestamp,
+        channel: u32,
+        errors: ErrorClasses,
+    },
+    /// Controller status report, such as the slcan `F` reply.
+    Status {
+        time: Timestamp,
+        channel: u32,
+        errors: ErrorClasses,
+    },
+}
+
+/// Error conditions decoded from error and status frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    TxTimeout,
+    ArbitrationLost,
+    /// Controller problem that is not otherwise classified.
+    Controller,
+    /// Receive or transmit buffer overflow.
+    Overrun,
+    ErrorWarning,
+    ErrorPassive,
+    BusOff,
+    /// Transmitted frame was not acknowledged.
+    Ack,
+    /// Bit, stuff, form or CRC violation.
+    Protocol,
+    Transceiver,
+    BusError,
+    Restarted,
+    /// Packets were dropped by this library because a subscriber fell behind, not by the controller.
+    Dropped,
+    /// The adapter was lost, reported by [`Reconnecting`](crate::reconnect::Reconnecting).  A `Restarted` status
+    /// follows when it is connected again.
+    Disconnected,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 14] = [
+        ErrorClass::TxTimeout,
+        ErrorClass::ArbitrationLost,
+        ErrorClass::Controller,
+        ErrorClass::Overrun,
+        ErrorClass::ErrorWarning,
+        ErrorClass::ErrorPassive,
+        ErrorClass::BusOff,
+        ErrorClass::Ack,
+        ErrorClass::Protocol,
+        ErrorClass::Transceiver,
+        ErrorClass::BusError,
+        ErrorClass::Restarted,
+        ErrorClass::Dropped,
+        ErrorClass::Disconnected,
+    ];
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+impl Display for ErrorClass {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ErrorClass::ALL
+            .into_iter()
+            .find(|c| c.to_string() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown error class: {s}"))
+    }
+}
+
+/// Set of [`ErrorClass`].  `Copy` so that error packets are as cheap to pass around as data packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorClasses(u16);
+
+impl ErrorClasses {
+    pub fn insert(&mut self, class: ErrorClass) {
+        self.0 |= class.bit();
+    }
+    pub fn contains(&self, class: ErrorClass) -> bool {
+        self.0 & class.bit() != 0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+    pub fn iter(&self) -> impl Iterator<Item = ErrorClass> + '_ {
+        ErrorClass::ALL.into_iter().filter(|c| self.contains(*c))
+    }
+}
+
+impl FromIterator<ErrorClass> for ErrorClasses {
+    fn from_iter<I: IntoIterator<Item = ErrorClass>>(iter: I) -> Self {
+        let mut errors = ErrorClasses::default();
+        iter.into_iter().for_each(|c| errors.insert(c));
+        errors
+    }
+}
+
+/// Space separated class names.
+impl Display for ErrorClasses {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        let names: Vec<String> = self.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", names.join(" "))
+    }
 }
 
 /// For now, try to copy the Vector .ASC format to keep the engineering community happy.
 impl Display for Packet {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        if self.is_fd() || !self.is_data() {
+            let time = format!(
+                "{:12.4}",
+                self.time().map(|d| d.as_secs_f64()).unwrap_or_default()
+            );
+            return write!(
+                f,
+                "{}",
+                asc::format_line(self, &time, self.channel().unwrap_or_default())
+            );
+        }
         write!(
             f,
-            "{:12.4} {} {:08X} [{}] {}{}",
+            "{:12.4} {} {} [{}] {}{}",
             self.time().map(|d| d.as_secs_f64()).unwrap_or_default(),
             self.channel().unwrap_or_default(),
-            self.id,
+            self.id_str(),
             self.payload.len(),
             self.payload_str(),
             if self.is_tx() { " (TX)" } else { "" }
@@ -127,27 +420,81 @@ impl Packet {
             id,
             payload: payload.into(),
             state: PacketState::TX,
+            fd: None,
+            id_type: IdType::Extended,
+            send_channel: 0,
         }
     }
+
+    /// Creates a new CAN FD [`Packet`] for transmit.  The payload is padded with zeros to the next valid FD length.
+    pub fn new_fd(id: u32, payload: &[u8], flags: FdFlags) -> Result<Self, Error> {
+        Ok(Self {
+            id,
+            payload: fd_payload(payload)?,
+            state: PacketState::TX,
+            fd: Some(flags),
+            id_type: IdType::Extended,
+            send_channel: 0,
+        })
+    }
     pub fn time(&self) -> Option<Duration> {
         match self.state {
             PacketState::TX => None,
-            PacketState::RX { time, channel: _ } => Some(time),
+            PacketState::RX { time, .. }
+            | PacketState::Echo { time, .. }
+            | PacketState::Remote { time, .. }
+            | PacketState::Error { time, .. }
+            | PacketState::Status { time, .. } => Some(time.elapsed),
         }
     }
+
+    /// Full timestamp of a received or echoed packet, with its wall clock anchor and source.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self.state {
+            PacketState::TX => None,
+            PacketState::RX { time, .. }
+            | PacketState::Echo { time, .. }
+            | PacketState::Remote { time, .. }
+            | PacketState::Error { time, .. }
+            | PacketState::Status { time, .. } => Some(time),
+        }
+    }
+
+    /// Wall clock time of the packet, if the connection or log file has an anchor.
+    pub fn wall_time(&self) -> Option<SystemTime> {
+        self.timestamp().and_then(|t| t.wall())
+    }
     pub fn channel(&self) -> Option<u32> {
         match self.state {
             PacketState::TX => None,
-            PacketState::RX { time: _, channel } => Some(channel),
+            PacketState::RX { channel, .. }
+            | PacketState::Echo { channel, .. }
+            | PacketState::Remote { channel, .. }
+            | PacketState::Error { channel, .. }
+            | PacketState::Status { channel, .. } => Some(channel),
         }
     }
     pub fn is_tx(&self) -> bool {
+        matches!(self.state, PacketState::TX | PacketState::Echo { .. })
+    }
+
+    /// True for transmitted and received data frames.  False for remote, error and status frames.
+    pub fn is_data(&self) -> bool {
+        matches!(
+            self.state,
+            PacketState::TX | PacketState::RX { .. } | PacketState::Echo { .. }
+        )
+    }
+
+    pub fn is_remote(&self) -> bool {
+        matches!(self.state, PacketState::Remote { .. })
+    }
+
+    /// Errors reported by an error or status frame.
+    pub fn errors(&self) -> Option<ErrorClasses> {
         match self.state {
-            PacketState::TX => true,
-            PacketState::RX {
-                time: _,
-                channel: _,
-            } => false,
+            PacketState::Error { errors, .. } | PacketState::Status { errors, .. } => Some(errors),
+            _ => None,
         }
     }
 
@@ -160,18 +507,183 @@ impl Packet {
     }
 
     /// Creates a packet for receive. Connections will call this.
-    pub fn new_rx(id: u32, payload: &[u8], time: Duration, channel: u32) -> Packet {
+    pub fn new_rx(id: u32, payload: &[u8], time: impl Into<Timestamp>, channel: u32) -> Packet {
         Packet {
             id,
             payload: payload.into(),
+            state: PacketState::RX {
+                time: time.into(),
+                channel,
+            },
+            fd: None,
+            id_type: IdType::Extended,
+            send_channel: 0,
+        }
+    }
+
+    /// Creates a received remote transmission request.
+    pub fn new_remote_rx(id: u32, dlc: u8, time: impl Into<Timestamp>, channel: u32) -> Packet {
+        Packet {
+            state: PacketState::Remote {
+                time: time.into(),
+                channel,
+                dlc,
+            },
+            ..Packet::new(id, &[])
+        }
+    }
+
+    /// Creates an error frame.  `data` is the adapter specific error data.
+    pub fn new_error(
+        errors: ErrorClasses,
+        data: &[u8],
+        time: impl Into<Timestamp>,
+        channel: u32,
+    ) -> Packet {
+        Packet {
+            state: PacketState::Error {
+                time: time.into(),
+                channel,
+                errors,
+            },
+            ..Packet::new(0, data)
+        }
+    }
+
+    /// Creates a controller status report.
+    pub fn new_status(errors: ErrorClasses, time: impl Into<Timestamp>, channel: u32) -> Packet {
+        Packet {
+            state: PacketState::Status {
+                time: time.into(),
+                channel,
+                errors,
+            },
+            ..Packet::new(0, &[])
+        }
+    }
+
+    /// Gap marker for packets dropped by a full [`PushBus`](crate::pushbus::PushBus) subscriber.  A status packet at the
+    /// time of the first dropped packet, with the number dropped as the payload.
+    pub fn new_gap(dropped: u64, first: &Packet) -> Packet {
+        Packet {
+            payload: dropped.to_be_bytes().into(),
+            ..Packet::new_status(
+                [ErrorClass::Dropped].into_iter().collect(),
+                first.timestamp().unwrap_or_default(),
+                first.channel().unwrap_or_default(),
+            )
+        }
+    }
+
+    /// Number of packets dropped before this gap marker, or `None` if this is not a gap marker.
+    pub fn dropped(&self) -> Option<u64> {
+        match self.state {
+            PacketState::Status { errors, .. } if errors.contains(ErrorClass::Dropped) => {
+                Some(u64::from_be_bytes(self.payload.get(..8)?.try_into().ok()?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Creates a CAN FD packet for receive. Connections will call this.
+    pub fn new_rx_fd(
+        id: u32,
+        payload: &[u8],
+        flags: FdFlags,
+        time: impl Into<Timestamp>,
+        channel: u32,
+    ) -> Result<Packet, Error> {
+        let time = time.into();
+        Ok(Packet {
+            id,
+            payload: fd_payload(payload)?,
             state: PacketState::RX { time, channel },
+            fd: Some(flags),
+            id_type: IdType::Extended,
+            send_channel: 0,
+        })
+    }
+
+    /// Replace the elapsed time of a received packet, keeping its anchor and source.  Packets that have not been sent
+    /// are unchanged.
+    pub fn with_time(self, time: Duration) -> Self {
+        match self.timestamp() {
+            Some(t) => self.with_timestamp(Timestamp { elapsed: time, ..t }),
+            None => self,
+        }
+    }
+
+    /// Replace the timestamp of a received packet.  Packets that have not been sent are unchanged.
+    pub fn with_timestamp(mut self, time: Timestamp) -> Self {
+        match &mut self.state {
+            PacketState::TX => {}
+            PacketState::RX { time: t, .. }
+            | PacketState::Echo { time: t, .. }
+            | PacketState::Remote { time: t, .. }
+            | PacketState::Error { time: t, .. }
+            | PacketState::Status { time: t, .. } => *t = time,
         }
+        self
     }
+
+    /// Replace the channel of a packet.  For packets that have not been sent, this sets [`Packet::send_channel`].
+    pub fn with_channel(mut self, channel: u32) -> Self {
+        match &mut self.state {
+            PacketState::TX => self.send_channel = channel,
+            PacketState::RX { channel: c, .. }
+            | PacketState::Echo { channel: c, .. }
+            | PacketState::Remote { channel: c, .. }
+            | PacketState::Error { channel: c, .. }
+            | PacketState::Status { channel: c, .. } => *c = channel,
+        }
+        self
+    }
+
+    /// Use an 11 bit standard or 29 bit extended id. Packets are extended unless changed.
+    pub fn with_id_type(mut self, id_type: IdType) -> Self {
+        self.id_type = id_type;
+        self
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.id_type == IdType::Extended
+    }
+
+    /// Id as hex. 3 digits for standard ids, 8 digits for extended ids.
+    pub fn id_str(&self) -> String {
+        match self.id_type {
+            IdType::Standard => format!("{:03X}", self.id),
+            IdType::Extended => format!("{:08X}", self.id),
+        }
+    }
+
+    pub fn is_fd(&self) -> bool {
+        self.fd.is_some()
+    }
+
+    /// Data length code as sent on the wire.
+    pub fn dlc(&self) -> u8 {
+        len_to_dlc(self.payload.len())
+    }
+
     pub(crate) fn len(&self) -> usize {
         self.payload.len()
     }
 }
 
+/// Pad an FD payload to the next valid length.
+fn fd_payload(payload: &[u8]) -> Result<Payload, Error> {
+    if payload.len() > CANFD_MAX_LEN {
+        return Err(Error::InvalidArgument(format!(
+            "CAN FD payload too long: {} > {CANFD_MAX_LEN}",
+            payload.len()
+        )));
+    }
+    let mut data = [0; CANFD_MAX_LEN];
+    data[..payload.len()].copy_from_slice(payload);
+    Ok(Payload::new(&data[..dlc_to_len(len_to_dlc(payload.len()))]))
+}
+
 fn as_hex(data: &[u8]) -> String {
     data.iter()
         .map(|byte| format!("{byte:02X}"))
@@ -179,7 +691,7 @@ fn as_hex(data: &[u8]) -> String {
         .join(" ")
 }
 
-fn as_hex_nospace(data: &[u8]) -> String {
+pub(crate) fn as_hex_nospace(data: &[u8]) -> String {
     // FIXME optimize
     let mut s = String::with_capacity(data.len() * 2);
     for byte in data {
@@ -187,3 +699,174 @@ fn as_hex_nospace(data: &[u8]) -> String {
     }
     s.to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dlc() {
+        assert_eq!(8, len_to_dlc(8));
+        assert_eq!(9, len_to_dlc(9));
+        assert_eq!(9, len_to_dlc(12));
+        assert_eq!(13, len_to_dlc(25));
+        assert_eq!(15, len_to_dlc(64));
+        assert_eq!(48, dlc_to_len(14));
+        for dlc in 0..16 {
+            assert_eq!(dlc, len_to_dlc(dlc_to_len(dlc)));
+        }
+    }
+
+    #[test]
+    fn fd_padding() -> Result<()> {
+        let p = Packet::new_fd(0x18FFAAFA, &[1; 10], FdFlags::default())?;
+        assert_eq!(12, p.payload.len());
+        assert_eq!(9, p.dlc());
+        assert_eq!([1, 1, 0, 0], p.payload[8..]);
+        assert!(Packet::new_fd(0x18FFAAFA, &[0; 65], FdFlags::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fd_round_trip() -> Result<()> {
+        let flags = FdFlags {
+            brs: true,
+            esi: false,
+        };
+        let payload: Vec<u8> = (0..64).collect();
+        let p = Packet::new_rx_fd(0x18FFAAFA, &payload, flags, Duration::from_millis(1500), 1)?;
+        let line = p.to_string();
+        assert!(line.starts_with("      1.5000 CANFD 1 Rx 18FFAAFAx 1 0 F 64 00 01 02"));
+
+        let q: Packet = line.parse()?;
+        assert_eq!(p.id, q.id);
+        assert_eq!(p.payload, q.payload);
+        assert_eq!(Some(flags), q.fd);
+        assert_eq!(Some(1), q.channel());
+        assert_eq!(p.time(), q.time());
+        Ok(())
+    }
+
+    #[test]
+    fn standard_id() -> Result<()> {
+        let p = Packet::new(0x7E0, &[2, 1, 0]).with_id_type(IdType::Standard);
+        assert_eq!("      0.0000 0 7E0 [3] 02 01 00 (TX)", p.to_string());
+
+        let p: Packet = "1.000 1 7E8 Rx d 3 02 41 00".parse()?;
+        assert_eq!(0x7E8, p.id);
+        assert_eq!(IdType::Standard, p.id_type);
+
+        let p: Packet = "1.000 1 7E8x Rx d 3 02 41 00".parse()?;
+        assert_eq!(0x7E8, p.id);
+        assert_eq!(IdType::Extended, p.id_type);
+        Ok(())
+    }
+
+    #[test]
+    fn remote() -> Result<()> {
+        let p = Packet::new_remote_rx(0x7DF, 8, Duration::from_millis(250), 1)
+            .with_id_type(IdType::Standard);
+        assert_eq!("      0.2500 1 7DF Rx r 8", p.to_string());
+        let q: Packet = p.to_string().parse()?;
+        assert!(q.is_remote());
+        assert!(!q.is_data());
+        assert_eq!(0x7DF, q.id);
+        assert_eq!(IdType::Standard, q.id_type);
+        assert!(matches!(q.state, PacketState::Remote { dlc: 8, .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn error_frame() -> Result<()> {
+        let errors: ErrorClasses = [ErrorClass::Ack, ErrorClass::BusOff].into_iter().collect();
+        let p = Packet::new_error(errors, &[0; 8], Duration::from_secs(2), 0);
+        assert_eq!("      2.0000 0 ErrorFrame BusOff Ack", p.to_string());
+        let q: Packet = p.to_string().parse()?;
+        assert_eq!(Some(errors), q.errors());
+
+        let q: Packet = "2.000000 1 ErrorFrame".parse()?;
+        assert_eq!(Some(ErrorClasses::default()), q.errors());
+        Ok(())
+    }
+
+    #[test]
+    fn status() -> Result<()> {
+        let errors: ErrorClasses = [ErrorClass::ErrorPassive, ErrorClass::Overrun]
+            .into_iter()
+            .collect();
+        let p = Packet::new_status(errors, Duration::from_secs(3), 1);
+        assert_eq!(
+            "      3.0000 CAN 1 Status:chip status error passive - Overrun",
+            p.to_string()
+        );
+        let q: Packet = p.to_string().parse()?;
+        assert_eq!(Some(errors), q.errors());
+        assert_eq!(Some(1), q.channel());
+        Ok(())
+    }
+
+    #[test]
+    fn fd_parse_vector() -> Result<()> {
+        let p: Packet =
+            "2.501000 CANFD 1 Rx 1a4 Engine 1 1 9 12 00 01 02 03 04 05 06 07 08 09 0a 0b 0 0 0"
+                .parse()?;
+        assert_eq!(0x1A4, p.id);
+        assert_eq!(IdType::Standard, p.id_type);
+        assert_eq!(12, p.payload.len());
+        assert_eq!(
+            Some(FdFlags {
+                brs: true,
+                esi: true
+            }),
+            p.fd
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn gap() {
+        let first = Packet::new_rx(0x18FEF100, &[1], Duration::from_millis(5), 2);
+        let gap = Packet::new_gap(300, &first);
+        assert_eq!(Some(300), gap.dropped());
+        assert_eq!(Some(2), gap.channel());
+        assert_eq!(first.time(), gap.time());
+        assert!(gap.errors().unwrap().contains(ErrorClass::Dropped));
+        assert_eq!(None, first.dropped());
+        let overrun = Packet::new_status(
+            [ErrorClass::Overrun].into_iter().collect(),
+            first.timestamp().unwrap(),
+            0,
+        );
+        assert_eq!(None, overrun.dropped());
+    }
+
+    #[test]
+    fn send_channel() {
+        let p = Packet::new(0x100, &[1]).with_channel(1);
+        assert_eq!(1, p.send_channel);
+        // not sent, so not on a channel yet
+        assert_eq!(None, p.channel());
+        let rx = Packet::new_rx(0x100, &[1], Duration::ZERO, 0).with_channel(3);
+        assert_eq!((Some(3), 0), (rx.channel(), rx.send_channel));
+    }
+
+    #[test]
+    fn timestamp_rebase() {
+        let anchor = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let t = Timestamp::new(Duration::from_secs(5), anchor, TimeSource::Kernel);
+        assert_eq!(Some(anchor + Duration::from_secs(5)), t.wall());
+
+        let later = t.rebase(anchor + Duration::from_secs(2));
+        assert_eq!(Duration::from_secs(3), later.elapsed);
+        assert_eq!(t.wall(), later.wall());
+        assert_eq!(TimeSource::Kernel, later.source);
+        assert_eq!(
+            Duration::ZERO,
+            t.rebase(anchor + Duration::from_secs(9)).elapsed
+        );
+
+        let p = Packet::new_rx(0x100, &[1], t, 0).with_time(Duration::from_secs(1));
+        assert_eq!(Some(anchor + Duration::from_secs(1)), p.wall_time());
+        assert_eq!(None, Packet::new(0x100, &[1]).wall_time());
+    }
+}
@@ -1,15 +1,25 @@
 use std::collections::VecDeque;
-use std::sync::atomic::AtomicBool;
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
+use std::sync::Condvar;
 use std::sync::Mutex;
-use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
 /// PushBusIter is an experiment to use array based queues per thread, instead of a shared Linked List.
 /// Most CPU time is used reading the RP1210 adapter, so the Bus isn't a significant contributer to CPU usage.
+///
+/// Each subscriber waits on its own condvar, so a push wakes waiting subscribers immediately instead of after a sleep.
+/// Each subscriber queue is bounded, see [`Overflow`].
 pub struct PushBus<T> {
     iters: Arc<Mutex<Vec<PushBusIter<T>>>>,
     name: String,
+    capacity: usize,
+    overflow: Overflow,
+    gap: Option<fn(u64, &T) -> T>,
+    dropped: Arc<AtomicU64>,
 }
 
 impl<T: Clone> Clone for PushBus<T> {
@@ -17,6 +27,10 @@ impl<T: Clone> Clone for PushBus<T> {
         Self {
             iters: self.iters.clone(),
             name: self.name.clone(),
+            capacity: self.capacity,
+            overflow: self.overflow,
+            gap: self.gap,
+            dropped: self.dropped.clone(),
         }
     }
 }
@@ -26,24 +40,188 @@ impl<T> PushBus<T> {
             .lock()
             .unwrap()
             .iter_mut()
-            .for_each(|i| i.running.store(false, std::sync::atomic::Ordering::Relaxed));
+            .for_each(|i| i.close());
+    }
+
+    /// Items dropped by all subscribers, including closed ones.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// What [`PushBus::push`] does when a subscriber's queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Keep the newest items, for live displays and loggers that must not stall the adapter.
+    #[default]
+    DropOldest,
+    /// Keep the oldest items, so a consumer sees a contiguous start.
+    DropNewest,
+    /// Wait for the subscriber to catch up.  Stalls the adapter and every other subscriber.
+    Block,
+}
+
+/// Default queue length per subscriber.  About 10 MB of packets, or 25 s of a fully loaded 500k bus at about 4000
+/// frames/s.
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+enum Slot<T> {
+    Item(T),
+    /// `dropped` items were lost here.  `first` is the first of them.
+    Gap {
+        dropped: u64,
+        first: T,
+    },
+}
+
+/// Decides on push whether an item is queued for a subscriber.
+type Accept<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+
+/// Queue of one subscriber.
+struct Subscriber<T> {
+    data: Mutex<VecDeque<Slot<T>>>,
+    /// Signaled on push
+    ready: Condvar,
+    /// Signaled on push and close, for [`PushBus::stream`]
+    #[cfg(feature = "async")]
+    woken: tokio::sync::Notify,
+    /// Signaled on receive, for [`Overflow::Block`]
+    space: Condvar,
+    /// Threads waiting on `ready` and `space`.  Only changed with `data` locked, so notifying can skip the syscall
+    /// when nobody waits.
+    receiving: AtomicUsize,
+    sending: AtomicUsize,
+    running: AtomicBool,
+    capacity: usize,
+    overflow: Overflow,
+    gap: Option<fn(u64, &T) -> T>,
+    dropped: AtomicU64,
+    /// [`PushBus::dropped`]
+    bus_dropped: Arc<AtomicU64>,
+    filter: Option<Accept<T>>,
+}
+
+impl<T> Subscriber<T> {
+    fn accepts(&self, item: &T) -> bool {
+        self.filter.as_ref().is_none_or(|f| f(item))
+    }
+
+    fn count_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        self.bus_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn notify_ready(&self) {
+        if self.receiving.load(Ordering::Relaxed) > 0 {
+            self.ready.notify_one();
+        }
+        // stores a permit if the stream isn't waiting, so a push between its check and its wait isn't missed
+        #[cfg(feature = "async")]
+        self.woken.notify_one();
+    }
+
+    fn notify_space(&self) {
+        if self.sending.load(Ordering::Relaxed) > 0 {
+            self.space.notify_one();
+        }
+    }
+}
+
+impl<T: Clone> Subscriber<T> {
+    /// Queue `item`, or drop according to the overflow policy.  `false` if the caller must wait for space.
+    fn offer(&self, item: &T, name: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        if data.len() >= self.capacity {
+            match self.overflow {
+                Overflow::Block => return false,
+                Overflow::DropNewest => {
+                    self.count_drop();
+                    match data.back_mut() {
+                        Some(Slot::Gap { dropped, .. }) => *dropped += 1,
+                        _ => {
+                            eprintln!("{name} pushbus full, dropping newest");
+                            data.push_back(Slot::Gap {
+                                dropped: 1,
+                                first: item.clone(),
+                            });
+                        }
+                    }
+                    return true;
+                }
+                Overflow::DropOldest => {
+                    // the gap stays at the front, ahead of the oldest kept item
+                    let mut gap = None;
+                    while data.len() >= self.capacity {
+                        match data.pop_front() {
+                            Some(Slot::Gap { dropped, first }) => gap = Some((dropped, first)),
+                            Some(Slot::Item(v)) => {
+                                self.count_drop();
+                                match &mut gap {
+                                    Some((dropped, _)) => *dropped += 1,
+                                    None => {
+                                        eprintln!("{name} pushbus full, dropping oldest");
+                                        gap = Some((1, v));
+                                    }
+                                }
+                            }
+                            None => break,
+                        }
+                    }
+                    if let Some((dropped, first)) = gap {
+                        data.push_front(Slot::Gap { dropped, first });
+                    }
+                }
+            }
+        }
+        data.push_back(Slot::Item(item.clone()));
+        self.notify_ready();
+        true
+    }
+
+    /// Queue `item` once there is space, for [`Overflow::Block`].
+    fn wait_for_space(&self, item: &T) {
+        let mut data = self.data.lock().unwrap();
+        while data.len() >= self.capacity && self.running.load(Ordering::Relaxed) {
+            self.sending.fetch_add(1, Ordering::Relaxed);
+            data = self.space.wait(data).unwrap();
+            self.sending.fetch_sub(1, Ordering::Relaxed);
+        }
+        data.push_back(Slot::Item(item.clone()));
+        self.notify_ready();
     }
 }
 
 #[derive(Clone)]
 pub struct PushBusIter<T> {
-    data: Arc<Mutex<VecDeque<Option<T>>>>,
-    running: Arc<AtomicBool>,
-    sleep: bool,
+    subscriber: Arc<Subscriber<T>>,
 }
 impl<T> PushBus<T> {
     pub fn new(name: &str) -> Self {
         Self {
             iters: Default::default(),
             name: name.to_string(),
+            capacity: DEFAULT_CAPACITY,
+            overflow: Overflow::default(),
+            gap: None,
+            dropped: Default::default(),
         }
     }
+
+    /// Queue length and overflow policy of subscribers created by [`PushBus::iter`] and [`PushBus::iter_until`].
+    pub fn with_capacity(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.capacity = capacity;
+        self.overflow = overflow;
+        self
+    }
+
+    /// Make subscribers yield `gap(dropped, first_dropped)` where items were dropped.  Without it, dropped items are
+    /// only counted.
+    pub fn with_gap(mut self, gap: fn(u64, &T) -> T) -> Self {
+        self.gap = Some(gap);
+        self
+    }
 }
+/// Longest wait in [`PushBusIter::next`] before reporting an empty poll.
 const SLEEP_DURATION: Duration = Duration::from_millis(1);
 impl<T> Iterator for PushBusIter<T> {
     /// That's right, `Option<Option<Packet>>`
@@ -52,49 +230,207 @@ impl<T> Iterator for PushBusIter<T> {
     /// Some(Packet) is a CAN packet
     type Item = Option<T>;
     fn next(&mut self) -> Option<Self::Item> {
-        if !self.running.load(std::sync::atomic::Ordering::Relaxed) {
+        if !self.is_running() {
             // done
             return None;
         }
-        let v = self.data.lock().unwrap().pop_front();
-        if v.is_some() {
-            self.sleep = false;
-            return v;
+        // wait for a push, but report an empty poll so callers can check their own conditions
+        match self.recv_timeout(SLEEP_DURATION) {
+            Ok(v) => Some(Some(v)),
+            Err(RecvTimeoutError::Timeout) => Some(None),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+impl<T> PushBusIter<T> {
+    fn is_running(&self) -> bool {
+        self.subscriber.running.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        self.subscriber.running.store(false, Ordering::Relaxed);
+        // take the lock so a waiter can't miss the notification between checking running and waiting
+        let _data = self.subscriber.data.lock().unwrap();
+        self.subscriber.ready.notify_all();
+        self.subscriber.space.notify_all();
+        #[cfg(feature = "async")]
+        self.subscriber.woken.notify_one();
+    }
+
+    /// Items dropped because this subscriber's queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.subscriber.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Next item or gap marker from a locked queue.
+    fn pop(&self, data: &mut VecDeque<Slot<T>>) -> Option<T> {
+        while let Some(slot) = data.pop_front() {
+            self.subscriber.notify_space();
+            match (slot, self.subscriber.gap) {
+                (Slot::Item(v), _) => return Some(v),
+                (Slot::Gap { dropped, first }, Some(gap)) => return Some(gap(dropped, &first)),
+                (Slot::Gap { .. }, None) => continue,
+            }
+        }
+        None
+    }
+
+    /// Block until an item is pushed.  `None` when the bus is closed.
+    pub fn recv(&mut self) -> Option<T> {
+        let mut data = self.subscriber.data.lock().unwrap();
+        loop {
+            if let Some(v) = self.pop(&mut data) {
+                return Some(v);
+            }
+            if !self.is_running() {
+                return None;
+            }
+            self.subscriber.receiving.fetch_add(1, Ordering::Relaxed);
+            data = self.subscriber.ready.wait(data).unwrap();
+            self.subscriber.receiving.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Block until an item is pushed or `deadline` passes.  Queued items are returned even after the deadline.
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        let mut data = self.subscriber.data.lock().unwrap();
+        loop {
+            if let Some(v) = self.pop(&mut data) {
+                return Ok(v);
+            }
+            if !self.is_running() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            self.subscriber.receiving.fetch_add(1, Ordering::Relaxed);
+            data = self
+                .subscriber
+                .ready
+                .wait_timeout(data, deadline - now)
+                .unwrap()
+                .0;
+            self.subscriber.receiving.fetch_sub(1, Ordering::Relaxed);
         }
-        // this means there was an empty response from poll()
-        // sleep to avoid busy spinning, but don't sleep the first time
-        if self.sleep {
-            thread::sleep(SLEEP_DURATION);
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_deadline(Instant::now() + timeout)
+    }
+
+    /// Wait without blocking the thread until an item is pushed.  `None` when the bus is closed.
+    #[cfg(feature = "async")]
+    async fn recv_async(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut data = self.subscriber.data.lock().unwrap();
+                if let Some(v) = self.pop(&mut data) {
+                    return Some(v);
+                }
+                if !self.is_running() {
+                    return None;
+                }
+            }
+            self.subscriber.woken.notified().await;
         }
-        self.sleep = true;
-        Some(None)
+    }
+}
+
+impl<T: Send + Sync + 'static> PushBusIter<T> {
+    /// Items received until `end`.  Blocks between items without polling, and ends at `end` even if nothing is pushed.
+    pub fn until(mut self, end: Instant) -> Box<dyn Iterator<Item = T> + Send + Sync> {
+        Box::new(iter::from_fn(move || self.recv_deadline(end).ok()))
     }
 }
 
 impl<T: Send + Sync + 'static + Clone> PushBus<T> {
-    pub fn iter(&self) -> Box<dyn Iterator<Item = Option<T>> + Send + Sync> {
+    pub fn subscribe(&self) -> PushBusIter<T> {
+        self.subscribe_with(self.capacity, self.overflow)
+    }
+
+    /// Subscribe with a queue length and overflow policy other than the bus default.
+    pub fn subscribe_with(&self, capacity: usize, overflow: Overflow) -> PushBusIter<T> {
+        self.add(capacity, overflow, None)
+    }
+
+    /// Subscribe to the items that `filter` accepts.  The filter runs in [`PushBus::push`], so other items are never
+    /// cloned or queued for this subscriber, and don't count against its capacity.
+    pub fn subscribe_filtered(
+        &self,
+        filter: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> PushBusIter<T> {
+        self.add(self.capacity, self.overflow, Some(Box::new(filter)))
+    }
+
+    fn add(
+        &self,
+        capacity: usize,
+        overflow: Overflow,
+        filter: Option<Accept<T>>,
+    ) -> PushBusIter<T> {
         let x = PushBusIter {
-            data: Arc::new(Mutex::new(VecDeque::new())),
-            running: Arc::new(AtomicBool::new(true)),
-            sleep: false,
+            subscriber: Arc::new(Subscriber {
+                data: Mutex::new(VecDeque::new()),
+                ready: Condvar::new(),
+                #[cfg(feature = "async")]
+                woken: tokio::sync::Notify::new(),
+                space: Condvar::new(),
+                receiving: AtomicUsize::new(0),
+                sending: AtomicUsize::new(0),
+                running: AtomicBool::new(true),
+                capacity: capacity.max(1),
+                overflow,
+                gap: self.gap,
+                dropped: AtomicU64::new(0),
+                bus_dropped: self.dropped.clone(),
+                filter,
+            }),
         };
         self.iters.lock().unwrap().push(x.clone());
-        Box::new(x)
+        x
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Option<T>> + Send + Sync> {
+        Box::new(self.subscribe())
+    }
+
+    /// Items pushed until `end`.  Blocks between items without polling, and ends at `end` even if nothing is pushed.
+    pub fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = T> + Send + Sync> {
+        self.subscribe().until(end)
+    }
+
+    /// Subscribe without polling.  The stream wakes when an item is pushed, and empty polls are skipped.  Like
+    /// [`PushBus::subscribe`], the queue has the bus capacity, overflow policy and gap markers.
+    #[cfg(feature = "async")]
+    pub fn stream(&self) -> impl futures_util::Stream<Item = T> + Send + Unpin {
+        Box::pin(futures_util::stream::unfold(
+            self.subscribe(),
+            |mut iter| async move { iter.recv_async().await.map(|item| (item, iter)) },
+        ))
     }
 
+    /// `None` is an empty poll of the adapter.  Subscribers report empty polls on their own when they wait, so it is
+    /// not queued.
     pub fn push(&self, item: Option<T>) {
-        let mut iters = self.iters.lock().unwrap();
-        // remove closed iterators.
-        iters.retain(|i| i.running.load(std::sync::atomic::Ordering::Relaxed));
-        iters.iter_mut().for_each(|i| {
-            let mut items = i.data.lock().unwrap();
-            let len = items.len();
-            if len > 10_000 {
-                let name = self.name.as_str();
-                eprintln!("{name} pushbus too deep: {len}");
-            }
-            items.push_back(item.clone())
-        });
+        let Some(item) = item else {
+            return;
+        };
+        // full blocking subscribers are waited on after releasing the lock, so they can still subscribe and send
+        let mut blocked = Vec::new();
+        {
+            let mut iters = self.iters.lock().unwrap();
+            // remove closed iterators.
+            iters.retain(|i| i.is_running());
+            iters.iter().for_each(|i| {
+                if i.subscriber.accepts(&item) && !i.subscriber.offer(&item, &self.name) {
+                    blocked.push(i.subscriber.clone());
+                }
+            });
+        }
+        blocked.iter().for_each(|s| s.wait_for_space(&item));
     }
 }
 
@@ -106,15 +442,20 @@ impl<T: Send + Sync + 'static + Clone> PushBus<T> {
 // }
 impl<T> Drop for PushBusIter<T> {
     fn drop(&mut self) {
-        self.running
-            .store(false, std::sync::atomic::Ordering::Relaxed);
+        self.close();
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        iter,
+        sync::mpsc::RecvTimeoutError,
+        thread,
+        time::{Duration, Instant},
+    };
 
-    use crate::pushbus::PushBus;
+    use crate::pushbus::{Overflow, PushBus, PushBusIter};
     #[test]
     fn test_clone() {
         let pb1 = PushBus::new("test");
@@ -136,4 +477,147 @@ mod tests {
         assert_eq!(None, i1.next().unwrap());
         assert_eq!(None, i2.next().unwrap());
     }
+
+    #[test]
+    fn test_wake_on_push() {
+        let pb = PushBus::new("test");
+        let mut i = pb.subscribe();
+        let start = Instant::now();
+        assert_eq!(
+            Err(RecvTimeoutError::Timeout),
+            i.recv_timeout(Duration::from_millis(20))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let producer = pb.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            producer.push(Some(Instant::now()));
+        });
+        let pushed = i.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(pushed.elapsed() < Duration::from_millis(10));
+
+        let mut pb = pb;
+        pb.close();
+        assert_eq!(None, i.recv());
+    }
+
+    #[test]
+    fn test_iter_until() {
+        let pb = PushBus::new("test");
+        let end = Instant::now() + Duration::from_millis(50);
+        let i = pb.iter_until(end);
+        pb.push(Some(1));
+        pb.push(None);
+        pb.push(Some(2));
+        assert_eq!(vec![1, 2], i.collect::<Vec<_>>());
+        assert!(Instant::now() >= end);
+    }
+
+    #[test]
+    fn test_overflow() {
+        // gap markers are negative counts
+        let pb = PushBus::new("test").with_gap(|dropped, _| -(dropped as i64));
+        let mut oldest = pb.subscribe_with(3, Overflow::DropOldest);
+        let mut newest = pb.subscribe_with(3, Overflow::DropNewest);
+        (1..=6).for_each(|i| pb.push(Some(i)));
+        let drain = |i: &mut PushBusIter<i64>| {
+            iter::from_fn(|| i.recv_timeout(Duration::ZERO).ok()).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![-3, 4, 5, 6], drain(&mut oldest));
+        assert_eq!(vec![1, 2, 3, -3], drain(&mut newest));
+        assert_eq!(3, oldest.dropped());
+        assert_eq!(3, newest.dropped());
+        drop(newest);
+        assert_eq!(6, pb.dropped());
+
+        // without a marker, drops are only counted
+        let pb = PushBus::new("test").with_capacity(2, Overflow::DropOldest);
+        let mut i = pb.subscribe();
+        (1..=3).for_each(|i| pb.push(Some(i)));
+        assert_eq!(Ok(2), i.recv_timeout(Duration::ZERO));
+        assert_eq!(1, i.dropped());
+    }
+
+    #[test]
+    fn test_overflow_block() {
+        let pb = PushBus::new("test");
+        let mut i = pb.subscribe_with(2, Overflow::Block);
+        let producer = pb.clone();
+        let done = thread::spawn(move || (1..=5).for_each(|n| producer.push(Some(n))));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!done.is_finished());
+        let received: Vec<_> = (0..5)
+            .map_while(|_| i.recv_timeout(Duration::from_secs(2)).ok())
+            .collect();
+        assert_eq!(vec![1, 2, 3, 4, 5], received);
+        assert_eq!(0, i.dropped());
+        done.join().unwrap();
+
+        // dropping a full subscriber releases the producer
+        drop(i);
+        let blocking = pb.subscribe_with(1, Overflow::Block);
+        let producer = pb.clone();
+        let done = thread::spawn(move || (1..=3).for_each(|n| producer.push(Some(n))));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!done.is_finished());
+        drop(blocking);
+        done.join().unwrap();
+    }
+
+    #[test]
+    fn test_filtered() {
+        let pb = PushBus::new("test").with_capacity(2, Overflow::DropOldest);
+        let all = pb.iter_until(Instant::now() + Duration::from_millis(20));
+        let even = pb
+            .subscribe_filtered(|i| i % 2 == 0)
+            .until(Instant::now() + Duration::from_millis(20));
+        (1..=4).for_each(|i| pb.push(Some(i)));
+        // rejected items don't take up space
+        assert_eq!(vec![2, 4], even.collect::<Vec<_>>());
+        assert_eq!(vec![3, 4], all.collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_stream() {
+        use futures_util::StreamExt;
+
+        let mut pb = PushBus::new("test");
+        let mut s = pb.stream();
+        pb.push(Some(1));
+        pb.push(None);
+        pb.push(Some(2));
+        assert_eq!(Some(1), s.next().await);
+        assert_eq!(Some(2), s.next().await);
+        drop(pb.stream());
+        pb.push(Some(3));
+        assert_eq!(1, pb.iters.lock().unwrap().len());
+        pb.close();
+        assert_eq!(Some(3), s.next().await);
+        assert_eq!(None, s.next().await);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_stream_overflow() {
+        use futures_util::StreamExt;
+
+        let pb = PushBus::new("test")
+            .with_capacity(3, Overflow::DropOldest)
+            .with_gap(|dropped, _| -(dropped as i64));
+        let mut s = pb.stream();
+        (1..=6).for_each(|i| pb.push(Some(i)));
+        let received: Vec<_> = s.by_ref().take(4).collect().await;
+        assert_eq!(vec![-3, 4, 5, 6], received);
+        assert_eq!(3, pb.dropped());
+
+        // a waiting stream wakes on push
+        let producer = pb.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            producer.push(Some(7));
+        });
+        assert_eq!(Some(7), s.next().await);
+    }
 }
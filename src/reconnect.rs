@@ -0,0 +1,353 @@
+//! Keeps a connection open across adapter loss, such as an unplugged USB dongle or a SocketCAN interface going down.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, Builder},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bus_state::BusState,
+    connection::{Clock, Connection, ConnectionFactory, Filter, IdMask},
+    error::{Error, Result},
+    packet::{ErrorClass, Packet},
+    pushbus::PushBus,
+    stats::Stats,
+};
+
+/// Delays between attempts to reconnect.  Each failed attempt doubles the delay, up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub first: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            first: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A connection from a [`ConnectionFactory`] that is created again when the adapter is lost.  Loss is noticed when
+/// the connection's packets end.  A status packet with [`ErrorClass::Disconnected`] reports it, and one with
+/// [`ErrorClass::Restarted`] reports the new connection.  Iterators keep receiving across reconnects, and packets are
+/// rebased to the clock of the `Reconnecting`, so their timestamps stay monotonic.
+pub struct Reconnecting {
+    current: Arc<RwLock<Option<Box<dyn Connection>>>>,
+    /// Of the factory, for errors
+    name: String,
+    bus: PushBus<Packet>,
+    running: Arc<AtomicBool>,
+    clock: Clock,
+    /// Programmed into each new connection
+    filters: Arc<Mutex<Option<Vec<IdMask>>>>,
+    /// Of the connections that were lost
+    lost: Arc<Mutex<Stats>>,
+}
+
+type Packets = Box<dyn Iterator<Item = Option<Packet>> + Send + Sync>;
+
+impl Reconnecting {
+    /// Fails if the first connection fails, so configuration errors are reported at once.
+    pub fn new(
+        factory: Box<dyn ConnectionFactory + Send>,
+        backoff: Backoff,
+    ) -> Result<Reconnecting> {
+        let connection = factory.create()?;
+        let packets = connection.iter();
+        let reconnecting = Reconnecting {
+            current: Arc::new(RwLock::new(Some(connection))),
+            name: factory.name(),
+            bus: PushBus::new("reconnecting").with_gap(Packet::new_gap),
+            running: Arc::new(AtomicBool::new(true)),
+            clock: Clock::new(),
+            filters: Default::default(),
+            lost: Default::default(),
+        };
+        let monitor = Monitor {
+            factory,
+            backoff,
+            current: reconnecting.current.clone(),
+            bus: reconnecting.bus.clone(),
+            running: reconnecting.running.clone(),
+            clock: reconnecting.clock,
+            filters: reconnecting.filters.clone(),
+            lost: reconnecting.lost.clone(),
+        };
+        Builder::new()
+            .name("reconnecting".into())
+            .spawn(move || monitor.run(packets))?;
+        Ok(reconnecting)
+    }
+
+    /// Whether the adapter is connected now.
+    pub fn is_connected(&self) -> bool {
+        self.current.read().unwrap().is_some()
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&dyn Connection) -> Result<T>) -> Result<T> {
+        match self.current.read().unwrap().as_deref() {
+            Some(connection) => f(connection),
+            None => Err(Error::Disconnected(format!("{}, reconnecting,", self.name))),
+        }
+    }
+}
+
+/// Moves packets from the current connection to the bus, and replaces the connection when its packets end.
+struct Monitor {
+    factory: Box<dyn ConnectionFactory + Send>,
+    backoff: Backoff,
+    current: Arc<RwLock<Option<Box<dyn Connection>>>>,
+    bus: PushBus<Packet>,
+    running: Arc<AtomicBool>,
+    clock: Clock,
+    filters: Arc<Mutex<Option<Vec<IdMask>>>>,
+    lost: Arc<Mutex<Stats>>,
+}
+
+impl Monitor {
+    fn run(self, mut packets: Packets) {
+        loop {
+            for packet in packets.by_ref() {
+                if !self.running() {
+                    return;
+                }
+                self.bus.push(packet.map(|p| rebase(p, self.clock)));
+            }
+            {
+                // lost before current, as in `stats`, so the counts are never missing from both
+                let mut lost = self.lost.lock().unwrap();
+                if let Some(stats) = self.current.write().unwrap().take().and_then(|c| c.stats()) {
+                    *lost = lost.plus(&stats);
+                }
+            }
+            self.status(ErrorClass::Disconnected);
+            match self.reconnect() {
+                Some(next) => packets = next,
+                None => return,
+            }
+            self.status(ErrorClass::Restarted);
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Create the connection again, waiting longer after each failure.  `None` if closed first.
+    fn reconnect(&self) -> Option<Packets> {
+        let mut delay = self.backoff.first;
+        loop {
+            let deadline = Instant::now() + delay;
+            // keep the empty polls going, and notice a close, while waiting
+            while Instant::now() < deadline {
+                if !self.running() {
+                    return None;
+                }
+                self.bus.push(None);
+                thread::sleep(Duration::from_millis(1).min(delay));
+            }
+            if let Ok(connection) = self.factory.create() {
+                if let Some(filters) = self.filters.lock().unwrap().as_ref() {
+                    if let Err(e) = connection.set_filters(filters) {
+                        eprintln!("{}: filters not restored: {e}", self.factory.name());
+                    }
+                }
+                let packets = connection.iter();
+                *self.current.write().unwrap() = Some(connection);
+                return Some(packets);
+            }
+            delay = (delay * 2).min(self.backoff.max);
+        }
+    }
+
+    fn status(&self, error: ErrorClass) {
+        let errors = [error].into_iter().collect();
+        self.bus
+            .push(Some(Packet::new_status(errors, self.clock.now(), 0)));
+    }
+}
+
+/// The packet on the clock of the `Reconnecting`, rather than of the connection that received it.
+fn rebase(packet: Packet, clock: Clock) -> Packet {
+    match packet.timestamp() {
+        Some(time) => packet.with_timestamp(time.rebase(clock.anchor())),
+        None => packet,
+    }
+}
+
+impl Connection for Reconnecting {
+    /// Fails while disconnected.
+    fn send(&self, packet: &Packet) -> Result<Packet> {
+        let echo = self.with_connection(|c| c.send(packet))?;
+        Ok(rebase(echo, self.clock))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        self.bus.iter()
+    }
+
+    fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus.iter_until(end)
+    }
+
+    fn subscribe(&self, filter: Filter) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        Box::new(self.bus.subscribe_filtered(move |p| filter.matches(p)))
+    }
+
+    fn subscribe_until(
+        &self,
+        filter: Filter,
+        end: Instant,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus
+            .subscribe_filtered(move |p| filter.matches(p))
+            .until(end)
+    }
+
+    /// Also programmed into each new connection.
+    fn set_filters(&self, filters: &[IdMask]) -> Result<()> {
+        *self.filters.lock().unwrap() = Some(filters.to_vec());
+        self.with_connection(|c| c.set_filters(filters))
+    }
+
+    /// Counts since this connection opened, including those of the connections that were lost.  The bitrate is of
+    /// the current connection.
+    fn stats(&self) -> Option<Stats> {
+        let lost = self.lost.lock().unwrap();
+        let stats = match self.current.read().unwrap().as_deref() {
+            Some(connection) => connection.stats()?.plus(&lost),
+            None => Stats {
+                bitrate: None,
+                ..*lost
+            },
+        };
+        Some(stats.plus(&Stats {
+            dropped: self.bus.dropped(),
+            ..Default::default()
+        }))
+    }
+
+    /// Of the current connection.  `None` while disconnected.
+    fn bus_state(&self) -> Option<BusState> {
+        self.with_connection(|c| Ok(c.bus_state())).ok().flatten()
+    }
+}
+
+impl Drop for Reconnecting {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.bus.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::sim::SimulatedConnection;
+
+    /// A simulated adapter that can be unplugged, and fails to connect `failures` times after that.
+    #[derive(Clone, Default)]
+    struct Plug {
+        unplugged: Arc<AtomicBool>,
+        failures: Arc<AtomicUsize>,
+        created: Arc<AtomicUsize>,
+    }
+
+    struct Unpluggable(SimulatedConnection, Arc<AtomicBool>);
+
+    impl Connection for Unpluggable {
+        fn send(&self, packet: &Packet) -> Result<Packet> {
+            self.0.send(packet)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+            let unplugged = self.1.clone();
+            Box::new(
+                self.0
+                    .iter()
+                    .take_while(move |_| !unplugged.load(Ordering::Relaxed)),
+            )
+        }
+
+        fn stats(&self) -> Option<Stats> {
+            self.0.stats()
+        }
+    }
+
+    impl ConnectionFactory for Plug {
+        fn create(&self) -> Result<Box<dyn Connection>> {
+            if self.failures.load(Ordering::Relaxed) > 0 {
+                self.failures.fetch_sub(1, Ordering::Relaxed);
+                return Err(Error::Disconnected("plug".into()));
+            }
+            self.created.fetch_add(1, Ordering::Relaxed);
+            self.unplugged.store(false, Ordering::Relaxed);
+            Ok(Box::new(Unpluggable(
+                SimulatedConnection::silent(),
+                self.unplugged.clone(),
+            )))
+        }
+
+        fn command_line(&self) -> String {
+            "plug".into()
+        }
+
+        fn name(&self) -> String {
+            "plug".into()
+        }
+    }
+
+    #[test]
+    fn reconnect() -> Result<()> {
+        let plug = Plug::default();
+        let backoff = Backoff {
+            first: Duration::from_millis(5),
+            max: Duration::from_millis(20),
+        };
+        let connection = Reconnecting::new(Box::new(plug.clone()), backoff)?;
+        let mut packets = connection.iter_for(Duration::from_secs(2));
+        let before = connection.send(&Packet::new(0x100, &[1]))?;
+        assert_eq!(0x100, packets.next().unwrap().id);
+
+        plug.failures.store(3, Ordering::Relaxed);
+        plug.unplugged.store(true, Ordering::Relaxed);
+        let lost = packets.next().unwrap();
+        assert!(lost.errors().unwrap().contains(ErrorClass::Disconnected));
+        let restarted = packets.next().unwrap();
+        assert!(restarted.errors().unwrap().contains(ErrorClass::Restarted));
+        assert_eq!(2, plug.created.load(Ordering::Relaxed));
+        assert!(connection.is_connected());
+
+        // the same iterator receives from the new connection, on the same clock
+        let after = connection.send(&Packet::new(0x200, &[2]))?;
+        assert_eq!(0x200, packets.next().unwrap().id);
+        assert!(after.time() > before.time());
+        // counts carry over from the lost connection
+        assert_eq!(2, connection.stats().unwrap().tx_frames);
+        Ok(())
+    }
+
+    #[test]
+    fn send_while_disconnected() -> Result<()> {
+        let plug = Plug::default();
+        let backoff = Backoff {
+            first: Duration::from_secs(10),
+            max: Duration::from_secs(10),
+        };
+        let connection = Reconnecting::new(Box::new(plug.clone()), backoff)?;
+        let mut packets = connection.iter_for(Duration::from_secs(1));
+        plug.unplugged.store(true, Ordering::Relaxed);
+        assert!(packets.any(|p| p.errors().is_some()));
+        assert!(!connection.is_connected());
+        assert!(connection.send(&Packet::new(0x100, &[1])).is_err());
+        Ok(())
+    }
+}
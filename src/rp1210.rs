@@ -4,9 +4,9 @@ use std::{
 };
 
 use crate::connection::ConnectionFactory;
+use crate::error::{Error, Result};
 use crate::packet::*;
-use crate::{connection::*, j1939::j1939_packet::*, pushbus::*};
-use anyhow::*;
+use crate::{bus_state::*, connection::*, j1939::j1939_packet::*, pushbus::*, stats::*};
 use libloading::os::windows::Symbol as WinSymbol;
 use libloading::*;
 use std::ffi::CString;
@@ -14,6 +14,7 @@ use std::sync::atomic::Ordering::Relaxed;
 use std::sync::atomic::*;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 
 pub const PACKET_SIZE: usize = 1600;
 
@@ -32,10 +33,20 @@ pub struct Rp1210 {
     api: API,
     bus: PushBus<Packet>,
     running: Arc<AtomicBool>,
+    clock: Clock,
+    /// J1939 filters only compare whole fields, so the exact filters are checked on receive.
+    acceptance: Acceptance,
+    counters: Arc<Counters>,
+    /// Read errors, reported as controller errors
+    monitor: BusMonitor,
+    /// Connected without claiming an address, and [`Connection::send`] fails
+    listen_only: bool,
 }
 #[derive(Debug)]
 struct API {
     id: i16,
+    /// Connected with a J1939 protocol string, rather than CAN.
+    j1939: bool,
 
     _lib: Library,
     client_connect_fn: WinSymbol<ClientConnectType>,
@@ -54,21 +65,25 @@ impl Drop for API {
 }
 impl API {
     fn new(id: &str) -> Result<API> {
+        let adapter = |e: libloading::Error| Error::Adapter(format!("{id}: {e}"));
         Ok(unsafe {
-            let lib = Library::new(id.to_string())?;
+            let lib = Library::new(id.to_string()).map_err(adapter)?;
             let client_connect: Symbol<ClientConnectType> =
-                lib.get(b"RP1210_ClientConnect\0").unwrap();
-            let send: Symbol<SendType> = lib.get(b"RP1210_SendMessage\0").unwrap();
-            let send_command: Symbol<CommandType> = lib.get(b"RP1210_SendCommand\0").unwrap();
-            let read: Symbol<ReadType> = lib.get(b"RP1210_ReadMessage\0").unwrap();
-            let get_error: Symbol<GetErrorType> = lib.get(b"RP1210_GetErrorMsg\0").unwrap();
+                lib.get(b"RP1210_ClientConnect\0").map_err(adapter)?;
+            let send: Symbol<SendType> = lib.get(b"RP1210_SendMessage\0").map_err(adapter)?;
+            let send_command: Symbol<CommandType> =
+                lib.get(b"RP1210_SendCommand\0").map_err(adapter)?;
+            let read: Symbol<ReadType> = lib.get(b"RP1210_ReadMessage\0").map_err(adapter)?;
+            let get_error: Symbol<GetErrorType> =
+                lib.get(b"RP1210_GetErrorMsg\0").map_err(adapter)?;
             let disconnect: Symbol<ClientDisconnectType> =
-                lib.get(b"RP1210_ClientDisconnect\0").unwrap();
-            let version: Symbol<VersionType> = lib.get(b"RP1210_ReadVersion\0").unwrap();
+                lib.get(b"RP1210_ClientDisconnect\0").map_err(adapter)?;
+            let version: Symbol<VersionType> = lib.get(b"RP1210_ReadVersion\0").map_err(adapter)?;
             let detailed_version: Symbol<ReadDetailedVersionType> =
-                lib.get(b"RP1210_ReadDetailedVersion\0").unwrap();
+                lib.get(b"RP1210_ReadDetailedVersion\0").map_err(adapter)?;
             API {
                 id: 0,
+                j1939: false,
                 client_connect_fn: client_connect.into_raw(),
                 send_fn: send.into_raw(),
                 read_fn: read.into_raw(),
@@ -93,16 +108,24 @@ impl API {
     }
     fn verify_return(&self, v: i16) -> Result<i16> {
         if v < 0 || v > 127 {
-            Err(anyhow!(format!("code: {} msg: {}", v, self.get_error(v)?)))
+            Err(Error::Adapter(format!(
+                "code: {} msg: {}",
+                v,
+                self.get_error(v)?
+            )))
         } else {
             Ok(v)
         }
     }
-    fn client_connect(&mut self, dev_id: i16, address: u8) -> Result<()> {
+    /// `listen_only` doesn't protect (claim) the address, because the claim is sent on the bus.
+    fn client_connect(&mut self, dev_id: i16, address: u8, listen_only: bool) -> Result<()> {
         let str = CONNECTION_STRING.read().unwrap().clone();
         let connection_string: &str = &str;
         let app_packetize: bool = *APP_PACKETIZATION.read().unwrap();
-        let c_to_print = CString::new(connection_string).expect("CString::new failed");
+        self.j1939 = connection_string.starts_with("J1939");
+        let c_to_print = CString::new(connection_string).map_err(|_| {
+            Error::InvalidArgument(format!("Invalid connection string {connection_string}"))
+        })?;
         self.id = self.verify_return(unsafe {
             (self.client_connect_fn)(
                 0,
@@ -113,7 +136,7 @@ impl API {
                 if app_packetize { 1 } else { 0 },
             )
         })?;
-        if !app_packetize {
+        if !app_packetize && !listen_only {
             self.send_command(
                 /*CMD_PROTECT_J1939_ADDRESS*/ 19,
                 vec![
@@ -125,7 +148,26 @@ impl API {
             /*CMD_ECHO_TRANSMITTED_MESSAGES*/ 16,
             vec![/*ECHO_ON*/ 1],
         )?;
-        self.send_command(/*CMD_SET_ALL_FILTERS_STATES_TO_PASS*/ 3, vec![])?;
+        self.set_filters(&[])
+    }
+
+    fn set_filters(&self, filters: &[IdMask]) -> Result<()> {
+        if filters.is_empty() {
+            self.send_command(/*CMD_SET_ALL_FILTERS_STATES_TO_PASS*/ 3, vec![])?;
+            return Ok(());
+        }
+        self.send_command(/*CMD_SET_ALL_FILTERS_STATES_TO_DISCARD*/ 14, vec![])?;
+        if self.j1939 {
+            self.send_command(
+                /*CMD_SET_MESSAGE_FILTERING_FOR_J1939*/ 4,
+                filters.iter().flat_map(j1939_filters).collect(),
+            )?;
+        } else {
+            self.send_command(
+                /*CMD_SET_MESSAGE_FILTERING_FOR_CAN*/ 5,
+                filters.iter().flat_map(can_filters).collect(),
+            )?;
+        }
         Ok(())
     }
 
@@ -149,6 +191,84 @@ impl API {
     }
 }
 
+/// RP1210 J1939 filters, 7 bytes each, accepting at least the ids matching `filter`.  A field is only compared when
+/// `filter` masks all of its bits.
+fn j1939_filters(filter: &IdMask) -> Vec<u8> {
+    const FILTER_PGN: u8 = 0x01;
+    const FILTER_PRIORITY: u8 = 0x02;
+    const FILTER_SOURCE: u8 = 0x04;
+    const FILTER_DESTINATION: u8 = 0x08;
+
+    let IdMask { id, mask } = *filter;
+    let pdu1 = (id >> 16) & 0xFF < 0xF0;
+    let mut flags = 0;
+    if mask & 0x1C00_0000 == 0x1C00_0000 {
+        flags |= FILTER_PRIORITY;
+    }
+    if mask & 0xFF == 0xFF {
+        flags |= FILTER_SOURCE;
+    }
+    // the PGN of PDU2 includes the group extension
+    if mask & 0xFF_0000 == 0xFF_0000 && (pdu1 || mask & 0xFF00 == 0xFF00) {
+        flags |= FILTER_PGN;
+        if pdu1 && mask & 0xFF00 == 0xFF00 {
+            flags |= FILTER_DESTINATION;
+        }
+    }
+    // the PGN includes the data page bits, so filter each value they may have
+    let pages = if flags & FILTER_PGN == 0 {
+        vec![0]
+    } else {
+        (0..4u32)
+            .filter(|page| ((page << 24) ^ id) & mask & 0x300_0000 == 0)
+            .collect()
+    };
+    pages
+        .into_iter()
+        .flat_map(|page| {
+            let ps = if pdu1 { 0 } else { (id >> 8) & 0xFF };
+            let pgn = ((page << 16) | ((id >> 8) & 0xFF00) | ps).to_le_bytes();
+            [
+                flags,
+                pgn[0],
+                pgn[1],
+                pgn[2],
+                ((id >> 26) & 7) as u8,
+                id as u8,
+                if flags & FILTER_DESTINATION != 0 {
+                    (id >> 8) as u8
+                } else {
+                    0
+                },
+            ]
+        })
+        .collect()
+}
+
+/// RP1210 CAN filters, 9 bytes each, for extended ids and for standard ids when `filter` fits in 11 bits.
+fn can_filters(filter: &IdMask) -> Vec<u8> {
+    const STANDARD_CAN: u8 = 0;
+    const EXTENDED_CAN: u8 = 1;
+
+    let mut v = [
+        &[EXTENDED_CAN][..],
+        &filter.mask.to_be_bytes(),
+        &filter.id.to_be_bytes(),
+    ]
+    .concat();
+    if filter.id <= 0x7FF {
+        v.extend(
+            [
+                &[STANDARD_CAN][..],
+                &(filter.mask & 0x7FF).to_be_bytes(),
+                &filter.id.to_be_bytes(),
+            ]
+            .concat(),
+        );
+    }
+    v
+}
+
 impl Drop for Rp1210 {
     fn drop(&mut self) {
         self.running.store(false, Relaxed);
@@ -157,24 +277,36 @@ impl Drop for Rp1210 {
 
 #[allow(dead_code)]
 impl Rp1210 {
-    pub fn new(id: &str, device: i16, address: u8) -> Result<Rp1210> {
-        let time_stamp_weight = time_stamp_weight(id)?;
+    /// `listen_only` is receive only: no address claim and [`Connection::send`] fails.  RP1210 has no command for a
+    /// listen only controller, so the adapter may still acknowledge frames.
+    pub fn new(id: &str, device: i16, address: u8, listen_only: bool) -> Result<Rp1210> {
+        let time_stamp_weight =
+            time_stamp_weight(id).map_err(|e| Error::Adapter(format!("{id}: {e}")))?;
 
         let mut api = API::new(id)?;
         let read = *api.read_fn;
         let get_error_fn = *api.get_error_fn;
 
         // there may be
-        api.client_connect(device, address)?;
+        api.client_connect(device, address, listen_only)?;
 
         let id = api.id;
 
         let running = Arc::new(AtomicBool::new(true));
-        let mut bus = PushBus::new("rp1210");
+        let mut bus = PushBus::new("rp1210").with_gap(Packet::new_gap);
+        let acceptance = Acceptance::default();
+        let counters = Arc::new(Counters::new(bitrate(&CONNECTION_STRING.read().unwrap())));
+        let clock = Clock::new();
+        let monitor = BusMonitor::new("rp1210");
         let rp1210 = Rp1210 {
             api,
             bus: bus.clone(),
             running: running.clone(),
+            clock,
+            acceptance: acceptance.clone(),
+            counters: counters.clone(),
+            monitor: monitor.clone(),
+            listen_only,
         };
         eprintln!(
             "RP1210 connected: {} device {} address {:02X}",
@@ -185,15 +317,19 @@ impl Rp1210 {
 
         std::thread::spawn(move || {
             let mut buf: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
-            let channel = 0; // FIXME channel.unwrap_or(0);
+            // one channel per connection, a MultiConnection numbers several
+            let channel = 0;
+            let mut anchor = None;
             while running.load(Relaxed) {
                 let size = unsafe { read(id, buf.as_mut_ptr(), PACKET_SIZE as i16, 0) };
-                if size > 0 {
+                // shorter messages don't have the header
+                if size >= 11 {
                     let data = &buf[0..size as usize];
-                    let time = u32::from_be_bytes(
-                        data[0..4].try_into().expect("unable to decode timestamp"),
-                    );
+                    let time = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
                     let time = Duration::from_secs_f64(time as f64 * time_stamp_weight);
+                    // the adapter clock started at an unknown time, so anchor it at the first frame
+                    let anchor = *anchor.get_or_insert_with(|| SystemTime::now() - time);
+                    let time = Timestamp::new(time, anchor, TimeSource::Hardware);
                     let echoed = data[4];
                     let payload = &data[11..(data.len())];
                     let priority = data[8] & 0x07;
@@ -212,7 +348,10 @@ impl Rp1210 {
                         payload,
                     )
                     .into();
-                    bus.push(Some(p));
+                    counters.received(&p);
+                    if acceptance.accepts(&p) {
+                        bus.push(Some(p));
+                    }
                 } else {
                     if size < 0 {
                         // read error
@@ -222,6 +361,10 @@ impl Rp1210 {
                         let driver =
                             format!("{} {} {}", id, device, CONNECTION_STRING.read().unwrap());
                         eprintln!("ERROR: {}: {}: {}", driver, code, msg,);
+                        let error = read_error(code, clock.now(), channel);
+                        counters.received(&error);
+                        monitor.report(&error, None);
+                        bus.push(Some(error));
                         std::thread::sleep(Duration::from_millis(250));
                     } else {
                         std::thread::sleep(Duration::from_millis(1));
@@ -277,23 +420,83 @@ impl Rp1210 {
 impl Connection for Rp1210 {
     /// Send packet and return packet echoed back from adapter
     fn send(&self, packet: &Packet) -> Result<Packet> {
+        check_listen_only(self.listen_only, "RP1210")?;
+        if !self.acceptance.accepts(packet) {
+            // the filters drop the echo
+            self.api.send(packet)?;
+            self.counters.sent(packet);
+            return Ok(Packet {
+                state: PacketState::RX {
+                    time: self.clock.now(),
+                    channel: packet.send_channel,
+                },
+                send_channel: 0,
+                ..packet.clone()
+            });
+        }
         let stream = self.bus.iter(); //_for();
-        let sent = self.api.send(packet);
-        // FIXME needs better error handling
+        self.api.send(packet)?;
+        self.counters.sent(packet);
         const DURATION: Duration = Duration::from_millis(50);
         let start = Instant::now();
-        sent.map(|_| {
-            stream
-                .take_while(|_| Instant::now().duration_since(start) < DURATION)
-                .flat_map(|o| o)
-                .find(move |p| p.id == packet.id && p.payload == packet.payload)
-                .expect("Echo failed.")
-        })
+        stream
+            .take_while(|_| Instant::now().duration_since(start) < DURATION)
+            .flatten()
+            .find(move |p| p.id == packet.id && p.payload == packet.payload)
+            .ok_or(Error::NoEcho { id: packet.id })
     }
 
     fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
         self.bus.iter()
     }
+
+    fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus.iter_until(end)
+    }
+
+    fn subscribe(&self, filter: Filter) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        Box::new(self.bus.subscribe_filtered(move |p| filter.matches(p)))
+    }
+
+    fn subscribe_until(
+        &self,
+        filter: Filter,
+        end: Instant,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus
+            .subscribe_filtered(move |p| filter.matches(p))
+            .until(end)
+    }
+
+    /// RP1210_Set_Message_Filtering_For_J1939 or _For_CAN, depending on the protocol of the connection.
+    fn set_filters(&self, filters: &[IdMask]) -> Result<()> {
+        self.api.set_filters(filters)?;
+        self.acceptance.set(filters);
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.counters.stats(self.bus.dropped()))
+    }
+
+    /// RP1210 doesn't report the controller state, so this only changes if an error report names one.
+    fn bus_state(&self) -> Option<BusState> {
+        Some(self.monitor.state())
+    }
+
+    fn bus_events(&self) -> Box<dyn Iterator<Item = Option<BusEvent>> + Send + Sync> {
+        self.monitor.events()
+    }
+}
+
+/// A failed RP1210_ReadMessage as a controller error, with the RP1210 error code as the payload.
+fn read_error(code: i16, time: Timestamp, channel: u32) -> Packet {
+    Packet::new_error(
+        [ErrorClass::Controller].into_iter().collect(),
+        &code.to_be_bytes(),
+        time,
+        channel,
+    )
 }
 
 pub static CONNECTION_STRING: LazyLock<Arc<RwLock<String>>> =
@@ -327,8 +530,18 @@ impl Display for Rp1210Factory {
 }
 impl ConnectionFactory for Rp1210Factory {
     // FIXME should be impl From<Rp1210Factory> for Rp1210
-    fn create(&self) -> Result<Box<dyn crate::connection::Connection>, anyhow::Error> {
-        Ok(Box::new(Rp1210::new(&self.id, self.device, self.address)?) as Box<dyn Connection>)
+    fn create(&self) -> Result<Box<dyn crate::connection::Connection>> {
+        Ok(
+            Box::new(Rp1210::new(&self.id, self.device, self.address, false)?)
+                as Box<dyn Connection>,
+        )
+    }
+
+    fn create_listen_only(&self) -> Result<Box<dyn crate::connection::Connection>> {
+        Ok(
+            Box::new(Rp1210::new(&self.id, self.device, self.address, true)?)
+                as Box<dyn Connection>,
+        )
     }
 
     fn command_line(&self) -> String {
@@ -356,7 +569,7 @@ impl Display for Rp1210Product {
 }
 
 /// legacy.  Should be inlined into list_all
-fn list_all_products() -> Result<Vec<Rp1210Product>> {
+fn list_all_products() -> anyhow::Result<Vec<Rp1210Product>> {
     let start = std::time::Instant::now();
     let filename = "c:\\Windows\\RP121032.ini";
     let load_from_file = ini::Ini::load_from_file(filename);
@@ -385,7 +598,7 @@ fn list_all_products() -> Result<Vec<Rp1210Product>> {
     rtn
 }
 
-fn list_devices_for_prod(id: &str) -> Result<(String, Vec<Rp1210Device>)> {
+fn list_devices_for_prod(id: &str) -> anyhow::Result<(String, Vec<Rp1210Device>)> {
     let start = std::time::Instant::now();
     let ini = ini::Ini::load_from_file(&format!("c:\\Windows\\{}.ini", id))?;
 
@@ -442,7 +655,16 @@ fn list_devices_for_prod(id: &str) -> Result<(String, Vec<Rp1210Device>)> {
     Ok((description, rtn))
 }
 
-pub fn time_stamp_weight(id: &str) -> Result<f64> {
+/// Bitrate from the `Baud=` of a connection string, in kbit/s.  `None` for `Baud=Auto`.
+fn bitrate(connection_string: &str) -> Option<u32> {
+    let baud = connection_string
+        .split(['=', ':', ';', ','])
+        .skip_while(|s| !s.eq_ignore_ascii_case("baud"))
+        .nth(1)?;
+    baud.trim().parse::<u32>().ok().map(|kbps| kbps * 1000)
+}
+
+pub fn time_stamp_weight(id: &str) -> anyhow::Result<f64> {
     let ini = ini::Ini::load_from_file(&format!("c:\\Windows\\{}.ini", id))?;
     Ok(ini
         .get_from_or::<&str>(Some("VendorInformation"), "TimeStampWeight", "1")
@@ -458,6 +680,37 @@ mod tests {
         list_all_products()?;
         Ok(())
     }
+
+    #[test]
+    fn baud() {
+        assert_eq!(Some(500_000), bitrate("J1939:Baud=500"));
+        assert_eq!(None, bitrate("J1939:Baud=Auto"));
+        assert_eq!(Some(250_000), bitrate("CAN:Baud=250,Channel=1"));
+    }
+
+    #[test]
+    fn filters() {
+        // PDU1 to F9 from any source, on any data page
+        assert_eq!(
+            (0..4)
+                .flat_map(|page| [0x09, 0, 0xEA, page, 0, 0, 0xF9])
+                .collect::<Vec<u8>>(),
+            j1939_filters(&IdMask::new(0xEAF900, 0xFFFF00))
+        );
+        // PDU2 needs the group extension
+        assert_eq!(
+            vec![0x07, 0xF1, 0xFE, 0, 3, 0, 0],
+            j1939_filters(&IdMask::exact(0x0CFEF100))
+        );
+        assert_eq!(
+            vec![0x04, 0, 0xFE, 0, 6, 0, 0],
+            j1939_filters(&IdMask::new(0x18FE0000, 0xFF00FF))
+        );
+        assert_eq!(
+            vec![1, 0, 0, 7, 0xFF, 0, 0, 7, 0xE8, 0, 0, 0, 7, 0xFF, 0, 0, 7, 0xE8],
+            can_filters(&IdMask::new(0x7E8, 0x7FF))
+        );
+    }
 }
 
 pub fn list_all() -> Result<ProtocolDescriptor, anyhow::Error> {
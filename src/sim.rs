@@ -1,43 +1,55 @@
-use anyhow::*;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use crate::error::Result;
 use std::sync::atomic::*;
 use std::thread::Builder;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use std::{iter, sync::*};
 
-use crate::connection::{Connection, ConnectionFactory, DeviceDescriptor, ProtocolDescriptor};
+#[cfg(feature = "async")]
+use crate::async_connection::{self, PacketStream, SendFuture};
+use crate::connection::{
+    Acceptance, Clock, Connection, ConnectionFactory, DeviceDescriptor, Filter, IdMask,
+    ProtocolDescriptor,
+};
+use crate::formats::{self, LogReader};
 use crate::j1939::j1939_packet::J1939Packet;
 use crate::packet::*;
 use crate::pushbus::PushBus;
+use crate::stats::{Counters, Stats};
+
+/// Nominal bitrate of the simulated bus, for [`Stats::load`].
+const BITRATE: u32 = 500_000;
 
 #[derive(Clone)]
 pub struct SimulatedConnection {
     bus: Box<PushBus<Packet>>,
     running: Arc<AtomicBool>,
+    clock: Clock,
+    acceptance: Acceptance,
+    counters: Arc<Counters>,
 }
 impl SimulatedConnection {
     pub fn new(file: Option<String>) -> Result<SimulatedConnection> {
-        let bus = PushBus::new("sim connextion");
+        let bus = PushBus::new("sim connextion").with_gap(Packet::new_gap);
         let running = Arc::new(AtomicBool::new(false));
+        let clock = Clock::new();
+        let acceptance = Acceptance::default();
+        let counters = Arc::new(Counters::new(Some(BITRATE)));
+        // fail early if the file is missing
+        let first = file.as_ref().map(formats::open).transpose()?;
         {
             let running = running.clone();
             let bus = bus.clone();
+            let acceptance = acceptance.clone();
+            let counters = counters.clone();
             Builder::new()
                 .name("simulated connection".into())
                 .spawn(move || {
-                    let packets = if let Some(file) = &file {
-                        let f = File::open(file)?;
-                        let i = iter::repeat_with(move || {
-                            BufReader::new(f.try_clone().expect("Unable to reread {file}"))
-                        })
-                        .flat_map(|reader| reader.lines())
-                        .filter_map(|line| line.ok()?.parse::<J1939Packet>().ok());
-                        Box::new(i) as Box<dyn Iterator<Item = J1939Packet>>
+                    let packets = if let (Some(file), Some(first)) = (file, first) {
+                        Box::new(replay(file, first)) as Box<dyn Iterator<Item = J1939Packet>>
                     } else {
                         let i = (0u64..).map(|n| {
                             J1939Packet::new_packet(
-                                Some(now()),
+                                Some(clock.now()),
                                 0,
                                 6,
                                 0xFEF1,
@@ -48,30 +60,77 @@ impl SimulatedConnection {
                         });
                         Box::new(i) as Box<dyn Iterator<Item = J1939Packet>>
                     };
-                    run(running, bus, packets)
+                    run(running, bus, clock, acceptance, &counters, packets)
                 })?;
         }
         Ok(SimulatedConnection {
             bus: Box::new(bus.clone()),
             running: running.clone(),
+            clock,
+            acceptance,
+            counters,
         })
     }
 }
 
+impl SimulatedConnection {
+    /// A bus with no other nodes.  Only sent packets are received.
+    pub fn silent() -> SimulatedConnection {
+        SimulatedConnection {
+            bus: Box::new(PushBus::new("silent sim connection").with_gap(Packet::new_gap)),
+            running: Arc::new(AtomicBool::new(true)),
+            clock: Clock::new(),
+            acceptance: Acceptance::default(),
+            counters: Arc::new(Counters::new(Some(BITRATE))),
+        }
+    }
+}
+
+/// The packets of `file` forever, starting with the opened `first`.  Ends if the file can't be opened again, or a
+/// pass has no packets, so an empty log doesn't spin.
+fn replay(file: String, first: Box<dyn LogReader + Send>) -> impl Iterator<Item = J1939Packet> {
+    let mut reader = Some(first);
+    let mut replayed = false;
+    iter::from_fn(move || loop {
+        match reader.as_mut()?.next() {
+            Some(Ok(p)) => {
+                replayed = true;
+                return Some(J1939Packet::from(p));
+            }
+            Some(Err(_)) => continue,
+            None if replayed => {
+                replayed = false;
+                reader = formats::open(&file).ok();
+            }
+            None => return None,
+        }
+    })
+}
+
 fn run(
     running: Arc<AtomicBool>,
     bus: PushBus<Packet>,
+    clock: Clock,
+    acceptance: Acceptance,
+    counters: &Counters,
     mut packets: impl Iterator<Item = J1939Packet>,
 ) -> Result<()> {
     running.store(true, Ordering::Relaxed);
     let mut last_time = Duration::MAX;
     while running.load(Ordering::Relaxed) {
-        let packet = packets.next().unwrap();
+        let Some(packet) = packets.next() else {
+            break;
+        };
+        // replay at the pace of the log, stamped by this connection's clock
         if let Some(time) = packet.time() {
             std::thread::sleep(time.saturating_sub(last_time));
             last_time = time;
         }
-        bus.push(Some(packet.into()));
+        let packet = Packet::from(packet).with_timestamp(clock.now());
+        counters.received(&packet);
+        if acceptance.accepts(&packet) {
+            bus.push(Some(packet));
+        }
     }
     Ok(())
 }
@@ -79,33 +138,64 @@ fn run(
 impl Connection for SimulatedConnection {
     /// Send packet and return packet echoed back from adapter
     fn send(&self, packet: &Packet) -> Result<Packet> {
-        let j1939: J1939Packet = packet.into();
-        let packet: Packet = J1939Packet::new_packet(
-            Some(now()),
-            j1939.channel().unwrap_or_default(),
-            j1939.priority(),
-            j1939.pgn(),
-            j1939.dest(),
-            j1939.source(),
-            {
-                let this = &j1939;
-                &this.payload
+        let packet = Packet {
+            state: PacketState::RX {
+                time: self.clock.now(),
+                channel: packet.send_channel,
             },
-        )
-        .into();
-        self.bus.push(Some(packet.clone()));
+            send_channel: 0,
+            ..packet.clone()
+        };
+        self.counters.sent(&packet);
+        self.counters.received(&packet);
+        if self.acceptance.accepts(&packet) {
+            self.bus.push(Some(packet.clone()));
+        }
         Ok(packet)
     }
 
     fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
         self.bus.iter()
     }
+
+    fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus.iter_until(end)
+    }
+
+    fn subscribe(&self, filter: Filter) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        Box::new(self.bus.subscribe_filtered(move |p| filter.matches(p)))
+    }
+
+    fn subscribe_until(
+        &self,
+        filter: Filter,
+        end: Instant,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus
+            .subscribe_filtered(move |p| filter.matches(p))
+            .until(end)
+    }
+
+    /// Filters in software, like an adapter with exact hardware filters.
+    fn set_filters(&self, filters: &[IdMask]) -> Result<()> {
+        self.acceptance.set(filters);
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.counters.stats(self.bus.dropped()))
+    }
 }
 
-fn now() -> Duration {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
+#[cfg(feature = "async")]
+impl async_connection::AsyncConnection for SimulatedConnection {
+    fn send<'a>(&'a self, packet: &'a Packet) -> SendFuture<'a> {
+        Box::pin(std::future::ready(Connection::send(self, packet)))
+    }
+
+    fn stream(&self) -> PacketStream {
+        Box::pin(self.bus.stream())
+    }
 }
 
 impl Drop for SimulatedConnection {
@@ -138,3 +228,120 @@ pub fn factory() -> Result<ProtocolDescriptor, anyhow::Error> {
         }],
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fd_echo() -> Result<()> {
+        let connection = SimulatedConnection::new(None)?;
+        let mut stream = connection.iter_for(Duration::from_secs(2));
+        let flags = FdFlags {
+            brs: true,
+            esi: false,
+        };
+        connection.send(&Packet::new_fd(0x18DA00F9, &[0x55; 20], flags)?)?;
+        let packet = stream.find(|p| p.id == 0x18DA00F9).unwrap();
+        assert_eq!(Some(flags), packet.fd);
+        assert_eq!([0x55; 20][..], packet.payload[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn standard_echo() -> Result<()> {
+        let connection = SimulatedConnection::new(None)?;
+        let mut stream = connection.iter_for(Duration::from_secs(2));
+        connection.send(&Packet::new(0x7E0, &[2, 1, 0]).with_id_type(IdType::Standard))?;
+        let packet = stream.find(|p| p.id == 0x7E0).unwrap();
+        assert_eq!(IdType::Standard, packet.id_type);
+        Ok(())
+    }
+
+    #[test]
+    fn replay_file() -> Result<()> {
+        let missing = std::env::temp_dir().join("can_adapter_missing.asc");
+        assert!(SimulatedConnection::new(Some(missing.display().to_string())).is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("can_adapter_replay_{}.asc", std::process::id()));
+        let file = path.display().to_string();
+        let header = "base hex  timestamps absolute\n";
+        std::fs::write(&path, header)?;
+        assert_eq!(0, replay(file.clone(), formats::open(&file)?).count());
+        std::fs::write(&path, format!("{header}   0.001000 1 100 Rx d 1 01\n"))?;
+        assert_eq!(
+            3,
+            replay(file.clone(), formats::open(&file)?).take(3).count()
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn silent() -> Result<()> {
+        let connection = SimulatedConnection::silent();
+        assert_eq!(0, connection.iter_for(Duration::from_millis(20)).count());
+        let mut stream = connection.iter_for(Duration::from_secs(2));
+        connection.send(&Packet::new(0x18FEF1F9, &[1]))?;
+        assert_eq!(Some(0x18FEF1F9), stream.next().map(|p| p.id));
+        Ok(())
+    }
+
+    #[test]
+    fn set_filters() -> Result<()> {
+        let connection = SimulatedConnection::new(None)?;
+        connection.set_filters(&[IdMask::new(0xF900, 0xFF00)])?;
+        let stream = connection.iter_for(Duration::from_millis(50));
+        let echo = connection.send(&Packet::new(0x18FEF1FA, &[1]))?;
+        assert!(echo.time().is_some());
+        connection.send(&Packet::new(0x18EAF9FA, &[2]))?;
+        // the simulated engine's 0x18FEF100 is filtered too
+        assert_eq!(vec![0x18EAF9FA], stream.map(|p| p.id).collect::<Vec<_>>());
+
+        connection.set_filters(&[])?;
+        assert!(connection
+            .iter_for(Duration::from_secs(1))
+            .any(|p| p.id == 0x18FEF100));
+        Ok(())
+    }
+
+    #[test]
+    fn echo_channel() -> Result<()> {
+        let connection = SimulatedConnection::silent();
+        let echo = connection.send(&Packet::new(0x18FEF1F9, &[1]).with_channel(1))?;
+        assert_eq!((Some(1), 0), (echo.channel(), echo.send_channel));
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<()> {
+        let connection = SimulatedConnection::silent();
+        connection.send(&Packet::new(0x18FEF1F9, &[1, 2, 3]))?;
+        connection.send(&Packet::new(0x18FEF1F9, &[4; 8]))?;
+        let stats = connection.stats().unwrap();
+        assert_eq!((2, 11), (stats.tx_frames, stats.tx_bytes));
+        assert_eq!((2, 11), (stats.rx_frames, stats.rx_bytes));
+        assert_eq!(Some(BITRATE), stats.bitrate);
+        assert!(stats.bits > 2 * 64);
+        Ok(())
+    }
+
+    #[test]
+    fn timestamps() -> Result<()> {
+        let before = std::time::SystemTime::now();
+        let connection = SimulatedConnection::new(None)?;
+        let packets: Vec<Packet> = connection
+            .iter_for(Duration::from_millis(50))
+            .take(5)
+            .collect();
+        assert!(packets.len() > 1);
+        for p in &packets {
+            let time = p.timestamp().unwrap();
+            assert_eq!(TimeSource::Host, time.source);
+            assert!(time.anchor.unwrap() >= before);
+        }
+        assert!(packets.windows(2).all(|w| w[0].time() <= w[1].time()));
+        Ok(())
+    }
+}
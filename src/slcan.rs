@@ -1,17 +1,28 @@
 use std::{
     collections::VecDeque,
+    io::ErrorKind,
     sync::{atomic::AtomicBool, Arc, Mutex},
     thread,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant},
 };
 
-use anyhow::{Error, Result};
 use serialport::{SerialPort, SerialPortInfo};
 
+#[cfg(feature = "async")]
+use crate::async_connection::{self, PacketStream, SendFuture};
 use crate::{
-    connection::{Connection, ConnectionFactory, DeviceDescriptor, ProtocolDescriptor},
-    packet::Packet,
+    bus_state::{BusEvent, BusMonitor, BusState},
+    connection::{
+        check_listen_only, Acceptance, Clock, Connection, ConnectionFactory, DeviceDescriptor,
+        Filter, IdMask, ProtocolDescriptor,
+    },
+    error::{Error, Result},
+    packet::{
+        dlc_to_len, ErrorClass, ErrorClasses, FdFlags, IdType, Packet, PacketState, Timestamp,
+        CANFD_MAX_LEN, CAN_MAX_LEN,
+    },
     pushbus::PushBus,
+    stats::{Counters, Stats},
 };
 
 type Speed = u32;
@@ -22,15 +33,25 @@ pub struct Slcan {
     bus: PushBus<Packet>,
     outbound: Arc<Mutex<VecDeque<String>>>,
     running: Arc<AtomicBool>,
-    start: SystemTime,
+    clock: Clock,
     verbose: bool,
     port: Arc<Mutex<Box<dyn SerialPort>>>,
+    /// The adapter has a single acceptance filter, so the exact filters are checked on receive.
+    acceptance: Acceptance,
+    counters: Arc<Counters>,
+    /// State from the replies to the `F` command
+    monitor: BusMonitor,
+    /// Opened with `L` rather than `O`, so the adapter never acknowledges or sends frames
+    listen_only: bool,
 }
 
 const ONE_MILLI: Duration = Duration::from_millis(1);
+/// How often the status flags are read.  SLCAN adapters don't report errors on their own.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
 
 impl Slcan {
-    pub fn new(verbose: bool, port_name: &str, speed: u32) -> Result<Slcan> {
+    /// `listen_only` opens the channel with `L`, so the adapter doesn't acknowledge frames or send error flags.
+    pub fn new(verbose: bool, port_name: &str, speed: u32, listen_only: bool) -> Result<Slcan> {
         if verbose {
             eprintln!("opening {port_name}");
         }
@@ -43,20 +64,29 @@ impl Slcan {
         port.clear(serialport::ClearBuffer::All)?;
 
         let mut slcan = Slcan {
-            bus: PushBus::new("slcan"),
+            bus: PushBus::new("slcan").with_gap(Packet::new_gap),
             outbound: Arc::new(Mutex::new(VecDeque::new())),
             running: Arc::new(AtomicBool::new(true)),
-            start: SystemTime::now(),
+            clock: Clock::new(),
             verbose,
             port: Arc::new(Mutex::new(port)),
+            acceptance: Acceptance::default(),
+            counters: Arc::new(Counters::new(Some(speed * 1000))),
+            monitor: BusMonitor::new("slcan"),
+            listen_only,
         };
 
         slcan.send_cmd(b"C")?;
         slcan.send_cmd(b"C")?;
         slcan.send_cmd(b"V")?;
-        let speed_command = &format!("S{}", CAN_SPEEDS.binary_search(&speed).unwrap());
+        let speed_index = CAN_SPEEDS.binary_search(&speed).map_err(|_| {
+            Error::InvalidArgument(format!(
+                "SLCAN speed {speed} is not one of {CAN_SPEEDS:?} kbaud"
+            ))
+        })?;
+        let speed_command = &format!("S{speed_index}");
         slcan.send_cmd(speed_command.as_bytes())?;
-        slcan.send_cmd(b"O")?;
+        slcan.send_cmd(Slcan::open_cmd(listen_only).as_bytes())?;
 
         // write outbound packets
         {
@@ -69,13 +99,18 @@ impl Slcan {
         Ok(slcan)
     }
 
-    fn now(&self) -> Duration {
-        SystemTime::now()
-            .duration_since(self.start)
-            .expect("Time went backwards")
+    /// Until the connection is dropped or the adapter is lost, which closes the bus so the iterators end.
+    fn run_can(&mut self) {
+        if let Err(e) = self.read_write() {
+            eprintln!("slcan: {e}");
+        }
+        self.running
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        self.bus.close();
+        self.monitor.close();
     }
 
-    fn run_can(&mut self) {
+    fn read_write(&mut self) -> std::io::Result<()> {
         // gross
         // copy from port to buf
         // copy from buf vecdeque
@@ -85,20 +120,21 @@ impl Slcan {
         let mut buf = [0; 1024];
         let mut q = VecDeque::new();
 
+        let mut polled = Instant::now();
         let mut port = self.port.lock().unwrap();
         while self.running.load(std::sync::atomic::Ordering::Relaxed) {
+            if polled.elapsed() >= STATUS_INTERVAL {
+                polled = Instant::now();
+                self.outbound.lock().unwrap().push_back("F".into());
+            }
             // tx
-            {
-                let mut items = self.outbound.lock().unwrap();
-
-                let packet = items.pop_front();
-                if let Some(p) = packet {
-                    port.write_all(p.as_bytes())
-                        .expect("Unable to write to serialport.");
-                    port.write_all(b"\r")
-                        .expect("Unable to write CR to serialport.");
-                    port.flush().expect("Unable to flush serialport.");
-                }
+            let line = self.outbound.lock().unwrap().front().cloned();
+            if let Some(line) = line {
+                port.write_all(line.as_bytes())?;
+                port.write_all(b"\r")?;
+                port.flush()?;
+                // written, so senders waiting for an empty queue can go on
+                self.outbound.lock().unwrap().pop_front();
             }
             // rx
             // not spinning, because port.read() is blocking
@@ -110,8 +146,9 @@ impl Slcan {
                             let index = q.iter().take_while(|u| **u != b'\r').count();
                             if index < q.len() {
                                 let vec: Vec<u8> = q.drain(..index).collect();
-                                let line: String = String::from_utf8(vec).expect("Invalid UTF8");
-                                self.bus.push(self.parse_result(line).ok());
+                                let line = String::from_utf8_lossy(&vec).to_string();
+                                let packet = self.parse_result(line).ok();
+                                self.bus.push(packet.and_then(|p| self.received(p)));
                                 q.pop_front(); // drop \r
                             } else {
                                 break;
@@ -120,16 +157,21 @@ impl Slcan {
                         }
                     }
                 }
-                Err(_error) => {
-                    //   eprintln!("{_error}");
-                }
+                // the read timeout
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
             }
         }
+        Ok(())
     }
 
     fn send_cmd(&mut self, cmd: &[u8]) -> Result<()> {
         if self.verbose {
-            eprintln!("sending cmd {}", String::from_utf8(cmd.into())?);
+            eprintln!("sending cmd {}", String::from_utf8_lossy(cmd));
         }
         let mut port = self.port.lock().unwrap();
         port.write_all(cmd)?;
@@ -138,26 +180,128 @@ impl Slcan {
         Ok(())
     }
 
-    // 0CF00A00 8 FF FF 00 FE FF FF 00 00
+    /// Count a received packet and track the status replies.  Returns the packet if subscribers should see it.  Most
+    /// replies to the status poll report nothing, so those are only passed on when the state changed.
+    fn received(&self, packet: Packet) -> Option<Packet> {
+        self.counters.received(&packet);
+        if let Some(errors) = packet.errors() {
+            if !self.monitor.report(&packet, None) && errors.is_empty() {
+                return None;
+            }
+        }
+        self.acceptance.accepts(&packet).then_some(packet)
+    }
+
+    /// Echo a sent packet like the other connections, stamped when it left the queue.
+    fn echo(&self, packet: &Packet) -> Packet {
+        self.counters.sent(packet);
+        let echo = Packet {
+            state: PacketState::RX {
+                time: self.clock.now(),
+                channel: packet.send_channel,
+            },
+            send_channel: 0,
+            ..packet.clone()
+        };
+        self.counters.received(&echo);
+        if self.acceptance.accepts(&echo) {
+            self.bus.push(Some(echo.clone()));
+        }
+        echo
+    }
+
+    /// Queue lines and wait until they are written.  The port belongs to the receive thread once the channel is open.
+    fn write_lines(&self, lines: &[String]) -> Result<()> {
+        self.outbound.lock().unwrap().extend(lines.iter().cloned());
+        while !self.outbound.lock().unwrap().is_empty() {
+            self.check_open()?;
+            thread::sleep(ONE_MILLI);
+        }
+        Ok(())
+    }
+
+    /// The command that opens the channel.
+    fn open_cmd(listen_only: bool) -> &'static str {
+        if listen_only {
+            "L"
+        } else {
+            "O"
+        }
+    }
+
+    fn check_open(&self) -> Result<()> {
+        if self.running.load(std::sync::atomic::Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(Error::Disconnected("SLCAN adapter".into()))
+        }
+    }
+
     fn parse_result(&self, buf: String) -> Result<Packet> {
-        const SIZE: usize = 9;
-        let now = self.now();
-        let len = buf.len();
-        // {T}{4 * 2 digit hex bytes}{1 digit length}{2 digit hex payload}
-        if len < SIZE || !len.is_multiple_of(2) {
-            let message = format!("Invalid buf [{buf}] len:{len} {}", len % 2);
-            if self.verbose {
-                eprintln!("{message}");
+        let result = parse(&buf, self.clock.now());
+        if self.verbose {
+            if let Err(e) = &result {
+                eprintln!("{e}");
             }
-            return Err(Error::msg(message));
         }
-        let id = u32::from_str_radix(&buf[1..SIZE], 16)?;
-        let payload: Result<Vec<u8>, _> = ((1 + SIZE)..len)
-            .step_by(2)
-            .map(|i| u8::from_str_radix(&buf[i..i + 2], 16))
-            .collect();
-        Ok(Packet::new_rx(id, &payload?, now, 0))
+        result
+    }
+}
+
+// T0CF00A008FFFF00FEFFFF0000 extended id
+// t7E88024100FFFFFFFFFF standard id
+// D/d (FD) and B/b (FD with bit rate switch) use the same layouts with a hex DLC.
+// r7DF8 / R18EAFF008 remote frames
+// F24 status flags
+fn parse(buf: &str, now: Timestamp) -> Result<Packet> {
+    let invalid = || Error::Protocol(format!("Invalid buf [{buf}] len:{}", buf.len()));
+    let hex = |digits: &str| u32::from_str_radix(digits, 16).map_err(|_| invalid());
+    let len = buf.len();
+    let cmd = buf.bytes().next().unwrap_or_default();
+    if !buf.is_ascii() {
+        return Err(invalid());
+    }
+    if cmd == b'F' && len == 3 {
+        return Ok(Packet::new_status(
+            status_flags(hex(&buf[1..])? as u8),
+            now,
+            0,
+        ));
     }
+    let (id_type, size) = if cmd.is_ascii_uppercase() {
+        (IdType::Extended, 9)
+    } else {
+        (IdType::Standard, 4)
+    };
+    // {T}{3 or 8 hex digit id}{1 digit length}{2 digit hex payload}
+    if len <= size || !(len - size - 1).is_multiple_of(2) {
+        return Err(invalid());
+    }
+    let id = hex(&buf[1..size])?;
+    if cmd.eq_ignore_ascii_case(&b'R') {
+        let dlc = hex(&buf[size..size + 1])? as u8;
+        return Ok(Packet::new_remote_rx(id, dlc, now, 0).with_id_type(id_type));
+    }
+    let payload: Result<Vec<u8>> = ((1 + size)..len)
+        .step_by(2)
+        .map(|i| hex(&buf[i..i + 2]).map(|b| b as u8))
+        .collect();
+    let packet = match cmd.to_ascii_uppercase() {
+        b'T' => Packet::new_rx(id, &payload?, now, 0),
+        b'D' => Packet::new_rx_fd(id, &payload?, FdFlags::default(), now, 0)?,
+        b'B' => Packet::new_rx_fd(
+            id,
+            &payload?,
+            FdFlags {
+                brs: true,
+                esi: false,
+            },
+            now,
+            0,
+        )?,
+        _ => return Err(Error::Protocol(format!("Unknown frame [{buf}]"))),
+    };
+    Ok(packet.with_id_type(id_type))
 }
 
 impl Drop for Slcan {
@@ -166,35 +310,170 @@ impl Drop for Slcan {
             self.running
                 .store(false, std::sync::atomic::Ordering::Relaxed);
             self.bus.close();
+            self.monitor.close();
             // give Windows time to clean up device
             //            thread::sleep(TIMEOUT * 2);
         }
     }
 }
 
-fn unparse(p: &Packet) -> String {
+/// SJA1000 single filter acceptance code and mask for the `M` and `m` commands, where set mask bits are don't care.
+/// The one filter compares only the bits that every filter compares with the same value.  It is programmed for either
+/// standard or extended frames, so all frames are accepted unless every filter only matches one kind.
+fn acceptance_registers(filters: &[IdMask]) -> (u32, u32) {
+    const ACCEPT_ALL: (u32, u32) = (0, 0xFFFF_FFFF);
+    let Some(first) = filters.first() else {
+        return ACCEPT_ALL;
+    };
+    // Requiring a bit above the 11 of a standard id only matches extended frames.  Requiring all of them to be 0 is
+    // taken as standard, since extended ids that small aren't used.
+    const HIGH: u32 = 0x1FFF_F800;
+    let extended = |f: &IdMask| f.id & f.mask & HIGH != 0;
+    let standard = |f: &IdMask| f.mask & HIGH == HIGH && f.id & HIGH == 0;
+    let (bits, shift) = if filters.iter().all(standard) {
+        (0x7FF, 21)
+    } else if filters.iter().all(extended) {
+        (0x1FFF_FFFF, 3)
+    } else {
+        return ACCEPT_ALL;
+    };
+    let mask = filters
+        .iter()
+        .fold(bits, |mask, f| mask & f.mask & !(f.id ^ first.id));
+    ((first.id & mask) << shift, !(mask << shift))
+}
+
+/// Decode the status flags returned by the `F` command.
+fn status_flags(flags: u8) -> ErrorClasses {
+    [
+        (0x01, ErrorClass::Overrun), // RX FIFO full
+        (0x02, ErrorClass::Overrun), // TX FIFO full
+        (0x04, ErrorClass::ErrorWarning),
+        (0x08, ErrorClass::Overrun),
+        (0x20, ErrorClass::ErrorPassive),
+        (0x40, ErrorClass::ArbitrationLost),
+        (0x80, ErrorClass::BusError),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| flags & bit != 0)
+    .map(|(_, class)| class)
+    .collect()
+}
+
+/// The line to send `p`.  Fails for an id or payload length the frame can't carry, rather than sending a different
+/// frame.
+fn unparse(p: &Packet) -> Result<String> {
+    let max_id = match p.id_type {
+        IdType::Standard => 0x7FF,
+        IdType::Extended => 0x1FFF_FFFF,
+    };
+    if p.id > max_id {
+        return Err(Error::InvalidArgument(format!("Invalid id {:X}", p.id)));
+    }
+    let len = p.payload.len();
+    let valid_len = match p.fd {
+        None => len <= CAN_MAX_LEN,
+        Some(_) => len <= CANFD_MAX_LEN && dlc_to_len(p.dlc()) == len,
+    };
+    if !valid_len {
+        return Err(Error::InvalidArgument(format!(
+            "Invalid payload length {len} for {:X}",
+            p.id
+        )));
+    }
     let payload = p.payload_str_nospace();
-    format!("T{:08X}{}{}", p.id, payload.len() / 2, payload)
+    let cmd = match p.fd {
+        None => 'T',
+        Some(FdFlags { brs: false, .. }) => 'D',
+        Some(FdFlags { brs: true, .. }) => 'B',
+    };
+    Ok(match p.id_type {
+        IdType::Extended => format!("{cmd}{:08X}{:X}{}", p.id, p.dlc(), payload),
+        IdType::Standard => format!(
+            "{}{:03X}{:X}{}",
+            cmd.to_ascii_lowercase(),
+            p.id,
+            p.dlc(),
+            payload
+        ),
+    })
 }
 
 impl Connection for Slcan {
-    fn send(&self, packet: &Packet) -> anyhow::Result<Packet> {
-        // send packet
-        self.outbound.lock().unwrap().push_back(unparse(packet));
-
+    fn send(&self, packet: &Packet) -> Result<Packet> {
+        check_listen_only(self.listen_only, "SLCAN")?;
         // SLCAN does not support echo, so wait until outbound is empty;
-        while !self.outbound.lock().unwrap().is_empty() {
-            thread::sleep(ONE_MILLI);
-        }
-
-        self.bus.push(Some(packet.clone()));
-        Ok(packet.clone())
+        self.write_lines(&[unparse(packet)?])?;
+        Ok(self.echo(packet))
     }
 
     fn iter(&self) -> Box<dyn Iterator<Item = Option<crate::packet::Packet>> + Send + Sync> {
         self.bus.iter()
     }
+
+    fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus.iter_until(end)
+    }
+
+    fn subscribe(&self, filter: Filter) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        Box::new(self.bus.subscribe_filtered(move |p| filter.matches(p)))
+    }
+
+    fn subscribe_until(
+        &self,
+        filter: Filter,
+        end: Instant,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus
+            .subscribe_filtered(move |p| filter.matches(p))
+            .until(end)
+    }
+
+    /// Closes the channel to set the acceptance code and mask, then reopens it.
+    fn set_filters(&self, filters: &[IdMask]) -> Result<()> {
+        let (code, mask) = acceptance_registers(filters);
+        self.acceptance.set(filters);
+        self.write_lines(&[
+            "C".into(),
+            format!("M{code:08X}"),
+            format!("m{mask:08X}"),
+            Slcan::open_cmd(self.listen_only).into(),
+        ])
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.counters.stats(self.bus.dropped()))
+    }
+
+    /// From the status flags, read every second.  SLCAN doesn't report bus off or the error counters.
+    fn bus_state(&self) -> Option<BusState> {
+        Some(self.monitor.state())
+    }
+
+    fn bus_events(&self) -> Box<dyn Iterator<Item = Option<BusEvent>> + Send + Sync> {
+        self.monitor.events()
+    }
+}
+#[cfg(feature = "async")]
+impl async_connection::AsyncConnection for Slcan {
+    fn send<'a>(&'a self, packet: &'a Packet) -> SendFuture<'a> {
+        Box::pin(async move {
+            check_listen_only(self.listen_only, "SLCAN")?;
+            let line = unparse(packet)?;
+            self.outbound.lock().unwrap().push_back(line);
+            while !self.outbound.lock().unwrap().is_empty() {
+                self.check_open()?;
+                tokio::time::sleep(ONE_MILLI).await;
+            }
+            Ok(self.echo(packet))
+        })
+    }
+
+    fn stream(&self) -> PacketStream {
+        Box::pin(self.bus.stream())
+    }
 }
+
 struct SclanFactory {
     port_info: SerialPortInfo,
     speed: u32,
@@ -202,7 +481,12 @@ struct SclanFactory {
 
 impl ConnectionFactory for SclanFactory {
     fn create(&self) -> Result<Box<dyn Connection>> {
-        Slcan::new(false, self.port_info.port_name.as_str(), self.speed)
+        Slcan::new(false, self.port_info.port_name.as_str(), self.speed, false)
+            .map(|c| Box::new(c) as Box<dyn Connection>)
+    }
+
+    fn create_listen_only(&self) -> Result<Box<dyn Connection>> {
+        Slcan::new(false, self.port_info.port_name.as_str(), self.speed, true)
             .map(|c| Box::new(c) as Box<dyn Connection>)
     }
 
@@ -240,3 +524,96 @@ pub fn list_all() -> Result<ProtocolDescriptor> {
         instructions_url: "http://fixme".to_string(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let now = Timestamp::from(Duration::from_millis(10));
+        let p = parse("T18FEF1008FFFF00FEFFFF0000", now)?;
+        assert_eq!(0x18FEF100, p.id);
+        assert_eq!(IdType::Extended, p.id_type);
+        assert_eq!([0xFF, 0xFF, 0, 0xFE, 0xFF, 0xFF, 0, 0][..], p.payload[..]);
+        assert_eq!("T18FEF1008FFFF00FEFFFF0000", unparse(&p)?);
+
+        let p = parse("t7E8302410C", now)?;
+        assert_eq!(0x7E8, p.id);
+        assert_eq!(IdType::Standard, p.id_type);
+        assert_eq!([2, 0x41, 0x0C][..], p.payload[..]);
+        assert_eq!("t7E8302410C", unparse(&p)?);
+
+        let p = parse(&format!("b7E89{}", "AA".repeat(12)), now)?;
+        assert_eq!(IdType::Standard, p.id_type);
+        assert!(p.fd.unwrap().brs);
+        assert_eq!(12, p.payload.len());
+
+        let p = parse("r7DF8", now)?;
+        assert!(p.is_remote());
+        assert_eq!(0x7DF, p.id);
+        assert_eq!(IdType::Standard, p.id_type);
+        let p = parse("R18EAFF003", now)?;
+        assert!(p.is_remote());
+        assert_eq!(0x18EAFF00, p.id);
+
+        let p = parse("F24", now)?;
+        assert_eq!("ErrorWarning ErrorPassive", p.errors().unwrap().to_string());
+        let state = BusState::default().after(&p);
+        assert_eq!(crate::bus_state::ErrorState::Passive, state.error_state);
+        let p = parse("F00", now)?;
+        assert_eq!(BusState::default(), state.after(&p));
+
+        assert!(parse("z", now).is_err());
+        // malformed lines from the adapter are errors, not panics
+        assert!(matches!(parse("t7E83024G0C", now), Err(Error::Protocol(_))));
+        assert!(matches!(parse("t7E8é", now), Err(Error::Protocol(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        let standard = Packet::new(0x1234, &[1, 2]).with_id_type(IdType::Standard);
+        assert!(matches!(unparse(&standard), Err(Error::InvalidArgument(_))));
+        assert!(unparse(&Packet::new(0x2000_0000, &[1])).is_err());
+        assert!(unparse(&Packet::new(0x18FEF100, &[0; 9])).is_err());
+        let mut fd = Packet::new_fd(0x18DA00F9, &[0; 12], FdFlags::default()).unwrap();
+        fd.payload = crate::packet::Payload::new(&[0; 11]);
+        assert!(unparse(&fd).is_err());
+    }
+
+    #[test]
+    fn listen_only() {
+        assert_eq!("L", Slcan::open_cmd(true));
+        assert_eq!("O", Slcan::open_cmd(false));
+    }
+
+    #[test]
+    fn acceptance() {
+        assert_eq!((0, 0xFFFF_FFFF), acceptance_registers(&[]));
+        // J1939 to F9 from any source
+        assert_eq!(
+            (0xF900 << 3, !(0xFF00 << 3)),
+            acceptance_registers(&[IdMask::new(0x18EAF900, 0xFF00)])
+        );
+        // 7E8 and 7E9 share all but the lowest bit
+        assert_eq!(
+            (0x7E8 << 21, !(0x7FE << 21)),
+            acceptance_registers(&[IdMask::exact(0x7E8), IdMask::exact(0x7E9)])
+        );
+        // standard and extended together
+        assert_eq!(
+            (0, 0xFFFF_FFFF),
+            acceptance_registers(&[IdMask::exact(0x7E8), IdMask::new(0x18EAF900, 0xFF00)])
+        );
+        // J1939 from source address 0 also matches extended frames, and so does 7E8 without the high bits
+        assert_eq!(
+            (0, 0xFFFF_FFFF),
+            acceptance_registers(&[IdMask::new(0x00, 0xFF)])
+        );
+        assert_eq!(
+            (0, 0xFFFF_FFFF),
+            acceptance_registers(&[IdMask::new(0x7E8, 0x7FF)])
+        );
+    }
+}
@@ -1,23 +1,38 @@
-use anyhow::{Context, Result};
 use color_print::cformat;
-use socketcan::{enumerate, CanFrame, Frame, Socket};
+use socketcan::{
+    enumerate, CanAnyFrame, CanCtrlMode, CanFdFrame, CanFilter, CanFrame, CanInterface,
+    CanTimestamps, Frame, Socket, SOF_TIMESTAMPING_OPT_CMSG, SOF_TIMESTAMPING_RAW_HARDWARE,
+    SOF_TIMESTAMPING_RX_HARDWARE,
+};
 
-use socketcan::{CanSocket, SocketOptions};
+use socketcan::{CanFdSocket, EmbeddedFrame, ExtendedId, Id, SocketOptions, StandardId};
 use std::{
-    io::Write,
+    io::{ErrorKind, Write},
     option::Option,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
     },
     thread,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+#[cfg(feature = "async")]
+use crate::async_connection::{self, PacketStream, SendFuture};
+#[cfg(feature = "async")]
+use futures_util::StreamExt;
+
 use crate::{
-    connection::{Connection, ConnectionFactory, DeviceDescriptor, ProtocolDescriptor},
-    packet::Packet,
+    bus_state::{BusEvent, BusMonitor, BusState},
+    connection::{
+        check_listen_only, Acceptance, Clock, Connection, ConnectionFactory, DeviceDescriptor,
+        Filter, IdMask, ProtocolDescriptor,
+    },
+    error::{Error, Result},
+    formats::socketcan::{bus_state, error_classes},
+    packet::{FdFlags, IdType, Packet, PacketState, TimeSource, Timestamp},
     pushbus::PushBus,
+    stats::{Counters, Stats},
 };
 
 /// ```sh
@@ -31,20 +46,37 @@ use crate::{
 /// ```
 #[derive(Clone)]
 pub struct SocketCanConnection {
-    socket: Arc<Mutex<CanSocket>>,
+    socket: Arc<Mutex<CanFdSocket>>,
     bus: PushBus<Packet>,
     running: Arc<AtomicBool>,
-    start: SystemTime,
+    clock: Clock,
+    /// The kernel filters, this only tells [`Connection::send`] whether to expect the echo.
+    acceptance: Acceptance,
+    counters: Arc<Counters>,
+    monitor: BusMonitor,
+    listen_only: bool,
+    /// Restores the interface when the last clone is dropped
+    _mode: Option<Arc<ListenOnlyMode>>,
 }
 
 impl SocketCanConnection {
-    // FIXME add speed support.  Currently requires root access to configure network stack!
-    pub fn new(str: &str, _speed: u64) -> Result<SocketCanConnection, anyhow::Error> {
+    // FIXME add speed support.  Currently requires root access to configure network stack!  `speed` is only used for
+    // the bus load.
+    /// `listen_only` puts the interface in `CAN_CTRLMODE_LISTENONLY`, which needs root, and [`Connection::send`]
+    /// fails.  Fails if the mode can't be set, unless the interface has no controller, like `vcan`.
+    pub fn new(str: &str, speed: u64, listen_only: bool) -> Result<SocketCanConnection> {
+        // before the socket is opened, because changing the mode takes the interface down
+        let mode = ListenOnlyMode::new(str, listen_only)?;
         let socket_can_connection = SocketCanConnection {
-            socket: Arc::new(Mutex::new(CanSocket::open(str)?)),
-            bus: PushBus::new("Socket CAN"),
+            socket: Arc::new(Mutex::new(CanFdSocket::open(str)?)),
+            bus: PushBus::new("Socket CAN").with_gap(Packet::new_gap),
             running: Arc::new(AtomicBool::new(false)),
-            start: SystemTime::now(),
+            clock: Clock::new(),
+            acceptance: Acceptance::default(),
+            counters: Arc::new(Counters::new(u32::try_from(speed).ok())),
+            monitor: BusMonitor::new("Socket CAN"),
+            listen_only,
+            _mode: mode,
         };
 
         let mut scc = socket_can_connection.clone();
@@ -55,80 +87,408 @@ impl SocketCanConnection {
             can_socket.set_nonblocking(false)?;
             can_socket.set_read_timeout(Duration::from_millis(50))?;
             can_socket.set_write_timeout(Duration::from_millis(500))?;
+            configure_receive(&*can_socket, str, can_socket.has_hw_timestamps())?;
         }
         thread::spawn(move || scc.run());
         Ok(socket_can_connection)
     }
+
+    /// Until the connection is dropped or the interface fails, such as going down, which closes the bus so the
+    /// iterators end.
     fn run(&mut self) {
         self.running.store(true, Ordering::Relaxed);
+        let mut hardware_anchor = None;
         while self.running.load(Ordering::Relaxed) {
-            let read_raw_frame = self.socket.lock().unwrap().read_raw_frame();
-            let p = if let Ok(frame) = read_raw_frame {
-                let len = frame.can_dlc as usize;
-                if 0xFFFF & (frame.can_id >> 8) == 0xFEEC {
-                    eprintln!("{:X} {:X?}", frame.can_id, frame.data)
+            let read = self.socket.lock().unwrap().read_frame_with_timestamps();
+            let p = match read {
+                Ok((frame, timestamps)) => {
+                    let reported = match &frame {
+                        CanAnyFrame::Error(frame) => bus_state(frame.error_bits(), frame.data()),
+                        _ => None,
+                    };
+                    let p = packet(
+                        frame,
+                        timestamp(&self.clock, &timestamps, &mut hardware_anchor),
+                    );
+                    if let Some(p) = p.as_ref().filter(|p| p.errors().is_some()) {
+                        self.monitor.report(p, reported);
+                    }
+                    p
+                }
+                // the read timeout
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+                    ) =>
+                {
+                    None
+                }
+                Err(e) => {
+                    eprintln!("socketcan: {e}");
+                    break;
                 }
-                Some(Packet::new_rx(
-                    frame.can_id & 0x7FFFFFFF,
-                    &frame.data[..len],
-                    self.now(),
-                    0,
-                ))
-            } else {
-                const ONE_MILLI: Duration = Duration::from_millis(1);
-                std::thread::sleep(ONE_MILLI);
-                None
             };
+            if let Some(p) = &p {
+                self.counters.received(p);
+            }
             self.bus.push(p);
         }
+        self.running.store(false, Ordering::Relaxed);
+        self.bus.close();
+        self.monitor.close();
+    }
+}
+
+/// Accept error frames and enable receive timestamps.
+fn configure_receive(socket: &impl SocketOptions, name: &str, hw_timestamps: bool) -> Result<()> {
+    socket.set_error_filter_accept_all()?;
+    // prefer the NIC clock, then the kernel receive time, then the time the frame was read
+    if let Err(e) = socket.set_recv_timestamp(true) {
+        eprintln!("{name}: kernel timestamps unavailable, using host time: {e}");
+    }
+    if hw_timestamps {
+        if let Err(e) = socket.set_timestamping(
+            SOF_TIMESTAMPING_RX_HARDWARE
+                | SOF_TIMESTAMPING_RAW_HARDWARE
+                | SOF_TIMESTAMPING_OPT_CMSG,
+        ) {
+            eprintln!("{name}: hardware timestamps unavailable: {e}");
+        }
     }
-    fn now(&self) -> Duration {
-        SystemTime::now()
-            .duration_since(self.start)
-            .expect("Time went backwards")
+    Ok(())
+}
+
+fn packet(frame: CanAnyFrame, time: Timestamp) -> Option<Packet> {
+    match frame {
+        CanAnyFrame::Normal(frame) => Some(
+            Packet::new_rx(frame.raw_id(), frame.data(), time, 0).with_id_type(id_type(&frame)),
+        ),
+        CanAnyFrame::Remote(frame) => Some(
+            Packet::new_remote_rx(frame.raw_id(), frame.dlc() as u8, time, 0)
+                .with_id_type(id_type(&frame)),
+        ),
+        CanAnyFrame::Error(frame) => Some(Packet::new_error(
+            error_classes(frame.error_bits(), frame.data()),
+            frame.data(),
+            time,
+            0,
+        )),
+        CanAnyFrame::Fd(frame) => {
+            let flags = FdFlags {
+                brs: frame.is_brs(),
+                esi: frame.is_esi(),
+            };
+            Packet::new_rx_fd(frame.raw_id(), frame.data(), flags, time, 0)
+                .map(|p| p.with_id_type(id_type(&frame)))
+                .ok()
+        }
+    }
+}
+
+fn frame(packet: &Packet) -> Result<CanAnyFrame> {
+    let id = match packet.id_type {
+        IdType::Standard => u16::try_from(packet.id)
+            .ok()
+            .and_then(StandardId::new)
+            .map(Id::from),
+        IdType::Extended => ExtendedId::new(packet.id).map(Id::from),
+    }
+    .ok_or_else(|| Error::InvalidArgument(format!("Invalid id {:X}", packet.id)))?;
+    let invalid = || {
+        Error::InvalidArgument(format!(
+            "Invalid payload length {} for {:X}",
+            packet.payload.len(),
+            packet.id
+        ))
+    };
+    Ok(if let Some(fd) = packet.fd {
+        let mut frame = CanFdFrame::new(id, &packet.payload).ok_or_else(invalid)?;
+        frame.set_brs(fd.brs);
+        frame.set_esi(fd.esi);
+        frame.into()
+    } else {
+        CanFrame::new(id, &packet.payload)
+            .ok_or_else(invalid)?
+            .into()
+    })
+}
+
+/// Best timestamp for a frame: the NIC clock, then the kernel receive time, then now.  The NIC clock has its own epoch,
+/// so it is anchored by the kernel time of the first frame and then follows the NIC.
+fn timestamp(
+    clock: &Clock,
+    timestamps: &CanTimestamps,
+    hardware_anchor: &mut Option<SystemTime>,
+) -> Timestamp {
+    let kernel = timestamps.socket.or(timestamps.sw);
+    if let Some(hw) = timestamps.hw {
+        let anchor = *hardware_anchor.get_or_insert_with(|| {
+            kernel
+                .unwrap_or_else(SystemTime::now)
+                .checked_sub(hw)
+                .unwrap_or(UNIX_EPOCH)
+        });
+        return clock.at(anchor + hw, TimeSource::Hardware);
+    }
+    match kernel {
+        Some(wall) => clock.at(wall, TimeSource::Kernel),
+        None => clock.now(),
+    }
+}
+
+fn id_type(frame: &impl EmbeddedFrame) -> IdType {
+    if frame.is_extended() {
+        IdType::Extended
+    } else {
+        IdType::Standard
+    }
+}
+
+/// `CAN_CTRLMODE_LISTENONLY` on an interface, so the controller doesn't acknowledge frames or send error flags.  The
+/// mode is restored when dropped.  The interface is taken down to change it, if it is up.
+struct ListenOnlyMode {
+    interface: CanInterface,
+    was_up: bool,
+}
+
+impl ListenOnlyMode {
+    /// Set the mode if `listen_only`, so frames are never acknowledged.
+    fn new(name: &str, listen_only: bool) -> Result<Option<Arc<ListenOnlyMode>>> {
+        if !listen_only {
+            return Ok(None);
+        }
+        Self::set(name)
+            .map(|mode| mode.map(Arc::new))
+            .map_err(|e| Error::Adapter(format!("{name}: unable to set listen only mode: {e}")))
+    }
+
+    /// `None` if the interface is already listen only, or has no controller, like `vcan`.
+    fn set(name: &str) -> anyhow::Result<Option<ListenOnlyMode>> {
+        let interface = CanInterface::open(name)?;
+        let details = interface.details()?;
+        match details.can.ctrl_mode {
+            // no controller, so nothing to acknowledge frames
+            None => Ok(None),
+            Some(modes) if modes.has_mode(CanCtrlMode::ListenOnly) => Ok(None),
+            Some(_) => {
+                let mode = ListenOnlyMode {
+                    interface,
+                    was_up: details.is_up,
+                };
+                mode.change(true)?;
+                Ok(Some(mode))
+            }
+        }
+    }
+
+    fn change(&self, on: bool) -> anyhow::Result<()> {
+        if self.was_up {
+            self.interface.bring_down()?;
+        }
+        self.interface.set_ctrlmode(CanCtrlMode::ListenOnly, on)?;
+        if self.was_up {
+            self.interface.bring_up()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ListenOnlyMode {
+    fn drop(&mut self) {
+        if let Err(e) = self.change(false) {
+            eprintln!("unable to restore from listen only mode: {e}");
+        }
     }
 }
 
 impl Connection for SocketCanConnection {
     fn send(&self, packet: &Packet) -> Result<Packet> {
-        // listen for echo
-        let mut i = self.iter_for(Duration::from_millis(1000));
+        check_listen_only(self.listen_only, "SocketCAN")?;
+        // listen for echo, unless the filters drop it
+        let echo = self
+            .acceptance
+            .accepts(packet)
+            .then(|| self.iter_for(Duration::from_millis(1000)));
 
         // send packet
         {
-            let frame =
-                CanFrame::from_raw_id(packet.id, &packet.payload).expect("Invalid data packet");
+            let frame = frame(packet)?;
             let mut can_socket = self.socket.lock().unwrap();
             can_socket.write_frame(&frame)?;
             can_socket.flush()?;
         }
-        self.bus.push(Some(Packet::new(packet.id, &packet.payload)));
-
-        i.find(
-            move |p| p.id == packet.id, /*&& p.data() == packet.data()*/
-        )
-        .context("no echo")
+        self.counters.sent(packet);
+        match echo {
+            // the echo from recv_own_msgs carries the receive timestamp
+            Some(mut i) => i
+                .find(
+                    move |p| p.id == packet.id, /*&& p.data() == packet.data()*/
+                )
+                .ok_or(Error::NoEcho { id: packet.id }),
+            None => Ok(Packet {
+                state: PacketState::RX {
+                    time: self.clock.now(),
+                    channel: packet.send_channel,
+                },
+                send_channel: 0,
+                ..packet.clone()
+            }),
+        }
     }
 
     fn iter(&self) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
         self.bus.iter()
     }
+
+    fn iter_until(&self, end: Instant) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus.iter_until(end)
+    }
+
+    fn subscribe(&self, filter: Filter) -> Box<dyn Iterator<Item = Option<Packet>> + Send + Sync> {
+        Box::new(self.bus.subscribe_filtered(move |p| filter.matches(p)))
+    }
+
+    fn subscribe_until(
+        &self,
+        filter: Filter,
+        end: Instant,
+    ) -> Box<dyn Iterator<Item = Packet> + Send + Sync> {
+        self.bus
+            .subscribe_filtered(move |p| filter.matches(p))
+            .until(end)
+    }
+
+    /// `CAN_RAW_FILTER`.  The masks don't include the extended frame flag, so they match standard and extended ids.
+    fn set_filters(&self, filters: &[IdMask]) -> Result<()> {
+        let socket = self.socket.lock().unwrap();
+        if filters.is_empty() {
+            socket.set_filter_accept_all()?;
+        } else {
+            let filters: Vec<CanFilter> = filters
+                .iter()
+                .map(|f| CanFilter::new(f.id, f.mask))
+                .collect();
+            socket.set_filters(&filters)?;
+        }
+        self.acceptance.set(filters);
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.counters.stats(self.bus.dropped()))
+    }
+
+    /// From the error frames.  Kernels that set `CAN_ERR_CNT` also report the error counters.
+    fn bus_state(&self) -> Option<BusState> {
+        Some(self.monitor.state())
+    }
+
+    fn bus_events(&self) -> Box<dyn Iterator<Item = Option<BusEvent>> + Send + Sync> {
+        self.monitor.events()
+    }
 }
 impl Drop for SocketCanConnection {
     fn drop(&mut self) {
         self.running.store(false, Ordering::Relaxed);
         self.bus.close();
+        self.monitor.close();
         //let _ = self.thread.take().unwrap().join();
     }
 }
 
+/// Epoll driven SocketCAN for tokio.  Each stream reads its own socket, so no thread is used and subscribers never wait
+/// on each other.  Must be created and used from within a tokio runtime.
+#[cfg(feature = "async")]
+#[derive(Clone)]
+pub struct AsyncSocketCanConnection {
+    interface: String,
+    socket: Arc<socketcan::tokio::CanFdSocket>,
+    clock: Clock,
+    listen_only: bool,
+    /// Restores the interface when the last clone is dropped
+    _mode: Option<Arc<ListenOnlyMode>>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncSocketCanConnection {
+    /// `listen_only` as for [`SocketCanConnection::new`].
+    pub fn new(interface: &str, listen_only: bool) -> Result<AsyncSocketCanConnection> {
+        let mode = ListenOnlyMode::new(interface, listen_only)?;
+        let socket = socketcan::tokio::CanFdSocket::open(interface)?;
+        // only used to send, so don't queue received frames
+        socket.set_filter_drop_all()?;
+        socket.set_error_filter_drop_all()?;
+        Ok(AsyncSocketCanConnection {
+            interface: interface.to_string(),
+            socket: Arc::new(socket),
+            clock: Clock::new(),
+            listen_only,
+            _mode: mode,
+        })
+    }
+
+    fn open_stream(&self) -> Result<PacketStream> {
+        let socket = socketcan::tokio::CanFdSocket::open(&self.interface)?;
+        configure_receive(&socket, &self.interface, socket.has_hw_timestamps())?;
+        let state = (socket, self.clock, None);
+        Ok(Box::pin(futures_util::stream::unfold(
+            state,
+            |(socket, clock, mut hardware_anchor)| async move {
+                loop {
+                    let (frame, timestamps) = socket.read_frame_with_timestamps().await.ok()?;
+                    let time = timestamp(&clock, &timestamps, &mut hardware_anchor);
+                    if let Some(p) = packet(frame, time) {
+                        return Some((p, (socket, clock, hardware_anchor)));
+                    }
+                }
+            },
+        )))
+    }
+}
+
+#[cfg(feature = "async")]
+impl async_connection::AsyncConnection for AsyncSocketCanConnection {
+    fn send<'a>(&'a self, packet: &'a Packet) -> SendFuture<'a> {
+        Box::pin(async move {
+            check_listen_only(self.listen_only, "SocketCAN")?;
+            // listen for echo.  Other sockets on the interface receive sent frames through the loopback.
+            let echo =
+                async_connection::AsyncConnection::stream_for(self, Duration::from_millis(1000))
+                    .filter(|p| std::future::ready(p.id == packet.id));
+            self.socket.write_frame(&frame(packet)?).await?;
+            std::pin::pin!(echo)
+                .next()
+                .await
+                .ok_or(Error::NoEcho { id: packet.id })
+        })
+    }
+
+    fn stream(&self) -> PacketStream {
+        self.open_stream().unwrap_or_else(|e| {
+            eprintln!("{}: unable to open stream: {e}", self.interface);
+            Box::pin(futures_util::stream::empty())
+        })
+    }
+}
+
 struct SocketCanConnectionFactory {
     name: String,
     speed: u64,
 }
 impl ConnectionFactory for SocketCanConnectionFactory {
-    fn create(&self) -> anyhow::Result<Box<dyn Connection>> {
-        Ok(Box::new(SocketCanConnection::new(&self.name, self.speed)?) as Box<dyn Connection>)
+    fn create(&self) -> Result<Box<dyn Connection>> {
+        Ok(
+            Box::new(SocketCanConnection::new(&self.name, self.speed, false)?)
+                as Box<dyn Connection>,
+        )
+    }
+
+    fn create_listen_only(&self) -> Result<Box<dyn Connection>> {
+        Ok(
+            Box::new(SocketCanConnection::new(&self.name, self.speed, true)?)
+                as Box<dyn Connection>,
+        )
     }
 
     fn command_line(&self) -> String {
@@ -139,12 +499,13 @@ impl ConnectionFactory for SocketCanConnectionFactory {
         cformat!("Linux socketcan on {}", self.name).to_string()
     }
 }
-pub(crate) fn list_all() -> Result<ProtocolDescriptor, anyhow::Error> {
+pub(crate) fn list_all() -> Result<ProtocolDescriptor> {
     Ok(ProtocolDescriptor {
         name: "socketcan".into(),
         instructions_url: "https://github.com/SolidDesignNet/j1939logger/blob/main/README.md"
             .to_string(),
-        devices: enumerate::available_interfaces()?
+        devices: enumerate::available_interfaces()
+            .map_err(|e| Error::Adapter(e.to_string()))?
             .iter()
             .map(|v| DeviceDescriptor {
                 name: v.clone(),
@@ -156,3 +517,103 @@ pub(crate) fn list_all() -> Result<ProtocolDescriptor, anyhow::Error> {
             .collect(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn timestamp_sources() {
+        let clock = Clock::new();
+        let mut anchor = None;
+        let kernel = clock.anchor() + Duration::from_millis(20);
+
+        let t = timestamp(&clock, &CanTimestamps::default(), &mut anchor);
+        assert_eq!(TimeSource::Host, t.source);
+
+        let ts = CanTimestamps {
+            socket: Some(kernel),
+            ..Default::default()
+        };
+        let t = timestamp(&clock, &ts, &mut anchor);
+        assert_eq!(TimeSource::Kernel, t.source);
+        assert_eq!(Duration::from_millis(20), t.elapsed);
+
+        // the NIC clock is anchored by the first kernel time, then advances on its own
+        let hw = Duration::from_secs(1000);
+        let ts = CanTimestamps {
+            socket: Some(kernel),
+            hw: Some(hw),
+            ..Default::default()
+        };
+        let t = timestamp(&clock, &ts, &mut anchor);
+        assert_eq!(TimeSource::Hardware, t.source);
+        assert_eq!(Duration::from_millis(20), t.elapsed);
+        let ts = CanTimestamps {
+            socket: Some(kernel + Duration::from_millis(7)),
+            hw: Some(hw + Duration::from_millis(5)),
+            ..Default::default()
+        };
+        let t = timestamp(&clock, &ts, &mut anchor);
+        assert_eq!(Duration::from_millis(25), t.elapsed);
+        assert_eq!(clock.anchor(), t.anchor.unwrap());
+    }
+
+    /// `sudo ip link add dev vcan0 type vcan && sudo ip link set up vcan0`
+    #[test]
+    #[ignore = "requires a vcan0 interface"]
+    fn vcan_kernel_timestamps() -> Result<()> {
+        let connection = SocketCanConnection::new("vcan0", 500_000, false)?;
+        let echo = connection.send(&Packet::new(0x18FEF1F9, &[1, 2, 3]))?;
+        let time = echo.timestamp().context("no timestamp")?;
+        assert_ne!(TimeSource::Host, time.source);
+        assert!(time.wall().unwrap() <= SystemTime::now());
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires a vcan0 interface"]
+    fn vcan_listen_only() -> Result<()> {
+        // vcan has no controller, so only sending is prevented
+        let connection = SocketCanConnection::new("vcan0", 500_000, true)?;
+        let error = connection.send(&Packet::new(0x18FEF1F9, &[1])).unwrap_err();
+        assert!(error.to_string().contains("listen only"));
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires a vcan0 interface"]
+    fn vcan_filters() -> Result<()> {
+        let connection = SocketCanConnection::new("vcan0", 500_000, false)?;
+        connection.set_filters(&[IdMask::new(0xF900, 0xFF00)])?;
+        let stream = connection.iter_for(Duration::from_millis(100));
+        let echo = connection.send(&Packet::new(0x18FEF1FA, &[1]))?;
+        assert_eq!(
+            TimeSource::Host,
+            echo.timestamp().context("no timestamp")?.source
+        );
+        connection.send(&Packet::new(0x18EAF9FA, &[2]))?;
+        assert_eq!(vec![0x18EAF9FA], stream.map(|p| p.id).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    #[ignore = "requires a vcan0 interface"]
+    async fn vcan_async_echo() -> Result<()> {
+        use crate::async_connection::AsyncConnection;
+
+        let connection = AsyncSocketCanConnection::new("vcan0", false)?;
+        let mut stream = connection.stream_for(Duration::from_secs(1));
+        let echo = AsyncConnection::send(&connection, &Packet::new(0x18FEF1F9, &[1, 2, 3])).await?;
+        assert_ne!(
+            TimeSource::Host,
+            echo.timestamp().context("no timestamp")?.source
+        );
+        let packet = stream.next().await.context("no packet")?;
+        assert_eq!((echo.id, echo.time()), (packet.id, packet.time()));
+        Ok(())
+    }
+}
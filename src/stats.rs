@@ -0,0 +1,287 @@
+//! Traffic counters and bus load of a connection.
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::packet::{dlc_to_len, IdType, Packet, PacketState};
+
+/// Counts since the connection opened.  Received frames include the echoes of sent frames, so `bits` covers all of the
+/// traffic on the bus.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    /// Error frames received
+    pub errors: u64,
+    /// Packets dropped by full [`PushBus`](crate::pushbus::PushBus) subscribers
+    pub dropped: u64,
+    /// Bits on the bus, including stuff bits and interframe space.  See [`frame_bits`].
+    pub bits: u64,
+    /// Nominal bitrate, if the connection knows it
+    pub bitrate: Option<u32>,
+}
+
+impl Stats {
+    /// Counts between `earlier` and this snapshot.  A count that went backwards, such as after the connection was
+    /// opened again, is 0.
+    pub fn since(&self, earlier: &Stats) -> Stats {
+        Stats {
+            rx_frames: self.rx_frames.saturating_sub(earlier.rx_frames),
+            rx_bytes: self.rx_bytes.saturating_sub(earlier.rx_bytes),
+            tx_frames: self.tx_frames.saturating_sub(earlier.tx_frames),
+            tx_bytes: self.tx_bytes.saturating_sub(earlier.tx_bytes),
+            errors: self.errors.saturating_sub(earlier.errors),
+            dropped: self.dropped.saturating_sub(earlier.dropped),
+            bits: self.bits.saturating_sub(earlier.bits),
+            bitrate: self.bitrate,
+        }
+    }
+
+    /// The counts of both, with the bitrate of this one.
+    pub fn plus(&self, other: &Stats) -> Stats {
+        Stats {
+            rx_frames: self.rx_frames + other.rx_frames,
+            rx_bytes: self.rx_bytes + other.rx_bytes,
+            tx_frames: self.tx_frames + other.tx_frames,
+            tx_bytes: self.tx_bytes + other.tx_bytes,
+            errors: self.errors + other.errors,
+            dropped: self.dropped + other.dropped,
+            bits: self.bits + other.bits,
+            bitrate: self.bitrate,
+        }
+    }
+
+    /// Fraction of `elapsed` the bus was busy, for the counts of [`Stats::since`].
+    pub fn load(&self, elapsed: Duration) -> Option<f64> {
+        self.bitrate
+            .map(|bitrate| self.bits as f64 / (bitrate as f64 * elapsed.as_secs_f64()))
+    }
+}
+
+/// Counters updated by a connection as it sends and receives.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    rx_frames: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_frames: AtomicU64,
+    tx_bytes: AtomicU64,
+    errors: AtomicU64,
+    bits: AtomicU64,
+    bitrate: Option<u32>,
+}
+
+impl Counters {
+    pub fn new(bitrate: Option<u32>) -> Self {
+        Counters {
+            bitrate,
+            ..Default::default()
+        }
+    }
+
+    pub fn received(&self, packet: &Packet) {
+        match packet.state {
+            PacketState::Error { .. } => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            PacketState::Status { .. } | PacketState::TX => return,
+            _ => {
+                self.rx_frames.fetch_add(1, Ordering::Relaxed);
+                self.rx_bytes
+                    .fetch_add(packet.payload.len() as u64, Ordering::Relaxed);
+            }
+        }
+        self.bits.fetch_add(frame_bits(packet), Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, packet: &Packet) {
+        self.tx_frames.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes
+            .fetch_add(packet.payload.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, dropped: u64) -> Stats {
+        Stats {
+            rx_frames: self.rx_frames.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_frames: self.tx_frames.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            dropped,
+            bits: self.bits.load(Ordering::Relaxed),
+            bitrate: self.bitrate,
+        }
+    }
+}
+
+/// CRC delimiter, ACK slot and delimiter, end of frame and interframe space.
+const TRAILER_BITS: u64 = 1 + 2 + 7 + 3;
+/// Error flag, the flags of the other nodes, the delimiter and interframe space.
+const ERROR_FRAME_BITS: u64 = 6 + 6 + 8 + 3;
+
+/// Bits `packet` takes on the bus at the nominal bitrate.  Classic frames are stuffed exactly.  FD frames use the worst
+/// case stuffing and, because the data bitrate is unknown, count the data phase at the nominal bitrate.
+pub fn frame_bits(packet: &Packet) -> u64 {
+    match packet.state {
+        PacketState::Error { .. } => ERROR_FRAME_BITS,
+        PacketState::Status { .. } => 0,
+        PacketState::Remote { dlc, .. } => classic_bits(packet.id, packet.id_type, true, dlc, &[]),
+        _ if packet.fd.is_some() => fd_bits(packet),
+        _ => classic_bits(
+            packet.id,
+            packet.id_type,
+            false,
+            packet.dlc(),
+            &packet.payload,
+        ),
+    }
+}
+
+fn classic_bits(id: u32, id_type: IdType, remote: bool, dlc: u8, data: &[u8]) -> u64 {
+    let mut bits = Vec::with_capacity(128);
+    let put = |bits: &mut Vec<u32>, value: u32, len: u32| {
+        (0..len).rev().for_each(|i| bits.push(value >> i & 1))
+    };
+    put(&mut bits, 0, 1); // start of frame
+    match id_type {
+        IdType::Standard => {
+            put(&mut bits, id, 11);
+            put(&mut bits, remote as u32, 1);
+            put(&mut bits, 0, 2); // IDE, r0
+        }
+        IdType::Extended => {
+            put(&mut bits, id >> 18, 11);
+            put(&mut bits, 1, 2); // SRR, IDE
+            put(&mut bits, id, 18);
+            put(&mut bits, remote as u32, 1);
+            put(&mut bits, 0, 2); // r1, r0
+        }
+    }
+    put(&mut bits, dlc as u32, 4);
+    data.iter().for_each(|b| put(&mut bits, *b as u32, 8));
+    let crc = crc15(&bits);
+    put(&mut bits, crc, 15);
+    bits.len() as u64 + stuff_bits(&bits) + TRAILER_BITS
+}
+
+/// A bit of the opposite value follows 5 equal bits, and counts towards the next run.
+fn stuff_bits(bits: &[u32]) -> u64 {
+    let mut stuff = 0;
+    let mut run = 0;
+    let mut last = 2;
+    for bit in bits.iter().copied() {
+        if bit == last {
+            run += 1;
+        } else {
+            last = bit;
+            run = 1;
+        }
+        if run == 5 {
+            stuff += 1;
+            last ^= 1;
+            run = 1;
+        }
+    }
+    stuff
+}
+
+fn crc15(bits: &[u32]) -> u32 {
+    bits.iter().fold(0, |crc, bit| {
+        let next = (crc << 1) & 0x7FFF;
+        if (crc >> 14) & 1 != *bit {
+            next ^ 0x4599
+        } else {
+            next
+        }
+    })
+}
+
+fn fd_bits(packet: &Packet) -> u64 {
+    let header: u64 = match packet.id_type {
+        // SOF, id, RRS, IDE, FDF, res, BRS, ESI, DLC
+        IdType::Standard => 1 + 11 + 1 + 1 + 1 + 1 + 1 + 1 + 4,
+        IdType::Extended => 1 + 11 + 2 + 18 + 1 + 1 + 1 + 1 + 1 + 4,
+    };
+    let data = 8 * dlc_to_len(packet.dlc()) as u64;
+    let crc: u64 = if packet.payload.len() <= 16 { 17 } else { 21 };
+    // the stuff count and CRC have a fixed stuff bit every 4 bits
+    let fixed = 4 + crc + (4 + crc).div_ceil(4);
+    header + data + (header + data - 1) / 4 + fixed + TRAILER_BITS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::FdFlags;
+
+    #[test]
+    fn bits() -> anyhow::Result<()> {
+        // 118 bits and a 13 bit trailer, with at most 29 stuff bits
+        let p = Packet::new(0x18FEF100, &[0x55; 8]);
+        let bits = frame_bits(&p);
+        assert!((131..=160).contains(&bits), "{bits}");
+        // all zeros stuff the most
+        assert!(frame_bits(&Packet::new(0x18FEF100, &[0; 8])) > bits);
+        let p = Packet::new(0x7E8, &[0; 8]).with_id_type(IdType::Standard);
+        assert!((111..=111 + 24).contains(&frame_bits(&p)));
+        let fd = Packet::new_fd(0x18DA00F9, &[0xAA; 64], FdFlags::default())?;
+        assert!(frame_bits(&fd) > 8 * 64);
+        Ok(())
+    }
+
+    #[test]
+    fn stuffing() {
+        assert_eq!(1, stuff_bits(&[0; 5]));
+        // the stuff bit starts the next run
+        assert_eq!(2, stuff_bits(&[0; 10]));
+        assert_eq!(2, stuff_bits(&[0, 0, 0, 0, 0, 1, 1, 1, 1]));
+        assert_eq!(0, stuff_bits(&[0, 1, 0, 1, 1, 1, 1, 0]));
+    }
+
+    #[test]
+    fn crc() {
+        // CRC-15/CAN check value
+        let bits: Vec<u32> = b"123456789"
+            .iter()
+            .flat_map(|b| (0..8).rev().map(move |i| (*b as u32) >> i & 1))
+            .collect();
+        assert_eq!(0x059E, crc15(&bits));
+    }
+
+    #[test]
+    fn load() {
+        let earlier = Stats {
+            bits: 1000,
+            bitrate: Some(250_000),
+            ..Default::default()
+        };
+        let now = Stats {
+            bits: 126_000,
+            rx_frames: 10,
+            ..earlier
+        };
+        let delta = now.since(&earlier);
+        assert_eq!(10, delta.rx_frames);
+        assert_eq!(Some(0.5), delta.load(Duration::from_secs(1)));
+        assert_eq!(None, Stats::default().load(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn counts_go_backwards() {
+        let earlier = Stats {
+            rx_frames: 10,
+            bits: 1000,
+            ..Default::default()
+        };
+        let reopened = Stats {
+            rx_frames: 2,
+            ..Default::default()
+        };
+        assert_eq!(Stats::default(), reopened.since(&earlier));
+        let total = earlier.plus(&reopened);
+        assert_eq!(12, total.rx_frames);
+        assert_eq!(1000, total.bits);
+    }
+}
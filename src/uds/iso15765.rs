@@ -1,24 +1,26 @@
 use std::{thread, time::Duration};
 
-use anyhow::{anyhow, Result};
-
-use crate::{connection::Connection, packet::Packet};
-
-pub struct Iso15765<'a> {
-    connection: &'a dyn Connection,
+#[cfg(feature = "async")]
+use futures_util::StreamExt;
+
+#[cfg(feature = "async")]
+use crate::async_connection::AsyncConnection;
+use crate::{
+    connection::{Connection, Filter, IdMask},
+    error::{Error, Result},
+    packet::Packet,
+};
+
+/// `C` is `dyn Connection`, or `dyn AsyncConnection` for the async variants.
+pub struct Iso15765<'a, C: ?Sized = dyn Connection> {
+    connection: &'a C,
     send_header: u32,
     receive_header: u32,
     duration: Duration,
 }
 
-impl<'a> Iso15765<'a> {
-    pub fn new(
-        connection: &'a dyn Connection,
-        pgn: u32,
-        duration: Duration,
-        sa: u8,
-        da: u8,
-    ) -> Self {
+impl<'a, C: ?Sized> Iso15765<'a, C> {
+    fn with_connection(connection: &'a C, pgn: u32, duration: Duration, sa: u8, da: u8) -> Self {
         let sa32 = sa as u32;
         let da32 = da as u32;
         Iso15765 {
@@ -28,129 +30,271 @@ impl<'a> Iso15765<'a> {
             receive_header: pgn << 8 | sa32 << 8 | da32,
         }
     }
+
+    fn single_frame(&self, request: &[u8]) -> Packet {
+        let mut payload = [&[request.len() as u8], request].concat();
+        // pad out to 8 bytes
+        while payload.len() < 8 {
+            payload.push(0xFF);
+        }
+        Packet::new(self.send_header, &payload)
+    }
+
+    fn first_frame(&self, request: &[u8]) -> Packet {
+        let size = request.len();
+        let payload = [&(0x1000 | (size as u16)).to_be_bytes(), &request[0..6]].concat();
+        Packet::new(self.send_header, &payload)
+    }
+
+    fn is_response(&self, p: &Packet) -> bool {
+        p.id & 0xFFFFFF == self.receive_header
+    }
+
+    /// Single or first frame of a response.
+    fn is_response_start(&self, p: &Packet) -> bool {
+        self.is_response(p)
+            && p.is_data()
+            && p.payload
+                .first()
+                .is_some_and(|pci| pci & 0xF0 == 0 || pci & 0xF0 == 0x10)
+    }
+
+    /// Separation time requested by the flow control response to a first frame.
+    fn separation_time(request: &[u8], p: &Packet) -> Result<Duration> {
+        match p.payload[..] {
+            // FIXME use block size and flow control!
+            [0x30, block_size, interpacket_delay, ..] => {
+                let interpacket_delay = interpacket_delay as u64;
+                Ok(if interpacket_delay > 0xF0 && interpacket_delay < 0xFA {
+                    Duration::from_micros(100 * (0xF & interpacket_delay))
+                } else {
+                    Duration::from_millis(interpacket_delay)
+                })
+            }
+            // a negative response instead of flow control
+            [0x7F, service, code, ..] | [_, 0x7F, service, code, ..] => {
+                Err(Error::Nack { service, code })
+            }
+            _ => Err(Error::Protocol(format!(
+                "Unexpected: {request:X?} -> {p} should this be ignored?"
+            ))),
+        }
+    }
+
+    /// The data of a single frame.
+    fn single_frame_data(p: &Packet) -> Result<Vec<u8>> {
+        let len = p.payload[0] as usize;
+        p.payload
+            .get(1..1 + len)
+            .map(|data| data.to_vec())
+            .ok_or_else(|| Error::Protocol(format!("Single frame too short: {p}")))
+    }
+
+    fn consecutive_frames<'r>(&self, request: &'r [u8]) -> impl Iterator<Item = Packet> + 'r {
+        let send_header = self.send_header;
+        // packet size of 9 means 1 consecutive packet
+        // packet size of 13 means 1 consecutive packet
+        // packet size of 14 means 2 consecutive packets
+        // packet size of 20 means 2 consecutive packets
+        // packet size of 21 means 3 consecutive packets
+
+        // First consecutive packet sequence is 1 and max is 0 (really. Look it up.)
+        let frames = 1 + request.len() / 7;
+        (1..frames).map(move |sequence| {
+            let offset = 6 + (sequence - 1) * 7;
+            let end = Ord::min(7 + offset, request.len());
+            let mut payload = [&[0x20 | (sequence as u8 & 0xF)], &request[offset..end]].concat();
+            while payload.len() < 8 {
+                payload.push(0xFF);
+            }
+            Packet::new(send_header, &payload)
+        })
+    }
+
+    fn flow_control(&self) -> Packet {
+        let payload = [0x30, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        Packet::new(self.send_header, &payload)
+    }
+
+    /// Length of the message and number of consecutive frames that follow a first frame, and the data of the first
+    /// frame.
+    fn parse_first_frame(packet: &Packet) -> Result<(usize, usize, Vec<u8>)> {
+        match packet.payload[..] {
+            [high, low, ref data @ ..] => {
+                let len = u16::from_be_bytes([high, low]) & 0x0FFF;
+                Ok((len as usize, len as usize / 7, data.to_vec()))
+            }
+            _ => Err(Error::Protocol(format!("First frame too short: {packet}"))),
+        }
+    }
+
+    /// The data of a consecutive frame.
+    fn consecutive_data(p: &Packet) -> &[u8] {
+        p.payload.get(1..).unwrap_or_default()
+    }
+}
+
+impl<'a> Iso15765<'a, dyn Connection + 'a> {
+    pub fn new(
+        connection: &'a dyn Connection,
+        pgn: u32,
+        duration: Duration,
+        sa: u8,
+        da: u8,
+    ) -> Self {
+        Iso15765::with_connection(connection, pgn, duration, sa, da)
+    }
+
+    /// Only the responses are queued for the session.
+    fn responses(&self) -> Filter {
+        IdMask::new(self.receive_header, 0xFFFFFF).into()
+    }
+
     pub fn send(&self, request: &[u8]) -> Result<()> {
         if request.len() > 8 {
             self.transport_send(request)?;
         } else {
-            let mut payload = [&[request.len() as u8], request].concat();
-            // pad out to 8 bytes
-            while payload.len() < 8 {
-                payload.push(0xFF);
-            }
-            let p = Packet::new(self.send_header, &payload);
-            self.connection.send(&p)?;
+            self.connection.send(&self.single_frame(request))?;
         }
         Ok(())
     }
 
     /// This assumes that all ISO15765 is synchronous.
     pub fn receive(&self, iter: &mut impl Iterator<Item = Packet>) -> Result<Option<Vec<u8>>> {
-        let packet = iter.find(|p| {
-            p.id & 0xFFFFFF == self.receive_header
-                && (p.payload[0] & 0xF0 == 0 || p.payload[0] & 0xF0 == 0x10)
-        });
+        let packet = iter.find(|p| self.is_response_start(p));
         if let Some(p) = packet {
             if p.payload[0] & 0xF0 == 0x00 {
-                Ok(Some(p.payload[1..(1 + p.payload[0] as usize)].to_vec()))
+                Ok(Some(Self::single_frame_data(&p)?))
             } else {
                 self.transport_receive(&p)
             }
         } else {
-            Err(anyhow!("No response"))
+            Err(Error::Timeout("a response".into()))
         }
     }
 
     pub fn send_receive(&self, request: &[u8]) -> Result<Option<Vec<u8>>> {
-        let mut iter = self.connection.iter_for(self.duration);
+        let mut iter = self
+            .connection
+            .subscribe_for(self.responses(), self.duration);
         self.send(request)?;
         self.receive(&mut iter)
     }
 
     fn transport_send(&self, request: &[u8]) -> Result<()> {
         // send first frame
-        let size = request.len();
-        let payload = [&(0x1000 | (size as u16)).to_be_bytes(), &request[0..6]].concat();
-        let first_frame = Packet::new(self.send_header, &payload);
-        let mut flow_control_stream = self.connection.iter_for(Duration::from_secs(2));
-        self.connection.send(&first_frame)?;
+        let mut flow_control_stream = self
+            .connection
+            .subscribe_for(self.responses(), Duration::from_secs(2));
+        self.connection.send(&self.first_frame(request))?;
 
         // response to flow control
-        let flow_control = flow_control_stream.find(|p| p.id & 0xFFFFFF == self.receive_header);
+        let flow_control = flow_control_stream.find(|p| self.is_response(p));
         match flow_control {
             Some(p) => {
-                if p.payload[0] == 0x7F {
-                    Err(anyhow!("NACK: {request:?} -> {p}"))
-                } else if p.payload[0] != 0x30 {
-                    Err(anyhow!(
-                        "Unexpected: {request:?} -> {p} should this be ignored?"
-                    ))
-                } else {
-                    // validate response?
-
-                    // FIXME use block size and flow control!
-                    let block_size = p.payload[1];
-
-                    let interpacket_delay = p.payload[2] as u64;
-                    let interpacket_delay = if interpacket_delay > 0xF0 && interpacket_delay < 0xFA
-                    {
-                        Duration::from_micros(100 * (0xF & interpacket_delay))
-                    } else {
-                        Duration::from_millis(interpacket_delay)
-                    };
-
-                    // packet size of 9 means 1 consecutive packet
-                    // packet size of 13 means 1 consecutive packet
-                    // packet size of 14 means 2 consecutive packets
-                    // packet size of 20 means 2 consecutive packets
-                    // packet size of 21 means 3 consecutive packets
-
-                    // First consecutive packet sequence is 1 and max is 0 (really. Look it up.)
-                    let frames = 1 + size / 7;
-                    for sequence in 1..frames {
-                        thread::sleep(interpacket_delay);
-                        let offset = 6 + (sequence - 1) * 7;
-                        let end = Ord::min(7 + offset, request.len());
-                        let mut payload =
-                            [&[0x20 | (sequence as u8 & 0xF)], &request[offset..end]].concat();
-                        while payload.len() < 8 {
-                            payload.push(0xFF);
-                        }
-                        let consecutive = Packet::new(self.send_header, &payload);
-                        self.connection.send(&consecutive)?;
-                    }
-
-                    Ok(())
+                let interpacket_delay = Self::separation_time(request, &p)?;
+                for consecutive in self.consecutive_frames(request) {
+                    thread::sleep(interpacket_delay);
+                    self.connection.send(&consecutive)?;
                 }
+                Ok(())
             }
-            None => Err(anyhow!("No response to: {request:X?}",)),
+            None => Err(Error::Timeout(format!("flow control for {request:X?}"))),
         }
     }
 
     fn transport_receive(&self, packet: &Packet) -> Result<Option<Vec<u8>>> {
-        let stream = self.connection.iter_for(self.duration);
+        let stream = self
+            .connection
+            .subscribe_for(self.responses(), self.duration);
 
         // send flow control
-        let payload = [0x30, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
-        let flow_control = Packet::new(self.send_header, &payload);
-        self.connection.send(&flow_control)?;
+        self.connection.send(&self.flow_control())?;
 
-        let mut result = Vec::new();
         // collect payload from first packet
-        result.extend(packet.payload[2..].iter());
+        let (len, frames, mut result) = Self::parse_first_frame(packet)?;
 
         // collect all payload from the rest
-        let bytes: [u8; 2] = packet.payload[0..2]
-            .try_into()
-            .expect("Failed to parse length.");
-        let len = u16::from_be_bytes(bytes) & 0x0FFF;
-        let frames = len / 7;
         stream
-            .filter(|p| p.id & 0xFFFFFF == self.receive_header)
+            .filter(|p| self.is_response(p))
             // exit as soon as we have all the frames
-            .take(frames as usize)
-            .for_each(|p| result.extend(p.payload[1..].iter()));
+            .take(frames)
+            .for_each(|p| result.extend(Self::consecutive_data(&p)));
+        // trim padding
+        result.truncate(len);
+        Ok(Some(result))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> Iso15765<'a, dyn AsyncConnection + 'a> {
+    pub fn new_async(
+        connection: &'a dyn AsyncConnection,
+        pgn: u32,
+        duration: Duration,
+        sa: u8,
+        da: u8,
+    ) -> Self {
+        Iso15765::with_connection(connection, pgn, duration, sa, da)
+    }
+
+    pub async fn send_async(&self, request: &[u8]) -> Result<()> {
+        if request.len() <= 8 {
+            self.connection.send(&self.single_frame(request)).await?;
+            return Ok(());
+        }
+        // send first frame
+        let mut flow_control_stream = self.connection.stream_for(Duration::from_secs(2));
+        self.connection.send(&self.first_frame(request)).await?;
+
+        // response to flow control
+        let p = loop {
+            match flow_control_stream.next().await {
+                Some(p) if self.is_response(&p) => break p,
+                Some(_) => continue,
+                None => return Err(Error::Timeout(format!("flow control for {request:X?}"))),
+            }
+        };
+        let interpacket_delay = Self::separation_time(request, &p)?;
+        for consecutive in self.consecutive_frames(request) {
+            tokio::time::sleep(interpacket_delay).await;
+            self.connection.send(&consecutive).await?;
+        }
+        Ok(())
+    }
+
+    /// Async variant of [`Iso15765::send_receive`].
+    pub async fn send_receive_async(&self, request: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut stream = self.connection.stream_for(self.duration);
+        self.send_async(request).await?;
+
+        let packet = loop {
+            match stream.next().await {
+                Some(p) if self.is_response_start(&p) => break p,
+                Some(_) => continue,
+                None => return Err(Error::Timeout("a response".into())),
+            }
+        };
+        if packet.payload[0] & 0xF0 == 0x00 {
+            return Ok(Some(Self::single_frame_data(&packet)?));
+        }
+
+        // send flow control, then collect the consecutive frames
+        self.connection.send(&self.flow_control()).await?;
+        let (len, frames, mut result) = Self::parse_first_frame(&packet)?;
+        let mut received = 0;
+        while received < frames {
+            match stream.next().await {
+                Some(p) if self.is_response(&p) => {
+                    result.extend(Self::consecutive_data(&p));
+                    received += 1;
+                }
+                Some(_) => continue,
+                None => break,
+            }
+        }
         // trim padding
-        result.truncate(len as usize);
+        result.truncate(len);
         Ok(Some(result))
     }
 }
@@ -159,7 +303,7 @@ impl<'a> Iso15765<'a> {
 mod tests {
     use super::*;
     use crate::sim::SimulatedConnection;
-    use anyhow::Ok;
+    use anyhow::{Ok, Result};
     #[test]
     fn send8() -> Result<()> {
         let connection = SimulatedConnection::new(None)?;
@@ -200,6 +344,36 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn unpowered_ecu() -> Result<()> {
+        const DURATION: Duration = Duration::from_millis(100);
+        let connection = SimulatedConnection::silent();
+        let tp = Iso15765::new(&connection, 0xDA00, DURATION, 0xF9, 0);
+        // a multi frame request waits 2 s for flow control
+        for (request, wait) in [(&[0x22, 0xF1, 0x90][..], 0), (&[0x55; 20], 2)] {
+            let start = std::time::Instant::now();
+            assert!(matches!(tp.send_receive(request), Err(Error::Timeout(_))));
+            assert!(start.elapsed() < Duration::from_secs(wait) + Duration::from_millis(500));
+        }
+        Ok(())
+    }
+    #[test]
+    fn flow_control_nack() {
+        let request = [0x2E, 0xF1, 0x90];
+        let nack = Packet::new(0x18DAF900, &[0x03, 0x7F, 0x2E, 0x31]);
+        assert!(matches!(
+            Iso15765::<dyn Connection>::separation_time(&request, &nack),
+            Err(Error::Nack {
+                service: 0x2E,
+                code: 0x31
+            })
+        ));
+        let short = Packet::new(0x18DAF900, &[0x30]);
+        assert!(matches!(
+            Iso15765::<dyn Connection>::separation_time(&request, &short),
+            Err(Error::Protocol(_))
+        ));
+    }
+    #[test]
     fn send14() -> Result<()> {
         const DURATION: Duration = Duration::from_secs(2);
         let rx_connection = SimulatedConnection::new(None)?;
@@ -218,6 +392,27 @@ mod tests {
         assert_eq!([0x55; 14][..], packet.unwrap());
         Ok(())
     }
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn send_receive_async() -> Result<()> {
+        const DURATION: Duration = Duration::from_secs(2);
+        let connection = SimulatedConnection::new(None)?;
+
+        // echo ISO15765 requests back with each byte incremented, using the blocking API
+        let tx_connection = connection.clone();
+        let mut stream = tx_connection.iter_for(DURATION);
+        thread::spawn(move || {
+            let tp = Iso15765::new(&tx_connection, 0xDA00, DURATION, 0, 0xF9);
+            let rx = tp.receive(&mut stream).unwrap().unwrap();
+            let tx = rx.iter().map(|u| u + 3).collect::<Vec<u8>>();
+            tp.send(&tx).expect("Failed to send");
+        });
+
+        let tp = Iso15765::new_async(&connection, 0xDA00, DURATION, 0xF9, 0);
+        let buf = tp.send_receive_async(&[1; 20]).await?;
+        assert_eq!(Some(vec![4; 20]), buf);
+        Ok(())
+    }
     #[test]
     fn send4000() -> Result<()> {
         const DURATION: Duration = Duration::from_secs(2);
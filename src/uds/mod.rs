@@ -1,10 +1,13 @@
-use crate::{uds::iso15765::Iso15765, CanContext};
-use anyhow::Result;
+use crate::{
+    error::{Error, Result},
+    uds::iso15765::Iso15765,
+    CanContext,
+};
 use clap::*;
 use clap_num::maybe_hex;
 use std::time::Duration;
 
-mod iso15765;
+pub mod iso15765;
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum Uds {
@@ -141,8 +144,7 @@ impl Iso14229Command {
         self
     }
 
-    // Err(None) means no response.
-    /// Err(UdsBuffer) is the NACK
+    /// [`Error::Timeout`] means no response, and [`Error::Nack`] is a negative response.
     pub fn execute(&self, context: &mut CanContext) -> Result<Option<Vec<u8>>> {
         let connection = context.connection.as_mut();
         let iso15765 = Iso15765::new(
@@ -153,6 +155,12 @@ impl Iso14229Command {
             context.can_can.destination_address,
         );
 
-        iso15765.send_receive(&self.raw)
+        match iso15765.send_receive(&self.raw)? {
+            Some(response) => match response[..] {
+                [0x7F, service, code, ..] => Err(Error::Nack { service, code }),
+                _ => Ok(Some(response)),
+            },
+            None => Ok(None),
+        }
     }
 }